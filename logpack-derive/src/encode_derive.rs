@@ -160,9 +160,10 @@ fn encoder_for_enum(name: &Ident, data_enum: &DataEnum, sizer: bool) -> Tokens {
             )
         } else {
             quote!(
-                match self {
+                let res: Result<(), (usize, usize)> = match self {
                     #(#impls),*
-                }?
+                };
+                res?
             )
         }
     }
@@ -58,8 +58,6 @@ fn add_trait_bounds(
                 let bound = syn::parse(quote! { #trait_name }.into()).unwrap();
                 type_param.bounds.push(bound);
             }
-            let bound = syn::parse(quote! { 'static }.into()).unwrap();
-            type_param.bounds.push(bound);
         }
     }
     generics
@@ -13,6 +13,14 @@ pub fn derive(input: &DeriveInput) -> Tokens {
     );
     let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
+    // Only types without generic parameters are known to be 'static, which
+    // keying them by `TypeId` requires.
+    let make_name = if input.generics.params.is_empty() {
+        quote! { make_name_for }
+    } else {
+        quote! { make_name_for_borrowed }
+    };
+
     let fields = match &input.data {
         Data::Enum(data) => {
             let fields = bintype_for_enum(data);
@@ -32,9 +40,7 @@ pub fn derive(input: &DeriveInput) -> Tokens {
              fn logpack_describe(st: &mut logpack::SeenTypes) ->
                  logpack::Description<logpack::TypeNameId, logpack::FieldName>
              {
-                 use std::any::TypeId;
-                 let self_id = TypeId::of::<Self>();
-                 let (first_seen, typename_id) = st.make_name_for_id(stringify!(#name), self_id);
+                 let (first_seen, typename_id) = st.#make_name::<Self>();
                  let may_recurse = if first_seen { #fields } else { None };
 
                  logpack::Description::ByName(typename_id, may_recurse)
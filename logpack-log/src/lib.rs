@@ -0,0 +1,191 @@
+use logpack::Level;
+use logpack::callsite::StreamSink;
+use logpack::envelope::Envelope;
+use logpack::file::{self, Metadata};
+use logpack::framing::FrameWriter;
+use logpack::stream::{RecordSink, StreamWriter};
+use logpack_derive::Logpack;
+
+use log::kv::{self, VisitSource, VisitValue};
+use log::{LevelFilter, Log, Metadata as LogMetadata, Record, SetLoggerError};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+//////////////////////////////////////////////////////////////////////////
+//
+// `log` facade backend
+//
+// Every `log::Record` becomes a log entry whose envelope carries the level,
+// time and thread, and whose value is a `LogRecord`. The message is
+// formatted on the spot, as `log` gives no access to the arguments.
+
+#[derive(Logpack, Debug, PartialEq)]
+pub enum FieldValue {
+    Null,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    Str(String),
+    /// Values of other kinds, e.g. floats and errors, as displayed.
+    Other(String),
+}
+
+#[derive(Logpack, Debug, PartialEq)]
+pub struct Field {
+    pub key: String,
+    pub value: FieldValue,
+}
+
+#[derive(Logpack, Debug)]
+pub struct LogRecord<'a> {
+    pub target: &'a str,
+    pub module_path: Option<&'a str>,
+    pub file: Option<&'a str>,
+    pub line: Option<u32>,
+    pub message: &'a str,
+    pub fields: &'a [Field],
+}
+
+pub fn level(level: log::Level) -> Level {
+    match level {
+        log::Level::Error => Level::Error,
+        log::Level::Warn => Level::Warn,
+        log::Level::Info => Level::Info,
+        log::Level::Debug => Level::Debug,
+        log::Level::Trace => Level::Trace,
+    }
+}
+
+struct Fields(Vec<Field>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let mut field = FieldVisitor(FieldValue::Null);
+        value.visit(&mut field)?;
+        self.0.push(Field { key: key.as_str().to_owned(), value: field.0 });
+        Ok(())
+    }
+}
+
+struct FieldVisitor(FieldValue);
+
+impl<'v> VisitValue<'v> for FieldVisitor {
+    fn visit_any(&mut self, value: kv::Value) -> Result<(), kv::Error> {
+        self.0 = FieldValue::Other(value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = FieldValue::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = FieldValue::U64(value);
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = FieldValue::I64(value);
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = FieldValue::Bool(value);
+        Ok(())
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        self.0 = FieldValue::Str(value.to_owned());
+        Ok(())
+    }
+}
+
+/// A `log::Log` writing to a `StreamSink`, which may be shared with
+/// `logpack::log!`.
+pub struct Logger<W: RecordSink + Send + 'static> {
+    sink: &'static StreamSink<W>,
+    level: LevelFilter,
+}
+
+impl<W: RecordSink + Send + 'static> Logger<W> {
+    pub fn new(sink: &'static StreamSink<W>) -> Self {
+        Self { sink, level: LevelFilter::Trace }
+    }
+
+    pub fn with_level(self, level: LevelFilter) -> Self {
+        Self { level, ..self }
+    }
+
+    pub fn sink(&self) -> &'static StreamSink<W> {
+        self.sink
+    }
+}
+
+impl<W: RecordSink + Send + 'static> Log for Logger<W> {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut formatted = String::new();
+        let message = match record.args().as_str() {
+            Some(message) => message,
+            None => {
+                let _ = write!(formatted, "{}", record.args());
+                &formatted
+            }
+        };
+
+        let mut fields = Fields(vec![]);
+        let _ = record.key_values().visit(&mut fields);
+
+        let value = LogRecord {
+            target: record.target(),
+            module_path: record.module_path(),
+            file: record.file(),
+            line: record.line(),
+            message,
+            fields: &fields.0,
+        };
+        self.sink.write_entry(&Envelope::now(level(record.level()), 0), &value);
+    }
+
+    fn flush(&self) {
+        let _ = self.sink.lock().flush();
+    }
+}
+
+/// Installs a `Logger` on the sink as the logger of the `log` facade.
+pub fn init<W: RecordSink + Send + 'static>(sink: &'static StreamSink<W>,
+                                            level: LevelFilter) -> Result<(), SetLoggerError>
+{
+    log::set_boxed_logger(Box::new(Logger::new(sink).with_level(level)))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    SetLogger(SetLoggerError),
+}
+
+pub type FileSink = StreamSink<FrameWriter<BufWriter<File>>>;
+
+/// Creates a log file at `path` and installs a logger writing to it. The
+/// sink is returned so that it can be flushed, or given to
+/// `logpack::callsite::set_sink` as well.
+pub fn init_file(path: &Path, level: LevelFilter) -> Result<&'static FileSink, Error> {
+    let out = BufWriter::new(File::create(path).map_err(Error::Io)?);
+    let frames = file::frame_writer(out, &Metadata::current()).map_err(Error::Io)?;
+    let sink: &'static FileSink = Box::leak(Box::new(StreamSink::new(StreamWriter::new(frames))));
+    init(sink, level).map_err(Error::SetLogger)?;
+    Ok(sink)
+}
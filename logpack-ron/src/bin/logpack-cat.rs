@@ -0,0 +1,306 @@
+//! Prints the records of log files, by default as log lines.
+//!
+//! Usage: logpack-cat [OPTIONS] [FILE...]
+//!
+//! Reads the standard input when no file is given, or for `-`. Files ending
+//! in `.zst`, as compressed by `rotate`, are decompressed on the way, as are
+//! compressed record blocks. The records of several files are merged by
+//! time.
+//!
+//! Options:
+//!
+//! * `-f`, `--follow`: waits for more records at the end of the file, as
+//!   `tail -f` does, until the file is finished. Takes a single file.
+//! * `--since TIME`: leaves out entries before `TIME`, seeking to it with
+//!   the file's index when there is one.
+//! * `--until TIME`: leaves out entries after `TIME`.
+//! * `--type NAME`: prints only values of the named type, e.g.
+//!   `GenericType<u32>`. May be given more than once.
+//! * `--filter EXPR`: prints only values matching the expression, e.g.
+//!   `level >= WARN && req.path ~ "^/api"`, as in `logpack::filter`.
+//! * `--format FORMAT`: `plain`, `color` or `ron`, as in
+//!   `logpack_ron::record::Format`. Defaults to `color` on a terminal.
+//!
+//! Instead of printing the records, sums them up with `logpack::aggregate`:
+//!
+//! * `--count`: prints the number of records.
+//! * `--group-by PATH`: prints a line for each value at `PATH`, e.g. for
+//!   each variant of an `error` field, with the number of records having
+//!   it. Given more than once, for each combination of values.
+//! * `--stats PATH`: adds the sum, least, greatest and mean of the integers
+//!   at `PATH`, and their p50, p90 and p99. May be given more than once.
+//! * `--histogram`: adds the buckets of values of each `--stats` path.
+//!
+//! Groups with more records come first. Paths are as in `logpack::path`,
+//! e.g. `req.status`.
+//!
+//! Times are given as printed, e.g. `2024-03-05T14:02:11.123456Z`, or in
+//! nanoseconds since the Unix epoch.
+
+use logpack::Description;
+use logpack::aggregate::Aggregator;
+use logpack::file::{self, FileReader};
+use logpack::filter::Filter;
+use logpack::merge::MergeReader;
+use logpack::path;
+use logpack::rotate::{open_segment, Segment};
+use logpack::stream::{RecordSource, StreamReader, Value};
+use logpack_ron::envelope::parse_time;
+use logpack_ron::record::{render, Format};
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::Path;
+use std::process::exit;
+use std::time::Duration;
+
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+fn usage() -> ! {
+    eprintln!("usage: logpack-cat [-f|--follow] [--since TIME] [--until TIME] [--type NAME]... \
+               [--filter EXPR] [--format plain|color|ron] [--count] [--group-by PATH]... \
+               [--stats PATH]... [--histogram] [FILE...]");
+    exit(2);
+}
+
+struct Options {
+    follow: bool,
+    since: Option<u64>,
+    until: Option<u64>,
+    types: Vec<String>,
+    filter: Option<Filter>,
+    format: Format,
+    count: bool,
+    group_by: Vec<path::Path>,
+    stats: Vec<path::Path>,
+    histogram: bool,
+}
+
+impl Options {
+    fn selects(&self, value: &Value) -> bool {
+        if let Some(envelope) = &value.envelope {
+            if self.since.is_some_and(|since| envelope.time < since)
+                || self.until.is_some_and(|until| envelope.time > until)
+            {
+                return false;
+            }
+        }
+
+        if !self.types.is_empty() {
+            let named = match value.desc {
+                Description::ByName((name, _), _) => self.types.iter().any(|t| t == name),
+                _ => false,
+            };
+            if !named {
+                return false;
+            }
+        }
+
+        // Values that fail to decode don't match.
+        self.filter.as_ref().is_none_or(|filter| filter.matches(value).unwrap_or(false))
+    }
+}
+
+fn main() {
+    let mut options = Options {
+        follow: false,
+        since: None,
+        until: None,
+        types: vec![],
+        filter: None,
+        format: if io::stdout().is_terminal() { Format::Color } else { Format::Plain },
+        count: false,
+        group_by: vec![],
+        stats: vec![],
+        histogram: false,
+    };
+    let mut paths = vec![];
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "-f" | "--follow" => options.follow = true,
+            "--since" => options.since = Some(parse_time(&value()).unwrap_or_else(|| usage())),
+            "--until" => options.until = Some(parse_time(&value()).unwrap_or_else(|| usage())),
+            "--type" => options.types.push(value()),
+            "--filter" => match value().parse() {
+                Ok(filter) => options.filter = Some(filter),
+                Err(err) => {
+                    eprintln!("--filter: {}", err);
+                    exit(2);
+                }
+            },
+            "--format" => options.format = value().parse().unwrap_or_else(|_| usage()),
+            "--count" => options.count = true,
+            "--group-by" => options.group_by.push(value().parse().unwrap_or_else(|_| usage())),
+            "--stats" => options.stats.push(value().parse().unwrap_or_else(|_| usage())),
+            "--histogram" => options.histogram = true,
+            "-h" | "--help" => usage(),
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.is_empty() {
+        paths.push("-".to_owned());
+    }
+
+    let aggregates = options.count || !options.group_by.is_empty() || !options.stats.is_empty();
+    if options.histogram && options.stats.is_empty() {
+        usage();
+    }
+
+    if options.follow {
+        if paths.len() != 1 || paths[0] == "-" || aggregates {
+            usage();
+        }
+        if let Err(err) = follow(&paths[0], &options) {
+            eprintln!("{}: {:?}", paths[0], err);
+            exit(1);
+        }
+        return;
+    }
+
+    let streams = paths.iter().map(|path| match open(path, options.since) {
+        Ok(stream) => stream,
+        Err(err) => {
+            eprintln!("{}: {:?}", path, err);
+            exit(2);
+        }
+    }).collect();
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut failed = false;
+    let mut aggregator = if aggregates {
+        Some(Aggregator::new(options.group_by.clone(), options.stats.clone()))
+    } else {
+        None
+    };
+    let mut reader = MergeReader::new(streams);
+    loop {
+        match reader.next_value() {
+            Ok(Some(merged)) => {
+                if !options.selects(&merged.value) {
+                    continue;
+                }
+                if let Some(aggregator) = &mut aggregator {
+                    if let Err(err) = aggregator.add(&merged.value) {
+                        eprintln!("{}: {:?}", paths[merged.stream], err);
+                        failed = true;
+                    }
+                } else if writeln!(out, "{}", render(&merged.value, options.format)).is_err() {
+                    // E.g. piped into `head`.
+                    return;
+                }
+            }
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("{}: {:?}", paths[err.stream], err.error);
+                failed = true;
+            }
+        }
+    }
+
+    if let Some(aggregator) = &aggregator {
+        if report(&mut out, aggregator, options.histogram).is_err() {
+            return;
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+fn report(out: &mut impl Write, aggregator: &Aggregator, histogram: bool) -> io::Result<()> {
+    let mut groups: Vec<_> = aggregator.groups().iter().collect();
+    groups.sort_by_key(|g| std::cmp::Reverse(g.count));
+
+    for group in groups {
+        let mut line = String::new();
+        for (path, scalar) in aggregator.group_by().iter().zip(&group.key) {
+            match scalar {
+                Some(scalar) => line.push_str(&format!("{}={} ", path, scalar)),
+                None => line.push_str(&format!("{}=- ", path)),
+            }
+        }
+        line.push_str(&format!("count={}", group.count));
+
+        for (path, summary) in aggregator.summarized().iter().zip(&group.summaries) {
+            let (min, max, mean) = match (summary.min, summary.max, summary.mean()) {
+                (Some(min), Some(max), Some(mean)) => (min, max, mean),
+                _ => {
+                    line.push_str(&format!(" {}: -", path));
+                    continue;
+                }
+            };
+            line.push_str(&format!(" {}: sum={} min={} max={} mean={:.1}", path, summary.sum, min, max, mean));
+            for (name, q) in &[("p50", 0.5), ("p90", 0.9), ("p99", 0.99)] {
+                if let Some(val) = summary.quantile(*q) {
+                    line.push_str(&format!(" {}={}", name, val));
+                }
+            }
+        }
+        writeln!(out, "{}", line)?;
+
+        if histogram {
+            for (path, summary) in aggregator.summarized().iter().zip(&group.summaries) {
+                for (low, high, count) in summary.sketch.buckets() {
+                    writeln!(out, "    {} {}..={} {}", path, low, high, count)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn open(path: &str, since: Option<u64>) -> Result<StreamReader<Box<dyn RecordSource>>, file::Error> {
+    if path == "-" {
+        let input: Box<dyn BufRead> = Box::new(BufReader::new(io::stdin()));
+        return Ok(FileReader::new(input)?.into_stream().boxed());
+    }
+    if path.ends_with(".zst") {
+        let segment = Segment { seq: 0, path: path.into(), compressed: true };
+        return Ok(open_segment(&segment)?.into_stream().boxed());
+    }
+
+    let mut reader = FileReader::open(Path::new(path))?;
+    if let Some(since) = since {
+        seek(&mut reader, since)?;
+    }
+    Ok(reader.into_stream().boxed())
+}
+
+fn seek(reader: &mut FileReader<BufReader<File>>, since: u64) -> Result<(), file::Error> {
+    if let Some(index) = reader.seek_index()? {
+        reader.seek_time(&index, since)?;
+    }
+    Ok(())
+}
+
+fn follow(path: &str, options: &Options) -> Result<(), file::Error> {
+    let mut reader = FileReader::open(Path::new(path))?;
+    if let Some(since) = options.since {
+        seek(&mut reader, since)?;
+    }
+
+    reader.follow();
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    loop {
+        if let Some(value) = reader.next_value()? {
+            if options.selects(&value) && writeln!(out, "{}", render(&value, options.format)).is_err() {
+                return Ok(());
+            }
+            continue;
+        }
+        if reader.source().is_complete() {
+            return Ok(());
+        }
+
+        out.flush()?;
+        std::thread::sleep(FOLLOW_INTERVAL);
+    }
+}
@@ -0,0 +1,49 @@
+//! Rebuilds the footer indices of log files, e.g. of ones left behind by a
+//! process that crashed, or written before the seek index was added. A
+//! partial frame at the end of a file is dropped.
+//!
+//! Usage: logpack-reindex FILE...
+
+use logpack::file::reindex;
+
+use std::path::Path;
+use std::process::exit;
+
+fn usage() -> ! {
+    eprintln!("usage: logpack-reindex FILE...");
+    exit(2);
+}
+
+fn main() {
+    let mut paths = vec![];
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "-h" | "--help" => usage(),
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.is_empty() {
+        usage();
+    }
+
+    let mut failed = false;
+    for path in &paths {
+        match reindex(Path::new(path)) {
+            Ok(reindexed) => {
+                println!("{}: {} frames, {} seek points, {} bad frames, {} bytes dropped", path,
+                         reindexed.frames, reindexed.seek_points, reindexed.bad_frames,
+                         reindexed.dropped);
+            }
+            Err(err) => {
+                eprintln!("{}: {:?}", path, err);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        exit(1);
+    }
+}
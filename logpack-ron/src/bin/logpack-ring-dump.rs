@@ -0,0 +1,63 @@
+//! Prints the records left in an mmap ring file, e.g. by a process that
+//! crashed, from the oldest to the newest.
+//!
+//! Usage: logpack-ring-dump [--header] RING
+//!
+//! With `--header`, the ring's cursors are printed first.
+
+use logpack::mmap_ring::Snapshot;
+use logpack::stream::StreamReader;
+use logpack_ron::record::{render, Format};
+
+use std::path::Path;
+use std::process::exit;
+
+fn usage() -> ! {
+    eprintln!("usage: logpack-ring-dump [--header] RING");
+    exit(2);
+}
+
+fn main() {
+    let mut header = false;
+    let mut paths = vec![];
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--header" => header = true,
+            "-h" | "--help" => usage(),
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.len() != 1 {
+        usage();
+    }
+
+    let snapshot = match Snapshot::open(Path::new(&paths[0])) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            eprintln!("{}: {:?}", paths[0], err);
+            exit(2);
+        }
+    };
+
+    if header {
+        let (start, cursor) = snapshot.cursors();
+        println!("pid {}, wrapped {} times, records at {}..{}", snapshot.pid(), snapshot.wraps(),
+                 start, cursor);
+    }
+
+    let mut reader = StreamReader::new(snapshot);
+    loop {
+        let value = match reader.next_value() {
+            Ok(Some(value)) => value,
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("{}: {:?}", paths[0], err);
+                exit(1);
+            }
+        };
+
+        println!("{}", render(&value, Format::Plain));
+    }
+}
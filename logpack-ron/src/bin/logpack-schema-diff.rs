@@ -0,0 +1,57 @@
+//! Compares the logging schemas of two builds and reports whether records
+//! written by the old one can still be read with the new one.
+//!
+//! Usage: logpack-schema-diff [--ron] OLD NEW
+//!
+//! Exits with status 1 when an incompatible change is found.
+
+use logpack::compat;
+use logpack_ron::schema::load_name_map;
+
+use std::path::Path;
+use std::process::exit;
+
+fn usage() -> ! {
+    eprintln!("usage: logpack-schema-diff [--ron] OLD NEW");
+    exit(2);
+}
+
+fn main() {
+    let mut as_ron = false;
+    let mut paths = vec![];
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--ron" => as_ron = true,
+            "-h" | "--help" => usage(),
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.len() != 2 {
+        usage();
+    }
+
+    let load = |path: &str| match load_name_map(Path::new(path)) {
+        Ok(map) => map,
+        Err(err) => {
+            eprintln!("{}: {:?}", path, err);
+            exit(2);
+        }
+    };
+
+    let old = load(&paths[0]);
+    let new = load(&paths[1]);
+    let report = compat::compare(&old, &new);
+
+    if as_ron {
+        let config = ron::ser::PrettyConfig::default();
+        println!("{}", ron::ser::to_string_pretty(&report, config).unwrap());
+    } else {
+        print!("{}", report);
+    }
+
+    if !report.is_compatible() {
+        exit(1);
+    }
+}
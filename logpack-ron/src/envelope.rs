@@ -0,0 +1,149 @@
+use logpack::envelope::{Envelope, Level};
+
+use ansi_term::ANSIString;
+use ansi_term::Colour::RGB;
+use ansi_term::Colour;
+use std::fmt;
+
+/// Renders the envelope of a log entry as a conventional log line prefix,
+/// e.g. `2024-03-05T14:02:11.123456Z  WARN main[4711] net::conn:88: `.
+pub struct Prefix<'a> {
+    envelope: &'a Envelope<'a>,
+    callsite: Option<&'a str>,
+}
+
+impl<'a> Prefix<'a> {
+    pub fn new(envelope: &'a Envelope<'a>) -> Self {
+        Self { envelope, callsite: None }
+    }
+
+    /// Shows the callsite by name rather than by its id.
+    pub fn with_callsite(self, callsite: &'a str) -> Self {
+        Self { callsite: Some(callsite), ..self }
+    }
+
+    fn thread(&self) -> String {
+        let name = if self.envelope.thread_name.is_empty() { "-" } else { self.envelope.thread_name };
+        format!("{}[{}]", name, self.envelope.thread_id)
+    }
+
+    fn callsite(&self) -> String {
+        match self.callsite {
+            Some(callsite) => callsite.to_owned(),
+            None => format!("#{}", self.envelope.callsite),
+        }
+    }
+
+    pub fn ansi(&self, output: &mut Vec<ANSIString<'static>>) {
+        output.push(TIME.paint(format_time(self.envelope.time)));
+        output.push(PUNCT.paint(" ".to_string()));
+        output.push(level_colour(self.envelope.level).bold().paint(format!("{:>5}", self.envelope.level)));
+        output.push(PUNCT.paint(" ".to_string()));
+        output.push(THREAD.paint(self.thread()));
+        output.push(PUNCT.paint(" ".to_string()));
+        output.push(CALLSITE.paint(self.callsite()));
+        output.push(PUNCT.paint(": ".to_string()));
+    }
+}
+
+impl<'a> fmt::Display for Prefix<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:>5} {} {}: ", format_time(self.envelope.time), self.envelope.level,
+               self.thread(), self.callsite())
+    }
+}
+
+static TIME: Colour     = RGB(120, 120, 120);
+static PUNCT: Colour    = RGB(255, 255, 180);
+static THREAD: Colour   = RGB(180, 180, 255);
+static CALLSITE: Colour = RGB(150, 150, 150);
+
+fn level_colour(level: Level) -> Colour {
+    match level {
+        Level::Trace => RGB(120, 120, 120),
+        Level::Debug => RGB(100, 160, 255),
+        Level::Info => RGB(100, 200, 100),
+        Level::Warn => RGB(255, 200, 0),
+        Level::Error => RGB(255, 80, 80),
+    }
+}
+
+/// Formats nanoseconds since the Unix epoch as an RFC 3339 UTC timestamp
+/// with microseconds.
+pub fn format_time(ns: u64) -> String {
+    let secs = ns / 1_000_000_000;
+    let micros = (ns % 1_000_000_000) / 1000;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+            year, month, day, rem / 3600, (rem % 3600) / 60, rem % 60, micros)
+}
+
+/// Parses a time as written by `format_time`, with any number of fractional
+/// digits or none, or as plain nanoseconds since the Unix epoch.
+pub fn parse_time(s: &str) -> Option<u64> {
+    if let Ok(ns) = s.parse() {
+        return Some(ns);
+    }
+
+    let s = s.strip_suffix('Z').unwrap_or(s);
+    let (date, time) = s.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<u32>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (time, fraction) = match time.split_once('.') {
+        Some((time, fraction)) => (time, fraction),
+        None => (time, ""),
+    };
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let mut nanos = 0;
+    for (idx, digit) in fraction.chars().enumerate() {
+        let digit = digit.to_digit(10)? as u64;
+        if idx < 9 {
+            nanos += digit * 10u64.pow(8 - idx as u32);
+        }
+    }
+
+    let days = days_from_civil(year as i64, month, day);
+    if days < 0 {
+        return None;
+    }
+    // Times past 2554 don't fit.
+    let secs = (days as u64).checked_mul(86400)?.checked_add(hour * 3600 + minute * 60 + second)?;
+    secs.checked_mul(1_000_000_000)?.checked_add(nanos)
+}
+
+/// The inverse of `civil_from_days`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = (year - era * 400) as u64;
+    let mp = if month > 2 { month - 3 } else { month + 9 } as u64;
+    let doy = (153 * mp + 2) / 5 + day as u64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe as i64 - 719_468
+}
+
+/// Converts days since 1970-01-01 to a proleptic Gregorian date, after
+/// Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = (z - era * 146_097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe as i64 + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -3,21 +3,33 @@ use logpack::decoder::TypeNameId;
 use std::fmt::Write;
 
 pub mod ansi;
+pub mod schema;
+pub mod envelope;
+pub mod message;
+pub mod record;
 
 pub struct Repr<'a> {
     output: &'a mut String,
     enum_names: bool,
+    plain_strings: bool,
 }
 
 impl<'a> Repr<'a> {
     pub fn new(output: &'a mut String) -> Self {
         let enum_names = false;
-        Self { output, enum_names }
+        let plain_strings = false;
+        Self { output, enum_names, plain_strings }
     }
 
     pub fn with_enum_names(self) -> Self {
         Self { enum_names: true, ..self }
     }
+
+    /// Writes strings as they are rather than quoted and escaped, as `{}`
+    /// would.
+    pub fn with_plain_strings(self) -> Self {
+        Self { plain_strings: true, ..self }
+    }
 }
 
 impl<'a> Callbacks for Repr<'a> {
@@ -64,7 +76,11 @@ impl<'a> Callbacks for Repr<'a> {
     }
 
     fn handle_string(&mut self, val: &str)  {
-        write!(self.output, "{:?}", val).unwrap();
+        if self.plain_strings {
+            *self.output += val;
+        } else {
+            write!(self.output, "{:?}", val).unwrap();
+        }
     }
 
     fn handle_unit(&mut self)  {
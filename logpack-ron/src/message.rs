@@ -0,0 +1,93 @@
+use logpack::buffers::BufDecoder;
+use logpack::decoder::{self, Decoder, ResolvedDesc};
+use logpack::stream::Value;
+use logpack::Description;
+
+use crate::Repr;
+
+/// Renders the message of an entry written by `log!`, by applying its
+/// callsite's format string to the decoded arguments. Returns `None` for
+/// values that didn't come from a callsite.
+pub fn message(value: &Value) -> Option<Result<String, decoder::Error>> {
+    value.callsite.map(|callsite| format_message(&callsite.format, value))
+}
+
+/// Applies a format string to the arguments tuple in `value`.
+///
+/// `{}` writes strings unquoted, and `{:?}` writes them the way `Repr`
+/// does. Other values look the same either way. Positions such as `{1}` are
+/// honored, while any other format spec is ignored. Placeholders without a
+/// matching argument are left as they are.
+pub fn format_message(format: &str, value: &Value) -> Result<String, decoder::Error> {
+    let args: &[ResolvedDesc] = match value.desc {
+        Description::Tuple(args) => args,
+        Description::Unit => &[],
+        desc => std::slice::from_ref(desc),
+    };
+
+    let display = render_args(args, value, true)?;
+    let debug = render_args(args, value, false)?;
+
+    let mut output = String::new();
+    let mut next = 0;
+    let mut rest = format;
+
+    while let Some(pos) = rest.find(['{', '}']) {
+        output += &rest[..pos];
+        let tail = &rest[pos..];
+
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            output += &tail[..1];
+            rest = &tail[2..];
+            continue;
+        }
+
+        let end = match tail.find('}') {
+            Some(end) if tail.starts_with('{') => end,
+            _ => {
+                output += &tail[..1];
+                rest = &tail[1..];
+                continue;
+            }
+        };
+
+        let spec = &tail[1..end];
+        let (position, spec) = match spec.find(':') {
+            Some(colon) => (&spec[..colon], &spec[colon + 1..]),
+            None => (spec, ""),
+        };
+        let index = if position.is_empty() {
+            next += 1;
+            Some(next - 1)
+        } else {
+            position.parse::<usize>().ok()
+        };
+
+        let rendered = if spec.ends_with('?') { &debug } else { &display };
+        match index.and_then(|i| rendered.get(i)) {
+            Some(arg) => output += arg,
+            None => output += &tail[..=end],
+        }
+        rest = &tail[end + 1..];
+    }
+
+    output += rest;
+    Ok(output)
+}
+
+fn render_args(args: &[ResolvedDesc], value: &Value, plain: bool) -> Result<Vec<String>, decoder::Error> {
+    let mut decoder = Decoder::new(value.names, BufDecoder::new(value.data));
+    let mut rendered = vec![];
+
+    for arg in args {
+        let mut output = String::new();
+        let mut repr = Repr::new(&mut output);
+        if plain {
+            repr = repr.with_plain_strings();
+        }
+        decoder.decode(arg, &mut repr)?;
+        rendered.push(output);
+    }
+
+    Ok(rendered)
+}
@@ -0,0 +1,78 @@
+use logpack::stream::Value;
+
+use crate::ansi;
+use crate::envelope::Prefix;
+use crate::message::message;
+use crate::Repr;
+
+use ansi_term::ANSIStrings;
+use std::str::FromStr;
+
+/// How the command-line tools print records.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// A log line: the envelope as a prefix, followed by the message of a
+    /// `log!` entry or by the value.
+    Plain,
+    /// Like `Plain`, in color.
+    Color,
+    /// Only the value, with enum names.
+    Ron,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "color" => Ok(Format::Color),
+            "ron" => Ok(Format::Ron),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Renders a value as a line, without the newline. Values that fail to
+/// decode are shown by their error.
+pub fn render(value: &Value, format: Format) -> String {
+    let location = value.callsite.map(|callsite| callsite.location());
+    let prefix = value.envelope.as_ref().map(|envelope| match &location {
+        Some(location) => Prefix::new(envelope).with_callsite(location),
+        None => Prefix::new(envelope),
+    });
+    let text = if format == Format::Ron { None } else { message(value) };
+
+    let mut output = vec![];
+    if format == Format::Color {
+        if let Some(prefix) = &prefix {
+            prefix.ansi(&mut output);
+        }
+        match text {
+            Some(Ok(text)) => output.push(text.into()),
+            Some(Err(err)) => output.push(format!("<{:?}>", err).into()),
+            None => {
+                let mut repr = ansi::Repr::new(&mut output);
+                if let Err(err) = value.decode(&mut repr) {
+                    output.push(format!("<{:?}>", err).into());
+                }
+            }
+        }
+        return ANSIStrings(&output).to_string();
+    }
+
+    let text = text.unwrap_or_else(|| {
+        let mut output = String::new();
+        let decoded = match format {
+            Format::Ron => value.decode(&mut Repr::new(&mut output).with_enum_names()),
+            _ => value.decode(&mut Repr::new(&mut output)),
+        };
+        decoded.map(|_| output)
+    });
+    let text = text.unwrap_or_else(|err| format!("<{:?}>", err));
+
+    match prefix {
+        Some(prefix) if format == Format::Plain => format!("{}{}", prefix, text),
+        _ => text,
+    }
+}
@@ -0,0 +1,55 @@
+use logpack::NameMap;
+use logpack::ResolvedDesc;
+use logpack::decoder::FeedError;
+#[cfg(feature = "registry")]
+use logpack::registry;
+
+#[cfg(feature = "registry")]
+use std::fs;
+use std::io;
+#[cfg(feature = "registry")]
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Ron(ron::error::SpannedError),
+    Feed(FeedError),
+    #[cfg(feature = "registry")]
+    Registry(registry::Error),
+}
+
+/// Reads a schema that was saved as RON, either as a whole `NameMap` or as a
+/// list of descriptions such as the ones returned by `logpack_describe`.
+pub fn name_map_from_ron(text: &str) -> Result<NameMap, Error> {
+    let ron_err = match ron::de::from_str::<NameMap>(text) {
+        Ok(map) => return Ok(map),
+        Err(err) => err,
+    };
+
+    let descs: Vec<ResolvedDesc> = match ron::de::from_str(text) {
+        Ok(descs) => descs,
+        Err(_) => return Err(Error::Ron(ron_err)),
+    };
+
+    let mut map = NameMap::new();
+    for desc in descs {
+        map.feed(desc).map_err(Error::Feed)?;
+    }
+    Ok(map)
+}
+
+/// Loads a registry file in any of its formats, or a RON schema as accepted
+/// by `name_map_from_ron`. Requires the `registry` feature.
+#[cfg(feature = "registry")]
+pub fn load_name_map(path: &Path) -> Result<NameMap, Error> {
+    let bytes = fs::read(path).map_err(Error::Io)?;
+    if registry::is_registry(&bytes) {
+        let mut map = NameMap::new();
+        registry::read_into(&bytes[..], &mut map).map_err(Error::Registry)?;
+        return Ok(map);
+    }
+
+    let text = String::from_utf8_lossy(&bytes);
+    name_map_from_ron(&text)
+}
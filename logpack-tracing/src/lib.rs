@@ -0,0 +1,327 @@
+use logpack::{Description, Encoder, Level, Logpack, Named, RefDesc, SeenTypes, Struct};
+use logpack::buffers::BufEncoder;
+use logpack::callsite::StreamSink;
+use logpack::envelope::Envelope;
+use logpack::stream::RecordSink;
+use logpack_derive::Logpack;
+
+use tracing_core::callsite::Identifier;
+use tracing_core::field::{Field, Visit};
+use tracing_core::{span, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+//////////////////////////////////////////////////////////////////////////
+//
+// `tracing` layer
+//
+// Events and span lifecycles become log entries. The value of an event or
+// of a new span is a tuple of a fixed header (`Event` or `NewSpan`) and a
+// struct of its fields, named after the callsite. Field values are encoded
+// as they are, so the struct's description depends on the value types seen
+// at the callsite; each such combination is described once.
+//
+// `tracing` only hands over primitives and `Debug` values. Values wrapped
+// in `Packed` are captured as their own logpack type, while other `Debug`
+// values are written as strings.
+
+#[derive(Logpack, Debug)]
+pub struct Event<'a> {
+    /// The span the event happened in.
+    pub span: Option<u64>,
+    pub target: &'a str,
+}
+
+#[derive(Logpack, Debug)]
+pub struct NewSpan<'a> {
+    pub id: u64,
+    pub parent: Option<u64>,
+    pub target: &'a str,
+}
+
+#[derive(Logpack, Debug)]
+pub enum SpanEvent {
+    Enter(u64),
+    Exit(u64),
+    Close(u64),
+}
+
+pub fn level(level: &tracing_core::Level) -> Level {
+    match *level {
+        tracing_core::Level::ERROR => Level::Error,
+        tracing_core::Level::WARN => Level::Warn,
+        tracing_core::Level::INFO => Level::Info,
+        tracing_core::Level::DEBUG => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Structural capture
+
+/// Records a field as its logpack type rather than as a string, e.g.
+/// `info!(request = ?Packed(&request))`. Other layers format it as the
+/// type's name.
+pub struct Packed<T>(pub T);
+
+struct Captured {
+    fallback: &'static str,
+    type_key: &'static str,
+    describe: fn(&mut SeenTypes) -> RefDesc,
+    bytes: Vec<u8>,
+}
+
+thread_local! {
+    /// Set while a field is being captured, to be filled by `Packed`.
+    static CAPTURE: RefCell<Option<Option<Captured>>> = const { RefCell::new(None) };
+}
+
+impl<T: Logpack + Encoder> fmt::Debug for Packed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fallback = logpack::short_type_name(std::any::type_name::<T>());
+
+        CAPTURE.with(|capture| {
+            if let Some(slot) = capture.borrow_mut().as_mut() {
+                let mut bytes = vec![0; self.0.logpack_sizer()];
+                if self.0.logpack_encode(&mut BufEncoder::new(&mut bytes)).is_ok() {
+                    *slot = Some(Captured {
+                        fallback,
+                        type_key: std::any::type_name::<T>(),
+                        describe: T::logpack_describe,
+                        bytes,
+                    });
+                }
+            }
+        });
+
+        f.write_str(fallback)
+    }
+}
+
+/// Formats the value, returning what a `Packed` value captured instead if
+/// the value was nothing but that, e.g. not a message mentioning it.
+fn capture(value: &dyn fmt::Debug) -> Result<Captured, String> {
+    CAPTURE.with(|capture| *capture.borrow_mut() = Some(None));
+    let text = format!("{:?}", value);
+    let captured = CAPTURE.with(|capture| capture.borrow_mut().take()).and_then(|x| x);
+
+    match captured {
+        Some(captured) if captured.fallback == text => Ok(captured),
+        _ => Err(text),
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Fields
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum FieldType {
+    U64,
+    I64,
+    Bool,
+    Str,
+    Packed(&'static str),
+}
+
+#[derive(Default)]
+struct Fields {
+    types: Vec<(&'static str, FieldType)>,
+    describers: Vec<fn(&mut SeenTypes) -> RefDesc>,
+    buf: Vec<u8>,
+}
+
+impl Fields {
+    fn put<T: Encoder + ?Sized>(&mut self, field: &Field, ty: FieldType, value: &T) {
+        let start = self.buf.len();
+        self.buf.resize(start + value.logpack_sizer(), 0);
+        if value.logpack_encode(&mut BufEncoder::new(&mut self.buf[start..])).is_ok() {
+            self.types.push((field.name(), ty));
+        } else {
+            self.buf.truncate(start);
+        }
+    }
+
+    fn describe(&self, seen: &mut SeenTypes, name: &'static str, key: &'static str) -> RefDesc {
+        let describe = |seen: &mut SeenTypes| self.describe(seen, name, key);
+        let (first_seen, typename_id) = seen.make_name_for_id(name, key, &describe);
+        if !first_seen {
+            return Description::ByName(typename_id, None);
+        }
+
+        let mut describers = self.describers.iter();
+        let fields: Vec<_> = self.types.iter().map(|(name, ty)| {
+            let desc = match ty {
+                FieldType::U64 => Description::U64,
+                FieldType::I64 => Description::I64,
+                FieldType::Bool => Description::Bool,
+                FieldType::Str => Description::String,
+                FieldType::Packed(_) => (describers.next().unwrap())(seen),
+            };
+            (*name, desc)
+        }).collect();
+
+        let fields = if fields.is_empty() { Struct::Unit } else { Struct::Named(fields) };
+        Description::ByName(typename_id, Some(Named::Struct(fields)))
+    }
+}
+
+impl Visit for Fields {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.put(field, FieldType::U64, &value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.put(field, FieldType::I64, &value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.put(field, FieldType::Bool, &value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.put(field, FieldType::Str, value);
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.put(field, FieldType::Str, &value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match capture(value) {
+            Ok(captured) => {
+                self.types.push((field.name(), FieldType::Packed(captured.type_key)));
+                self.describers.push(captured.describe);
+                self.buf.extend_from_slice(&captured.bytes);
+            }
+            Err(text) => self.put(field, FieldType::Str, &text),
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Layer
+
+/// A callsite and the types of the fields an event or span there had.
+type FieldsId = (Identifier, Vec<(&'static str, FieldType)>);
+
+/// A `tracing_subscriber::Layer` writing to a `StreamSink`, which may be
+/// shared with `logpack::log!`.
+pub struct LogpackLayer<W: RecordSink + Send + 'static> {
+    sink: &'static StreamSink<W>,
+    level: Level,
+    /// Type keys of the field combinations seen per callsite.
+    keys: Mutex<HashMap<FieldsId, &'static str>>,
+}
+
+impl<W: RecordSink + Send + 'static> LogpackLayer<W> {
+    pub fn new(sink: &'static StreamSink<W>) -> Self {
+        Self {
+            sink,
+            level: Level::Trace,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_level(self, level: Level) -> Self {
+        Self { level, ..self }
+    }
+
+    pub fn sink(&self) -> &'static StreamSink<W> {
+        self.sink
+    }
+
+    fn key(&self, metadata: &'static Metadata<'static>, fields: &Fields) -> &'static str {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        let id = (metadata.callsite(), fields.types.clone());
+        let next = keys.len();
+        keys.entry(id).or_insert_with(|| {
+            let key = format!("tracing#{}:{}", next, metadata.name());
+            Box::leak(key.into_boxed_str())
+        })
+    }
+
+    fn write_fields<H>(&self, metadata: &'static Metadata<'static>, header: &H, fields: Fields)
+        where H: Logpack + Encoder
+    {
+        let key = self.key(metadata, &fields);
+        let mut value = vec![0; header.logpack_sizer()];
+        if header.logpack_encode(&mut BufEncoder::new(&mut value)).is_err() {
+            return;
+        }
+        value.extend_from_slice(&fields.buf);
+
+        let envelope = Envelope::now(level(metadata.level()), 0);
+        self.sink.write_with(|writer| {
+            let index = writer.register_with(key, |seen| Description::Tuple(vec![
+                H::logpack_describe(seen),
+                fields.describe(seen, metadata.name(), key),
+            ]))?;
+            writer.write_entry_bytes(&envelope, index, &value)
+        });
+    }
+
+    fn write_span_event<S>(&self, id: &span::Id, ctx: &Context<'_, S>, event: SpanEvent)
+        where S: Subscriber + for<'a> LookupSpan<'a>
+    {
+        let level = match ctx.metadata(id) {
+            Some(metadata) => level(metadata.level()),
+            None => return,
+        };
+        if level >= self.level {
+            self.sink.write_entry(&Envelope::now(level, 0), &event);
+        }
+    }
+}
+
+impl<S, W> Layer<S> for LogpackLayer<W>
+    where S: Subscriber + for<'a> LookupSpan<'a>,
+          W: RecordSink + Send + 'static
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let metadata = attrs.metadata();
+        if level(metadata.level()) < self.level {
+            return;
+        }
+
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let header = NewSpan {
+            id: id.into_u64(),
+            parent: ctx.span(id).and_then(|span| span.parent()).map(|parent| parent.id().into_u64()),
+            target: metadata.target(),
+        };
+        self.write_fields(metadata, &header, fields);
+    }
+
+    fn on_event(&self, event: &tracing_core::Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if level(metadata.level()) < self.level {
+            return;
+        }
+
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let header = Event {
+            span: ctx.event_span(event).map(|span| span.id().into_u64()),
+            target: metadata.target(),
+        };
+        self.write_fields(metadata, &header, fields);
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.write_span_event(id, &ctx, SpanEvent::Enter(id.into_u64()));
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.write_span_event(id, &ctx, SpanEvent::Exit(id.into_u64()));
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        self.write_span_event(&id, &ctx, SpanEvent::Close(id.into_u64()));
+    }
+}
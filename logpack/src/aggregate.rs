@@ -0,0 +1,235 @@
+use super::decoder::{self, NameMap, ResolvedDesc};
+use super::envelope::Envelope;
+use super::path::{Extractor, Path, Scalar};
+use super::stream::Value;
+
+use std::collections::{BTreeMap, HashMap};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Aggregation
+//
+// `Aggregator` sums up records instead of listing them, e.g. the p99 of
+// `latency_us` grouped by `endpoint`, or the count of each variant of
+// `error`. Records are put in groups by what a set of paths address in
+// them, where a missing part is a key of its own, and each group counts its
+// records and summarizes the integers found at another set of paths.
+//
+// Quantiles come from a sketch that keeps a count per bucket of values
+// rather than the values themselves. Below 32 each bucket holds a single
+// value, and above it each power of two is split into 32 buckets, so that
+// a quantile is off by less than about 3% of it.
+//
+// Like filters, aggregation only reads the fields of values that the paths
+// go through, and doesn't read values at all when grouping by builtin
+// fields alone.
+
+/// Values below this have buckets of their own.
+const SUB_BUCKETS: u32 = 32;
+const SUB_BITS: u32 = 5;
+
+fn bucket(magnitude: u128) -> u32 {
+    if magnitude < SUB_BUCKETS as u128 {
+        return magnitude as u32;
+    }
+    let shift = 127 - magnitude.leading_zeros() - SUB_BITS;
+    shift * SUB_BUCKETS + (magnitude >> shift) as u32
+}
+
+/// The least and the greatest magnitude in a bucket.
+fn bucket_bounds(bucket: u32) -> (u128, u128) {
+    if bucket < SUB_BUCKETS {
+        return (bucket as u128, bucket as u128);
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    let mantissa = (bucket % SUB_BUCKETS + SUB_BUCKETS) as u128;
+    (mantissa << shift, ((mantissa + 1) << shift) - 1)
+}
+
+fn to_i128(magnitude: u128, negative: bool) -> i128 {
+    let val = magnitude.min(i128::MAX as u128) as i128;
+    if negative { -val } else { val }
+}
+
+/// A histogram of integers with buckets of about equal relative size.
+#[derive(Clone, Default, Debug)]
+pub struct Sketch {
+    /// Counts by bucket, negated for negative values so that the keys are in
+    /// the order of the values.
+    counts: BTreeMap<i32, u64>,
+    count: u64,
+}
+
+impl Sketch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, val: i128) {
+        let bucket = bucket(val.unsigned_abs()) as i32;
+        let key = if val < 0 { -bucket } else { bucket };
+        *self.counts.entry(key).or_insert(0) += 1;
+        self.count += 1;
+    }
+
+    pub fn merge(&mut self, other: &Sketch) {
+        for (key, count) in &other.counts {
+            *self.counts.entry(*key).or_insert(0) += count;
+        }
+        self.count += other.count;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The buckets holding values, in order, as the least and the greatest
+    /// value they stand for and how many values fell in them.
+    pub fn buckets(&self) -> impl Iterator<Item = (i128, i128, u64)> + '_ {
+        self.counts.iter().map(|(key, count)| {
+            let (low, high) = bucket_bounds(key.unsigned_abs());
+            if *key < 0 {
+                (to_i128(high, true), to_i128(low, true), *count)
+            } else {
+                (to_i128(low, false), to_i128(high, false), *count)
+            }
+        })
+    }
+
+    /// Estimates the value that a fraction `q` of the values are at most,
+    /// e.g. 0.99 for the p99.
+    pub fn quantile(&self, q: f64) -> Option<i128> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q * self.count as f64).ceil() as u64).clamp(1, self.count);
+
+        let mut seen = 0;
+        for (low, high, count) in self.buckets() {
+            seen += count;
+            if seen >= rank {
+                return Some(low + (high - low) / 2);
+            }
+        }
+        None
+    }
+}
+
+/// The integers found at a path in the records of a group. Parts of other
+/// kinds are left out.
+#[derive(Clone, Default, Debug)]
+pub struct Summary {
+    pub sum: i128,
+    pub min: Option<i128>,
+    pub max: Option<i128>,
+    pub sketch: Sketch,
+}
+
+impl Summary {
+    pub fn add(&mut self, val: i128) {
+        self.sum = self.sum.saturating_add(val);
+        self.min = Some(self.min.map_or(val, |min| min.min(val)));
+        self.max = Some(self.max.map_or(val, |max| max.max(val)));
+        self.sketch.add(val);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.sketch.count()
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        match self.count() {
+            0 => None,
+            count => Some(self.sum as f64 / count as f64),
+        }
+    }
+
+    /// Like `Sketch::quantile`, within the least and the greatest value.
+    pub fn quantile(&self, q: f64) -> Option<i128> {
+        let val = self.sketch.quantile(q)?;
+        Some(val.clamp(self.min?, self.max?))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Group {
+    /// What the group-by paths address in its records, in their order.
+    pub key: Vec<Option<Scalar>>,
+    pub count: u64,
+    /// By the summarized paths, in their order.
+    pub summaries: Vec<Summary>,
+}
+
+pub struct Aggregator {
+    /// The group-by paths followed by the summarized ones.
+    extractor: Extractor,
+    group_by: usize,
+    index: HashMap<Vec<Option<Scalar>>, usize>,
+    groups: Vec<Group>,
+}
+
+impl Aggregator {
+    pub fn new(group_by: Vec<Path>, summarize: Vec<Path>) -> Self {
+        let len = group_by.len();
+        let mut paths = group_by;
+        paths.extend(summarize);
+
+        Self {
+            extractor: Extractor::new(paths),
+            group_by: len,
+            index: HashMap::new(),
+            groups: vec![],
+        }
+    }
+
+    pub fn group_by(&self) -> &[Path] {
+        &self.extractor.paths()[..self.group_by]
+    }
+
+    pub fn summarized(&self) -> &[Path] {
+        &self.extractor.paths()[self.group_by..]
+    }
+
+    /// The groups, in the order of their first record.
+    pub fn groups(&self) -> &[Group] {
+        &self.groups
+    }
+
+    pub fn into_groups(self) -> Vec<Group> {
+        self.groups
+    }
+
+    pub fn add(&mut self, value: &Value) -> Result<(), decoder::Error> {
+        self.add_data(value.envelope.as_ref(), value.desc, value.names, value.data)
+    }
+
+    /// Like `add`, for a value of type `desc` encoded in `data`.
+    pub fn add_data(&mut self, envelope: Option<&Envelope>, desc: &ResolvedDesc, names: &NameMap,
+                    data: &[u8]) -> Result<(), decoder::Error>
+    {
+        let mut values = self.extractor.extract_data(envelope, desc, names, data)?;
+        let summarized = values.split_off(self.group_by);
+
+        let idx = match self.index.get(&values) {
+            Some(idx) => *idx,
+            None => {
+                self.index.insert(values.clone(), self.groups.len());
+                self.groups.push(Group {
+                    key: values,
+                    count: 0,
+                    summaries: vec![Summary::default(); summarized.len()],
+                });
+                self.groups.len() - 1
+            }
+        };
+
+        let group = &mut self.groups[idx];
+        group.count += 1;
+        for (summary, scalar) in group.summaries.iter_mut().zip(summarized) {
+            if let Some(Scalar::Int(val)) = scalar {
+                summary.add(val);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,418 @@
+use super::envelope::peek_time;
+use super::file::{self, read_frame, Error};
+use super::framing::MAX_FRAME_LEN;
+use super::stream::{is_definition, RecordSink, RecordSource, RECORD_ENTRY};
+
+use serde_derive::{Serialize, Deserialize};
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Compressed blocks
+//
+// `BlockWriter` batches the value and entry records of a stream and writes
+// them compressed, as `RECORD_BLOCK` records whose type index is the codec:
+//
+//     block := u32(raw len) u32(records) data
+//     raw   := (u8(kind) u32(type) u32(len) payload)*
+//
+// Integers are little-endian. Definitions are written as they come, outside
+// of blocks, so that they can be read without decompressing anything. Blocks
+// hold at most `MAX_BLOCK_SIZE` bytes before compression, and readers take
+// ones claiming more than `MAX_FRAME_LEN` for corruption.
+//
+// When the writer is finished, it writes a `RECORD_BLOCK_INDEX` record
+// holding the bincode-serialized `BlockIndex`, followed by its own length as
+// a `u32`. In a log file it is one of the footer records, found walking back
+// from the file's index, and gives the offsets of the definitions and of the blocks
+// along with their time ranges, for reading entries of a time range without
+// going through the whole file.
+
+pub const RECORD_BLOCK: u8 = 6;
+pub const RECORD_BLOCK_INDEX: u8 = 7;
+
+pub const CODEC_LZ4: u32 = 1;
+pub const CODEC_ZSTD: u32 = 2;
+
+pub const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+
+/// The largest block before compression, leaving room within `MAX_FRAME_LEN`
+/// for data that compression makes larger.
+pub const MAX_BLOCK_SIZE: usize = MAX_FRAME_LEN / 2;
+
+const BLOCK_HEADER_LEN: usize = 8;
+const RECORD_HEADER_LEN: usize = 9;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Codec {
+    /// Requires the `lz4` feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// zstd at the given level. Requires the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Codec {
+    fn id(self) -> u32 {
+        match self {
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => CODEC_LZ4,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd(_) => CODEC_ZSTD,
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn compress(self, raw: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => Ok(lz4_flex::compress(raw)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd(level) => zstd::bulk::compress(raw, level),
+        }
+    }
+}
+
+#[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+fn decompress(codec: u32, data: &[u8], raw_len: usize) -> io::Result<Vec<u8>> {
+    match codec {
+        #[cfg(feature = "lz4")]
+        CODEC_LZ4 => lz4_flex::decompress(data, raw_len)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        #[cfg(feature = "zstd")]
+        CODEC_ZSTD => zstd::bulk::decompress(data, raw_len),
+        _ => Err(io::Error::new(io::ErrorKind::Unsupported,
+                                format!("block codec {} is not supported by this build", codec))),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct BlockInfo {
+    pub offset: u64,
+    pub records: u32,
+    /// The times of the first and last entries, if the block has any.
+    pub times: Option<(u64, u64)>,
+}
+
+impl BlockInfo {
+    pub fn overlaps(&self, from: u64, to: u64) -> bool {
+        self.times.is_some_and(|(first, last)| first <= to && last >= from)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct BlockIndex {
+    /// Offsets of the definition records.
+    pub definitions: Vec<u64>,
+    pub blocks: Vec<BlockInfo>,
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Writer
+
+pub struct BlockWriter<S: RecordSink> {
+    sink: S,
+    codec: Codec,
+    block_size: usize,
+    raw: Vec<u8>,
+    records: u32,
+    times: Option<(u64, u64)>,
+    out: Vec<u8>,
+    index: BlockIndex,
+}
+
+impl<S: RecordSink> BlockWriter<S> {
+    pub fn new(sink: S, codec: Codec) -> Self {
+        Self {
+            sink,
+            codec,
+            block_size: DEFAULT_BLOCK_SIZE,
+            raw: vec![],
+            records: 0,
+            times: None,
+            out: vec![],
+            index: BlockIndex::default(),
+        }
+    }
+
+    /// Sets the uncompressed size at which a block is written, up to
+    /// `MAX_BLOCK_SIZE`.
+    pub fn with_block_size(self, block_size: usize) -> Self {
+        Self { block_size: block_size.min(MAX_BLOCK_SIZE), ..self }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.records == 0 {
+            return Ok(());
+        }
+
+        self.out.clear();
+        self.out.extend_from_slice(&(self.raw.len() as u32).to_le_bytes());
+        self.out.extend_from_slice(&self.records.to_le_bytes());
+        self.out.extend_from_slice(&self.codec.compress(&self.raw)?);
+
+        let offset = self.sink.offset();
+        self.sink.put_record(RECORD_BLOCK, self.codec.id(), &self.out)?;
+        if let Some(offset) = offset {
+            self.index.blocks.push(BlockInfo { offset, records: self.records, times: self.times });
+        }
+        if let Some((_, last)) = self.times {
+            self.sink.entry_time(last);
+        }
+
+        self.raw.clear();
+        self.records = 0;
+        self.times = None;
+        Ok(())
+    }
+
+    pub fn index(&self) -> &BlockIndex {
+        &self.index
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.sink
+    }
+
+    /// Writes the pending block and the index, returning the sink. Records
+    /// not yet written in a block are lost if the writer is dropped instead.
+    pub fn finish(mut self) -> io::Result<S> {
+        self.write_block()?;
+
+        if self.sink.offset().is_some() {
+            let payload = file::footer_record(&self.index)?;
+            self.sink.put_record(RECORD_BLOCK_INDEX, 0, &payload)?;
+        }
+
+        self.sink.flush()?;
+        Ok(self.sink)
+    }
+}
+
+impl<S: RecordSink> RecordSink for BlockWriter<S> {
+    fn put_record(&mut self, kind: u8, type_index: u32, payload: &[u8]) -> io::Result<()> {
+        if is_definition(kind) {
+            if let Some(offset) = self.sink.offset() {
+                self.index.definitions.push(offset);
+            }
+            return self.sink.put_record(kind, type_index, payload);
+        }
+
+        if kind == RECORD_ENTRY {
+            if let Some(time) = peek_time(payload) {
+                self.times = Some(match self.times {
+                    Some((first, last)) => (first.min(time), last.max(time)),
+                    None => (time, time),
+                });
+            }
+        }
+
+        let len = RECORD_HEADER_LEN + payload.len();
+        if len > MAX_BLOCK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "record larger than a block"));
+        }
+        if self.raw.len() + len > MAX_BLOCK_SIZE {
+            self.write_block()?;
+        }
+
+        self.raw.push(kind);
+        self.raw.extend_from_slice(&type_index.to_le_bytes());
+        self.raw.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.raw.extend_from_slice(payload);
+        self.records += 1;
+
+        if self.raw.len() >= self.block_size {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the pending records as a block of their own, so frequent
+    /// flushing makes for smaller blocks.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.sink.flush()
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Reader
+
+/// Returns the records of the blocks read from a source, one at a time.
+#[derive(Default)]
+pub(crate) struct Unpacker {
+    raw: Vec<u8>,
+    pos: usize,
+    /// How many records the block has left, by its header.
+    records: u32,
+}
+
+impl Unpacker {
+    pub(crate) fn unpack(&mut self, codec: u32, block: &[u8]) -> io::Result<()> {
+        if block.len() < BLOCK_HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short block header"));
+        }
+        let mut v = [0u8; 4];
+        v.copy_from_slice(&block[..4]);
+        let raw_len = u32::from_le_bytes(v) as usize;
+        v.copy_from_slice(&block[4..8]);
+        let records = u32::from_le_bytes(v);
+        if raw_len > MAX_FRAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "block longer than MAX_FRAME_LEN"));
+        }
+
+        let raw = decompress(codec, &block[BLOCK_HEADER_LEN..], raw_len)?;
+        if raw.len() != raw_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "block length differs from its header"));
+        }
+        self.raw = raw;
+        self.pos = 0;
+        self.records = records;
+        Ok(())
+    }
+
+    pub(crate) fn next(&mut self, payload: &mut Vec<u8>) -> io::Result<Option<(u8, u32)>> {
+        let rest = &self.raw[self.pos..];
+        if rest.is_empty() {
+            if self.records != 0 {
+                self.records = 0;
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "block with fewer records than its header says"));
+            }
+            return Ok(None);
+        }
+        if self.records == 0 {
+            self.pos = self.raw.len();
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "block with more records than its header says"));
+        }
+        if rest.len() < RECORD_HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short record in block"));
+        }
+
+        let mut v = [0u8; 4];
+        v.copy_from_slice(&rest[1..5]);
+        let type_index = u32::from_le_bytes(v);
+        v.copy_from_slice(&rest[5..9]);
+        let len = u32::from_le_bytes(v) as usize;
+        if rest.len() - RECORD_HEADER_LEN < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "record runs past its block"));
+        }
+
+        payload.clear();
+        payload.extend_from_slice(&rest[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len]);
+        let kind = rest[0];
+        self.pos += RECORD_HEADER_LEN + len;
+        self.records -= 1;
+        Ok(Some((kind, type_index)))
+    }
+}
+
+/// Reads a stream with blocks, returning the records in them in place of
+/// the blocks. `file::FileReader` does so by itself.
+pub struct BlockSource<R: RecordSource> {
+    source: R,
+    unpacker: Unpacker,
+}
+
+impl<R: RecordSource> BlockSource<R> {
+    pub fn new(source: R) -> Self {
+        Self { source, unpacker: Unpacker::default() }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+}
+
+impl<R: RecordSource> RecordSource for BlockSource<R> {
+    fn next_record(&mut self, payload: &mut Vec<u8>) -> io::Result<Option<(u8, u32)>> {
+        loop {
+            if let Some(record) = self.unpacker.next(payload)? {
+                return Ok(Some(record));
+            }
+            match self.source.next_record(payload)? {
+                Some((RECORD_BLOCK, codec)) => self.unpacker.unpack(codec, payload)?,
+                Some((RECORD_BLOCK_INDEX, _)) => {}
+                record => return Ok(record),
+            }
+        }
+    }
+}
+
+/// Reads the block index of a finished log file, or returns `None` for a
+/// file that doesn't have one.
+pub fn read_index<R: Read + Seek>(input: &mut R) -> Result<Option<BlockIndex>, Error> {
+    match file::read_footer_record(input, RECORD_BLOCK_INDEX)? {
+        Some(payload) => Ok(Some(bincode::deserialize(&payload).map_err(Error::Bincode)?)),
+        None => Ok(None),
+    }
+}
+
+/// Reads the entries of a log file in a time range, decompressing only the
+/// blocks whose entries overlap it. The definitions are read first, while
+/// values other than entries are returned only if they share a block with
+/// entries of the range.
+pub struct RangeSource<R: Read + Seek> {
+    input: R,
+    from: u64,
+    to: u64,
+    definitions: VecDeque<u64>,
+    blocks: VecDeque<u64>,
+    unpacker: Unpacker,
+    blocks_read: usize,
+}
+
+impl<R: Read + Seek> RangeSource<R> {
+    /// Times are nanoseconds since the Unix epoch, and `to` is inclusive.
+    pub fn new(input: R, index: &BlockIndex, from: u64, to: u64) -> Self {
+        Self {
+            input,
+            from,
+            to,
+            definitions: index.definitions.iter().cloned().collect(),
+            blocks: index.blocks.iter().filter(|b| b.overlaps(from, to)).map(|b| b.offset).collect(),
+            unpacker: Unpacker::default(),
+            blocks_read: 0,
+        }
+    }
+
+    /// How many blocks were decompressed so far.
+    pub fn blocks_read(&self) -> usize {
+        self.blocks_read
+    }
+}
+
+impl<R: Read + Seek> RecordSource for RangeSource<R> {
+    fn next_record(&mut self, payload: &mut Vec<u8>) -> io::Result<Option<(u8, u32)>> {
+        if let Some(offset) = self.definitions.pop_front() {
+            return match read_frame(&mut self.input, offset)? {
+                Some((kind, type_index, frame)) if is_definition(kind) => {
+                    *payload = frame;
+                    Ok(Some((kind, type_index)))
+                }
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "block index points at no definition")),
+            };
+        }
+
+        loop {
+            while let Some(record) = self.unpacker.next(payload)? {
+                let outside = record.0 == RECORD_ENTRY
+                    && peek_time(payload).is_some_and(|time| time < self.from || time > self.to);
+                if !outside {
+                    return Ok(Some(record));
+                }
+            }
+
+            let offset = match self.blocks.pop_front() {
+                Some(offset) => offset,
+                None => return Ok(None),
+            };
+            match read_frame(&mut self.input, offset)? {
+                Some((RECORD_BLOCK, codec, block)) => self.unpacker.unpack(codec, &block)?,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "block index points at no block")),
+            }
+            self.blocks_read += 1;
+        }
+    }
+}
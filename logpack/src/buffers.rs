@@ -125,7 +125,7 @@ impl<'a> BufDecoder<'a> {
         self.slice.len() - self.position
     }
 
-    pub fn get_slice(&mut self, size: usize) -> Result<&[u8], (usize, usize)>
+    pub fn get_slice(&mut self, size: usize) -> Result<&'a [u8], (usize, usize)>
     {
         let remaining = self.remaining();
         if remaining < size {
@@ -137,7 +137,7 @@ impl<'a> BufDecoder<'a> {
         Ok(value)
     }
 
-    pub fn get_remaining_slice(&mut self) -> Result<&[u8], (usize, usize)>
+    pub fn get_remaining_slice(&mut self) -> Result<&'a [u8], (usize, usize)>
     {
         let remaining = self.remaining();
         self.get_slice(remaining)
@@ -0,0 +1,271 @@
+use super::{Logpack, RefDesc, SeenTypes};
+use super::buffers::BufEncoder;
+use super::encoder::Encoder;
+use super::envelope::{Envelope, Level};
+use super::stream::{RecordSink, StreamWriter};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Log callsites
+//
+// Every `log!` invocation has a static `Callsite` holding its format string
+// and location. On first use it is registered under a process-wide id along
+// with the description of its argument types, so that a log record only
+// needs to carry the id and the encoded arguments. Formatting is left to
+// whoever reads the records back.
+
+pub struct Callsite {
+    pub module: &'static str,
+    pub file: &'static str,
+    pub line: u32,
+    pub format: &'static str,
+    first: OnceLock<&'static CallsiteInfo>,
+}
+
+/// A registered callsite, for one set of argument types.
+pub struct CallsiteInfo {
+    pub id: u32,
+    pub module: &'static str,
+    pub file: &'static str,
+    pub line: u32,
+    pub format: &'static str,
+    /// The `std::any::type_name` of the argument tuple.
+    pub args_key: &'static str,
+    pub describe: fn(&mut SeenTypes) -> RefDesc,
+}
+
+/// Identifies the registration of a callsite for one set of argument types,
+/// by the address of the callsite and `CallsiteInfo::args_key`.
+type InstanceKey = (usize, &'static str);
+
+#[derive(Default)]
+struct Registry {
+    /// Ordered by id.
+    callsites: Vec<&'static CallsiteInfo>,
+    instances: HashMap<InstanceKey, &'static CallsiteInfo>,
+}
+
+static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+
+fn registry() -> MutexGuard<'static, Registry> {
+    lock(REGISTRY.get_or_init(Default::default))
+}
+
+impl Callsite {
+    pub const fn new(module: &'static str, file: &'static str, line: u32, format: &'static str) -> Self {
+        Self { module, file, line, format, first: OnceLock::new() }
+    }
+
+    /// Returns the registration of this callsite for arguments of type `T`,
+    /// registering it if this is the first use.
+    pub fn info<T: Logpack + ?Sized>(&'static self) -> &'static CallsiteInfo {
+        let args_key = std::any::type_name::<T>();
+        let info = *self.first.get_or_init(|| self.register(args_key, T::logpack_describe));
+        if info.args_key == args_key {
+            return info;
+        }
+
+        // A callsite in a generic function is shared by all of its
+        // instantiations, each of which gets an id of its own. They are kept
+        // per thread too, so that logging doesn't take the registry's lock.
+        thread_local! {
+            static INSTANCES: RefCell<HashMap<InstanceKey, &'static CallsiteInfo>> =
+                RefCell::new(HashMap::new());
+        }
+
+        let key = (self as *const Self as usize, args_key);
+        INSTANCES.try_with(|instances| {
+            *instances.borrow_mut().entry(key)
+                .or_insert_with(|| self.register(args_key, T::logpack_describe))
+        }).unwrap_or_else(|_| self.register(args_key, T::logpack_describe))
+    }
+
+    /// Returns the registration of this callsite for `args_key`, adding it
+    /// if there is none yet.
+    fn register(&'static self, args_key: &'static str,
+                describe: fn(&mut SeenTypes) -> RefDesc) -> &'static CallsiteInfo
+    {
+        let mut registry = registry();
+        let Registry { callsites, instances } = &mut *registry;
+        instances.entry((self as *const Self as usize, args_key)).or_insert_with(|| {
+            let info: &'static CallsiteInfo = Box::leak(Box::new(CallsiteInfo {
+                id: callsites.len() as u32 + 1,
+                module: self.module,
+                file: self.file,
+                line: self.line,
+                format: self.format,
+                args_key,
+                describe,
+            }));
+            callsites.push(info);
+            info
+        })
+    }
+}
+
+/// All callsites registered so far, ordered by id.
+pub fn callsites() -> Vec<&'static CallsiteInfo> {
+    registry().callsites.clone()
+}
+
+pub fn lookup(id: u32) -> Option<&'static CallsiteInfo> {
+    registry().callsites.get((id as usize).wrapping_sub(1)).cloned()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Sinks
+
+/// Receives the records of `log!`, with the arguments already encoded.
+pub trait Sink: Send + Sync {
+    fn enabled(&self, _level: Level) -> bool {
+        true
+    }
+
+    fn log(&self, callsite: &'static CallsiteInfo, envelope: &Envelope, args: &[u8]);
+
+    fn flush(&self) {}
+}
+
+static SINK: OnceLock<&'static dyn Sink> = OnceLock::new();
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Trace as u8);
+
+/// Installs the sink of `log!`. This can be done only once; the sink is
+/// returned if one was already installed.
+pub fn set_sink(sink: &'static dyn Sink) -> Result<(), &'static dyn Sink> {
+    SINK.set(sink).map_err(|_| sink)
+}
+
+pub fn set_boxed_sink(sink: Box<dyn Sink>) -> Result<(), &'static dyn Sink> {
+    set_sink(Box::leak(sink))
+}
+
+pub fn sink() -> Option<&'static dyn Sink> {
+    SINK.get().cloned()
+}
+
+/// Records below this level are dropped before their arguments are encoded.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn max_level() -> Level {
+    Level::from_index(MAX_LEVEL.load(Ordering::Relaxed)).unwrap_or(Level::Trace)
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 >= MAX_LEVEL.load(Ordering::Relaxed) && SINK.get().is_some()
+}
+
+pub fn flush() {
+    if let Some(sink) = sink() {
+        sink.flush();
+    }
+}
+
+/// The body of `log!`.
+#[doc(hidden)]
+pub fn dispatch<T>(callsite: &'static Callsite, level: Level, args: &T)
+    where T: Logpack + Encoder + ?Sized
+{
+    let sink = match sink() {
+        Some(sink) if sink.enabled(level) => sink,
+        _ => return,
+    };
+
+    let info = callsite.info::<T>();
+    let envelope = Envelope::now(level, info.id);
+
+    let emit = |buf: &mut Vec<u8>| {
+        buf.clear();
+        buf.resize(args.logpack_sizer(), 0);
+        if args.logpack_encode(&mut BufEncoder::new(buf)).is_ok() {
+            sink.log(info, &envelope, buf);
+        }
+    };
+
+    thread_local! {
+        static BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    BUF.with(|buf| match buf.try_borrow_mut() {
+        Ok(mut buf) => emit(&mut buf),
+        // A sink that logs from within `log`.
+        Err(_) => emit(&mut vec![]),
+    });
+}
+
+/// A sink writing to a stream, defining each callsite in it on first use.
+pub struct StreamSink<W: RecordSink + Send> {
+    writer: Mutex<StreamWriter<W>>,
+    level: Level,
+    errors: AtomicU64,
+}
+
+impl<W: RecordSink + Send> StreamSink<W> {
+    pub fn new(writer: StreamWriter<W>) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+            level: Level::Trace,
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_level(self, level: Level) -> Self {
+        Self { level, ..self }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, StreamWriter<W>> {
+        lock(&self.writer)
+    }
+
+    /// Returns the writer, e.g. to finish the file it writes to.
+    pub fn into_inner(self) -> StreamWriter<W> {
+        self.writer.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes a log entry of a value other than `log!` arguments, e.g. from
+    /// another logging facade.
+    pub fn write_entry<T>(&self, envelope: &Envelope, value: &T)
+        where T: Logpack + Encoder + ?Sized
+    {
+        self.write_with(|writer| writer.write_entry(envelope, value))
+    }
+
+    /// Runs `f` on the writer, counting its failure as a lost record.
+    pub fn write_with<F>(&self, f: F)
+        where F: FnOnce(&mut StreamWriter<W>) -> io::Result<()>
+    {
+        if f(&mut self.lock()).is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// How many records were lost to write errors.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+}
+
+impl<W: RecordSink + Send> Sink for StreamSink<W> {
+    fn enabled(&self, level: Level) -> bool {
+        level >= self.level
+    }
+
+    fn log(&self, callsite: &'static CallsiteInfo, envelope: &Envelope, args: &[u8]) {
+        self.write_with(|writer| writer.write_logged(callsite, envelope, args))
+    }
+
+    fn flush(&self) {
+        let _ = self.lock().flush();
+    }
+}
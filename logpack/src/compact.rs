@@ -0,0 +1,495 @@
+use super::Description;
+use super::Named;
+use super::Struct;
+use super::buffers::{BufEncoder, BufDecoder};
+use super::decoder::ResolvedDesc;
+use super::fingerprint::TypeRef;
+
+use std::collections::HashMap;
+use std::str::{self, Utf8Error};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Compact binary encoding of descriptions
+//
+// A schema block encodes a list of descriptions, with every type, field and
+// variant name stored once in a string table up front:
+//
+//     block   := varint(#strings) string* varint(#descs) desc*
+//     string  := varint(len) utf8-bytes
+//     desc    := tag [payload]
+//     named   := 0 varint(#variants) (varint(name) struct)*    -- enum
+//              | 1 struct                                       -- struct
+//     struct  := 0 | 1 varint(#fields) desc* | 2 varint(#fields) (varint(name) desc)*
+//
+// Description tags are listed below. `ByName` references carry the index of
+// the type name in the string table and the id, and are followed by the
+// definition when it is inline. Varints are unsigned LEB128.
+
+const TAG_U64: u8 = 0;
+const TAG_U32: u8 = 1;
+const TAG_U16: u8 = 2;
+const TAG_U8: u8 = 3;
+const TAG_I64: u8 = 4;
+const TAG_I32: u8 = 5;
+const TAG_I16: u8 = 6;
+const TAG_I8: u8 = 7;
+const TAG_UNIT: u8 = 8;
+const TAG_PHANTOM: u8 = 9;
+const TAG_BOOL: u8 = 10;
+const TAG_STRING: u8 = 11;
+const TAG_RAW_PTR: u8 = 12;
+const TAG_OPTION: u8 = 13;
+const TAG_RESULT: u8 = 14;
+const TAG_ARRAY: u8 = 15;
+const TAG_SLICE: u8 = 16;
+const TAG_TUPLE: u8 = 17;
+const TAG_BY_NAME: u8 = 18;
+const TAG_BY_NAME_DEF: u8 = 19;
+
+const NAMED_ENUM: u8 = 0;
+const NAMED_STRUCT: u8 = 1;
+
+const STRUCT_UNIT: u8 = 0;
+const STRUCT_TUPLE: u8 = 1;
+const STRUCT_NAMED: u8 = 2;
+
+/// Deeper nesting than this in a block is rejected as corrupt, rather than
+/// risking the decoder's stack.
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug)]
+pub enum Error {
+    GetError((usize, usize)),
+    UTF8Error(Utf8Error),
+    InvalidTag(u8),
+    InvalidString(u64),
+    InvalidVarint,
+    TooDeep,
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Encoding
+
+/// Where encoded bytes go: a buffer, or just a byte count for sizing.
+trait Output {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), (usize, usize)>;
+}
+
+impl<'a> Output for BufEncoder<'a> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), (usize, usize)> {
+        self.append_bytes(bytes)
+    }
+}
+
+struct Counter(usize);
+
+impl Output for Counter {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), (usize, usize)> {
+        self.0 += bytes.len();
+        Ok(())
+    }
+}
+
+fn put_varint<O: Output>(out: &mut O, mut value: u64) -> Result<(), (usize, usize)> {
+    let mut bytes = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes[len] = byte;
+            len += 1;
+            break;
+        }
+        bytes[len] = byte | 0x80;
+        len += 1;
+    }
+    out.bytes(&bytes[..len])
+}
+
+struct Strings<'s> {
+    table: Vec<&'s str>,
+    index: HashMap<&'s str, u64>,
+}
+
+impl<'s> Strings<'s> {
+    fn add(&mut self, s: &'s str) {
+        if !self.index.contains_key(s) {
+            self.index.insert(s, self.table.len() as u64);
+            self.table.push(s);
+        }
+    }
+
+    fn desc<T: TypeRef, S: AsRef<str>>(&mut self, desc: &'s Description<T, S>) {
+        use Description::*;
+
+        match desc {
+            Option(sub) | Slice(sub) | Array(_, sub) => self.desc(sub),
+            Result(ok, err) => {
+                self.desc(ok);
+                self.desc(err);
+            }
+            Tuple(subs) => {
+                for sub in subs {
+                    self.desc(sub);
+                }
+            }
+            ByName(name, named) => {
+                self.add(name.type_name());
+                match named {
+                    Some(Named::Enum(variants)) => {
+                        for (name, s) in variants {
+                            self.add(name.as_ref());
+                            self.struct_(s);
+                        }
+                    }
+                    Some(Named::Struct(s)) => self.struct_(s),
+                    None => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn struct_<T: TypeRef, S: AsRef<str>>(&mut self, s: &'s Struct<T, S>) {
+        match s {
+            Struct::Unit => {}
+            Struct::Tuple(fields) => {
+                for field in fields {
+                    self.desc(field);
+                }
+            }
+            Struct::Named(fields) => {
+                for (name, field) in fields {
+                    self.add(name.as_ref());
+                    self.desc(field);
+                }
+            }
+        }
+    }
+}
+
+struct Writer<'s, 'o, O: Output> {
+    strings: &'o Strings<'s>,
+    out: &'o mut O,
+}
+
+impl<'s, 'o, O: Output> Writer<'s, 'o, O> {
+    fn name(&mut self, s: &str) -> Result<(), (usize, usize)> {
+        put_varint(self.out, self.strings.index[s])
+    }
+
+    fn desc<T: TypeRef, S: AsRef<str>>(&mut self, desc: &Description<T, S>) -> Result<(), (usize, usize)> {
+        use Description::*;
+
+        let tag = match desc {
+            U64 => TAG_U64,
+            U32 => TAG_U32,
+            U16 => TAG_U16,
+            U8 => TAG_U8,
+            I64 => TAG_I64,
+            I32 => TAG_I32,
+            I16 => TAG_I16,
+            I8 => TAG_I8,
+            Unit => TAG_UNIT,
+            PhantomData => TAG_PHANTOM,
+            Bool => TAG_BOOL,
+            String => TAG_STRING,
+            RawPtr => TAG_RAW_PTR,
+            Option(_) => TAG_OPTION,
+            Result(_, _) => TAG_RESULT,
+            Array(_, _) => TAG_ARRAY,
+            Slice(_) => TAG_SLICE,
+            Tuple(_) => TAG_TUPLE,
+            ByName(_, None) => TAG_BY_NAME,
+            ByName(_, Some(_)) => TAG_BY_NAME_DEF,
+        };
+        self.out.bytes(&[tag])?;
+
+        match desc {
+            Option(sub) | Slice(sub) => self.desc(sub),
+            Result(ok, err) => {
+                self.desc(ok)?;
+                self.desc(err)
+            }
+            Array(size, sub) => {
+                put_varint(self.out, *size as u64)?;
+                self.desc(sub)
+            }
+            Tuple(subs) => {
+                put_varint(self.out, subs.len() as u64)?;
+                for sub in subs {
+                    self.desc(sub)?;
+                }
+                Ok(())
+            }
+            ByName(name, named) => {
+                self.name(name.type_name())?;
+                put_varint(self.out, name.type_id())?;
+                match named {
+                    Some(named) => self.named(named),
+                    None => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn named<T: TypeRef, S: AsRef<str>>(&mut self, named: &Named<T, S>) -> Result<(), (usize, usize)> {
+        match named {
+            Named::Enum(variants) => {
+                self.out.bytes(&[NAMED_ENUM])?;
+                put_varint(self.out, variants.len() as u64)?;
+                for (name, s) in variants {
+                    self.name(name.as_ref())?;
+                    self.struct_(s)?;
+                }
+                Ok(())
+            }
+            Named::Struct(s) => {
+                self.out.bytes(&[NAMED_STRUCT])?;
+                self.struct_(s)
+            }
+        }
+    }
+
+    fn struct_<T: TypeRef, S: AsRef<str>>(&mut self, s: &Struct<T, S>) -> Result<(), (usize, usize)> {
+        match s {
+            Struct::Unit => self.out.bytes(&[STRUCT_UNIT]),
+            Struct::Tuple(fields) => {
+                self.out.bytes(&[STRUCT_TUPLE])?;
+                put_varint(self.out, fields.len() as u64)?;
+                for field in fields {
+                    self.desc(field)?;
+                }
+                Ok(())
+            }
+            Struct::Named(fields) => {
+                self.out.bytes(&[STRUCT_NAMED])?;
+                put_varint(self.out, fields.len() as u64)?;
+                for (name, field) in fields {
+                    self.name(name.as_ref())?;
+                    self.desc(field)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn write_block<T, S, O>(descs: &[Description<T, S>], out: &mut O) -> Result<(), (usize, usize)>
+    where T: TypeRef, S: AsRef<str>, O: Output
+{
+    let mut strings = Strings { table: vec![], index: HashMap::new() };
+    for desc in descs {
+        strings.desc(desc);
+    }
+
+    put_varint(out, strings.table.len() as u64)?;
+    for s in strings.table.iter() {
+        put_varint(out, s.len() as u64)?;
+        out.bytes(s.as_bytes())?;
+    }
+
+    put_varint(out, descs.len() as u64)?;
+    let mut writer = Writer { strings: &strings, out };
+    for desc in descs {
+        writer.desc(desc)?;
+    }
+    Ok(())
+}
+
+/// Encodes a schema block. Works for both the `RefDesc`s returned by
+/// `logpack_describe` and `ResolvedDesc`s.
+pub fn encode<T, S>(descs: &[Description<T, S>], buf: &mut BufEncoder) -> Result<(), (usize, usize)>
+    where T: TypeRef, S: AsRef<str>
+{
+    write_block(descs, buf)
+}
+
+/// The exact number of bytes `encode` writes for the same block.
+pub fn encoded_size<T, S>(descs: &[Description<T, S>]) -> usize
+    where T: TypeRef, S: AsRef<str>
+{
+    let mut counter = Counter(0);
+    let _ = write_block(descs, &mut counter);
+    counter.0
+}
+
+pub fn to_vec<T, S>(descs: &[Description<T, S>]) -> Vec<u8>
+    where T: TypeRef, S: AsRef<str>
+{
+    let mut bytes = vec![0u8; encoded_size(descs)];
+    encode(descs, &mut BufEncoder::new(&mut bytes)).unwrap();
+    bytes
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Decoding
+
+fn get_varint(buf: &mut BufDecoder) -> Result<u64, Error> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = buf.get::<u8>().map_err(Error::GetError)?;
+        if shift == 63 && byte > 1 {
+            return Err(Error::InvalidVarint);
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift > 63 {
+            return Err(Error::InvalidVarint);
+        }
+    }
+}
+
+struct Reader<'a, 'b> {
+    strings: Vec<String>,
+    buf: &'a mut BufDecoder<'b>,
+    depth: usize,
+}
+
+impl<'a, 'b> Reader<'a, 'b> {
+    fn name(&mut self) -> Result<String, Error> {
+        let idx = get_varint(self.buf)?;
+        match self.strings.get(idx as usize) {
+            Some(s) => Ok(s.clone()),
+            None => Err(Error::InvalidString(idx)),
+        }
+    }
+
+    fn count(&mut self) -> Result<usize, Error> {
+        let count = get_varint(self.buf)? as usize;
+        // Every item takes at least a byte, which bounds what a corrupt
+        // count can make us allocate.
+        if count > self.buf.remaining() {
+            return Err(Error::GetError((self.buf.remaining(), count)));
+        }
+        Ok(count)
+    }
+
+    fn desc(&mut self) -> Result<ResolvedDesc, Error> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        let desc = self.desc_inner();
+        self.depth -= 1;
+        desc
+    }
+
+    fn desc_inner(&mut self) -> Result<ResolvedDesc, Error> {
+        use Description::*;
+
+        let tag = self.buf.get::<u8>().map_err(Error::GetError)?;
+        Ok(match tag {
+            TAG_U64 => U64,
+            TAG_U32 => U32,
+            TAG_U16 => U16,
+            TAG_U8 => U8,
+            TAG_I64 => I64,
+            TAG_I32 => I32,
+            TAG_I16 => I16,
+            TAG_I8 => I8,
+            TAG_UNIT => Unit,
+            TAG_PHANTOM => PhantomData,
+            TAG_BOOL => Bool,
+            TAG_STRING => String,
+            TAG_RAW_PTR => RawPtr,
+            TAG_OPTION => Option(Box::new(self.desc()?)),
+            TAG_RESULT => {
+                let ok = self.desc()?;
+                let err = self.desc()?;
+                Result(Box::new(ok), Box::new(err))
+            }
+            TAG_ARRAY => {
+                let size = get_varint(self.buf)? as usize;
+                Array(size, Box::new(self.desc()?))
+            }
+            TAG_SLICE => Slice(Box::new(self.desc()?)),
+            TAG_TUPLE => {
+                let count = self.count()?;
+                let mut subs = vec![];
+                for _ in 0..count {
+                    subs.push(self.desc()?);
+                }
+                Tuple(subs)
+            }
+            TAG_BY_NAME | TAG_BY_NAME_DEF => {
+                let name = self.name()?;
+                let id = get_varint(self.buf)?;
+                let named = if tag == TAG_BY_NAME_DEF {
+                    Some(self.named()?)
+                } else {
+                    None
+                };
+                ByName((name, id), named)
+            }
+            tag => return Err(Error::InvalidTag(tag)),
+        })
+    }
+
+    fn named(&mut self) -> Result<Named<(String, u64)>, Error> {
+        match self.buf.get::<u8>().map_err(Error::GetError)? {
+            NAMED_ENUM => {
+                let count = self.count()?;
+                let mut variants = vec![];
+                for _ in 0..count {
+                    let name = self.name()?;
+                    variants.push((name, self.struct_()?));
+                }
+                Ok(Named::Enum(variants))
+            }
+            NAMED_STRUCT => Ok(Named::Struct(self.struct_()?)),
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+
+    fn struct_(&mut self) -> Result<Struct<(String, u64)>, Error> {
+        match self.buf.get::<u8>().map_err(Error::GetError)? {
+            STRUCT_UNIT => Ok(Struct::Unit),
+            STRUCT_TUPLE => {
+                let count = self.count()?;
+                let mut fields = vec![];
+                for _ in 0..count {
+                    fields.push(self.desc()?);
+                }
+                Ok(Struct::Tuple(fields))
+            }
+            STRUCT_NAMED => {
+                let count = self.count()?;
+                let mut fields = vec![];
+                for _ in 0..count {
+                    let name = self.name()?;
+                    fields.push((name, self.desc()?));
+                }
+                Ok(Struct::Named(fields))
+            }
+            tag => Err(Error::InvalidTag(tag)),
+        }
+    }
+}
+
+/// Decodes a schema block written by `encode`, leaving `buf` right after it.
+pub fn decode(buf: &mut BufDecoder) -> Result<Vec<ResolvedDesc>, Error> {
+    let mut reader = Reader { strings: vec![], buf, depth: 0 };
+
+    let count = reader.count()?;
+    for _ in 0..count {
+        let len = get_varint(reader.buf)? as usize;
+        let bytes = reader.buf.get_slice(len).map_err(Error::GetError)?;
+        let s = str::from_utf8(bytes).map_err(Error::UTF8Error)?;
+        reader.strings.push(s.to_owned());
+    }
+
+    let count = reader.count()?;
+    let mut descs = vec![];
+    for _ in 0..count {
+        descs.push(reader.desc()?);
+    }
+    Ok(descs)
+}
@@ -0,0 +1,293 @@
+use super::Description;
+use super::Named;
+use super::Struct;
+use super::decoder::{NameMap, TypeNameId, ResolvedDesc};
+use super::diff::{summary, struct_summary};
+use super::resolve::widens;
+
+use serde_derive::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Schema compatibility
+//
+// Compares the types of two builds and classifies each difference. A change
+// is compatible when records written by the old build can still be read with
+// the new build's schema through `resolve::Resolver`.
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChangeKind {
+    TypeAdded,
+    TypeRemoved,
+    FieldAdded,
+    FieldRemoved,
+    FieldsReordered,
+    VariantAdded,
+    VariantsReordered,
+    PrimitiveWidened,
+    MadeOptional,
+    Incompatible,
+}
+
+impl ChangeKind {
+    pub fn is_compatible(self) -> bool {
+        self != ChangeKind::Incompatible
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Change {
+    pub type_name: TypeNameId,
+    /// Path inside the type, e.g. `.req.status` or `::Failed.0`.
+    pub path: String,
+    pub kind: ChangeKind,
+    pub detail: String,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verdict = if self.kind.is_compatible() { "ok" } else { "INCOMPATIBLE" };
+        write!(f, "[{}] {}#{}{}: {:?}", verdict, self.type_name.0, self.type_name.1,
+               self.path, self.kind)?;
+        if !self.detail.is_empty() {
+            write!(f, " ({})", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Report {
+    pub changes: Vec<Change>,
+}
+
+impl Report {
+    pub fn is_compatible(&self) -> bool {
+        self.changes.iter().all(|c| c.kind.is_compatible())
+    }
+
+    pub fn incompatible(&self) -> impl Iterator<Item = &Change> {
+        self.changes.iter().filter(|c| !c.kind.is_compatible())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for change in self.changes.iter() {
+            writeln!(f, "{}", change)?;
+        }
+        let incompatible = self.incompatible().count();
+        if incompatible == 0 {
+            writeln!(f, "compatible: {} changes", self.changes.len())
+        } else {
+            writeln!(f, "incompatible: {} of {} changes", incompatible, self.changes.len())
+        }
+    }
+}
+
+/// Pairs types of the two maps. Ids are matched exactly first; the remaining
+/// types are matched by name when the name is unique on both sides, since
+/// counter-based ids may differ between builds.
+fn match_types<'a>(old: &'a NameMap, new: &'a NameMap) -> BTreeMap<&'a TypeNameId, &'a TypeNameId> {
+    let mut pairs = BTreeMap::new();
+    for id in old.get_map().keys() {
+        if let Some((new_id, _)) = new.get_map().get_key_value(id) {
+            pairs.insert(id, new_id);
+        }
+    }
+
+    let unmatched = |map: &'a NameMap, matched: &dyn Fn(&TypeNameId) -> bool| {
+        let mut by_name: HashMap<&'a str, Vec<&'a TypeNameId>> = HashMap::new();
+        for id in map.get_map().keys().filter(|id| !matched(id)) {
+            by_name.entry(id.0.as_str()).or_default().push(id);
+        }
+        by_name
+    };
+
+    let old_left = unmatched(old, &|id| pairs.contains_key(id));
+    let new_left = unmatched(new, &|id| pairs.values().any(|x| *x == id));
+
+    for (name, old_ids) in old_left.iter() {
+        if let Some(new_ids) = new_left.get(name) {
+            if old_ids.len() == 1 && new_ids.len() == 1 {
+                pairs.insert(old_ids[0], new_ids[0]);
+            }
+        }
+    }
+
+    pairs
+}
+
+struct Comparer<'a> {
+    pairs: BTreeMap<&'a TypeNameId, &'a TypeNameId>,
+    changes: Vec<Change>,
+    current: Option<TypeNameId>,
+}
+
+pub fn compare(old: &NameMap, new: &NameMap) -> Report {
+    let mut comparer = Comparer {
+        pairs: match_types(old, new),
+        changes: vec![],
+        current: None,
+    };
+
+    let mut old_ids: Vec<_> = old.get_map().keys().collect();
+    old_ids.sort();
+    for old_id in old_ids {
+        comparer.current = Some(old_id.clone());
+        match comparer.pairs.get(old_id) {
+            None => comparer.push("", ChangeKind::TypeRemoved, String::new()),
+            Some(new_id) => {
+                let old_named = &old.get_map()[old_id];
+                let new_named = &new.get_map()[*new_id];
+                comparer.named(old_named, new_named);
+            }
+        }
+    }
+
+    let mut new_ids: Vec<_> = new.get_map().keys().collect();
+    new_ids.sort();
+    for new_id in new_ids {
+        if !comparer.pairs.values().any(|x| *x == new_id) {
+            comparer.current = Some(new_id.clone());
+            comparer.push("", ChangeKind::TypeAdded, String::new());
+        }
+    }
+
+    Report { changes: comparer.changes }
+}
+
+impl<'a> Comparer<'a> {
+    fn push(&mut self, path: &str, kind: ChangeKind, detail: String) {
+        self.changes.push(Change {
+            type_name: self.current.clone().unwrap(),
+            path: path.to_owned(),
+            kind,
+            detail,
+        });
+    }
+
+    fn named(&mut self, old: &Named<TypeNameId>, new: &Named<TypeNameId>) {
+        match (old, new) {
+            (Named::Struct(old), Named::Struct(new)) => self.struct_("", old, new),
+            (Named::Enum(old), Named::Enum(new)) => {
+                for (name, old_struct) in old.iter() {
+                    let path = format!("::{}", name);
+                    match new.iter().find(|(n, _)| n == name) {
+                        Some((_, new_struct)) => self.struct_(&path, old_struct, new_struct),
+                        None => self.push(&path, ChangeKind::Incompatible,
+                                          "variant removed".to_owned()),
+                    }
+                }
+                for (name, _) in new.iter() {
+                    if !old.iter().any(|(o, _)| o == name) {
+                        self.push(&format!("::{}", name), ChangeKind::VariantAdded, String::new());
+                    }
+                }
+                if !same_order(old, new) {
+                    self.push("", ChangeKind::VariantsReordered, String::new());
+                }
+            }
+            (Named::Struct(_), Named::Enum(_)) => {
+                self.push("", ChangeKind::Incompatible, "struct became an enum".to_owned())
+            }
+            (Named::Enum(_), Named::Struct(_)) => {
+                self.push("", ChangeKind::Incompatible, "enum became a struct".to_owned())
+            }
+        }
+    }
+
+    fn struct_(&mut self, path: &str, old: &Struct<TypeNameId>, new: &Struct<TypeNameId>) {
+        match (old, new) {
+            (Struct::Unit, Struct::Unit) => {}
+            (Struct::Tuple(old), Struct::Tuple(new)) => self.tuple(path, old, new),
+            (Struct::Named(old), Struct::Named(new)) => {
+                for (name, old_field) in old.iter() {
+                    let field_path = format!("{}.{}", path, name);
+                    match new.iter().find(|(n, _)| n == name) {
+                        Some((_, new_field)) => self.desc(&field_path, old_field, new_field),
+                        None => self.push(&field_path, ChangeKind::FieldRemoved, summary(old_field)),
+                    }
+                }
+                for (name, new_field) in new.iter() {
+                    if old.iter().any(|(o, _)| o == name) {
+                        continue;
+                    }
+                    let field_path = format!("{}.{}", path, name);
+                    let kind = match new_field {
+                        Description::Option(_) => ChangeKind::FieldAdded,
+                        _ => ChangeKind::Incompatible,
+                    };
+                    self.push(&field_path, kind, format!("added as {}", summary(new_field)));
+                }
+                if !same_order(old, new) {
+                    self.push(path, ChangeKind::FieldsReordered, String::new());
+                }
+            }
+            (old, new) => {
+                let detail = format!("{} -> {}", struct_summary(old), struct_summary(new));
+                self.push(path, ChangeKind::Incompatible, detail)
+            }
+        }
+    }
+
+    fn tuple(&mut self, path: &str, old: &[ResolvedDesc], new: &[ResolvedDesc]) {
+        if old.len() != new.len() {
+            let detail = format!("{} items -> {} items", old.len(), new.len());
+            self.push(path, ChangeKind::Incompatible, detail);
+            return;
+        }
+
+        for (idx, (old, new)) in old.iter().zip(new.iter()).enumerate() {
+            self.desc(&format!("{}.{}", path, idx), old, new);
+        }
+    }
+
+    fn desc(&mut self, path: &str, old: &ResolvedDesc, new: &ResolvedDesc) {
+        use Description::*;
+
+        if old != new && widens(old, new) {
+            let detail = format!("{} -> {}", summary(old), summary(new));
+            self.push(path, ChangeKind::PrimitiveWidened, detail);
+            return;
+        }
+
+        match (old, new) {
+            (Option(old), Option(new)) => self.desc(&format!("{}?", path), old, new),
+            (Slice(old), Slice(new)) => self.desc(&format!("{}[]", path), old, new),
+            (Array(old_size, old), Array(new_size, new)) if old_size == new_size => {
+                self.desc(&format!("{}[]", path), old, new)
+            }
+            (Result(old_ok, old_err), Result(new_ok, new_err)) => {
+                self.desc(&format!("{}::Ok", path), old_ok, new_ok);
+                self.desc(&format!("{}::Err", path), old_err, new_err);
+            }
+            (Tuple(old), Tuple(new)) => self.tuple(path, old, new),
+            (ByName(old_id, _), ByName(new_id, _)) => {
+                if self.pairs.get(old_id) != Some(&new_id) {
+                    let detail = format!("{} -> {}", summary(old), summary(new));
+                    self.push(path, ChangeKind::Incompatible, detail);
+                }
+            }
+            (old, Option(inner)) if !matches!(old, Option(_)) => {
+                self.push(path, ChangeKind::MadeOptional, summary(old));
+                self.desc(path, old, inner);
+            }
+            (old, new) => {
+                if old != new {
+                    let detail = format!("{} -> {}", summary(old), summary(new));
+                    self.push(path, ChangeKind::Incompatible, detail);
+                }
+            }
+        }
+    }
+}
+
+/// Whether the items present on both sides appear in the same relative order.
+fn same_order<V>(old: &[(String, V)], new: &[(String, V)]) -> bool {
+    let common_old = old.iter().filter(|(n, _)| new.iter().any(|(m, _)| m == n)).map(|(n, _)| n);
+    let common_new = new.iter().filter(|(n, _)| old.iter().any(|(m, _)| m == n)).map(|(n, _)| n);
+    common_old.eq(common_new)
+}
@@ -2,21 +2,24 @@ use super::Description;
 use super::Named;
 use super::Struct;
 
+use serde_derive::{Serialize, Deserialize};
 use std::collections::{HashMap};
 use super::buffers::BufDecoder;
+use super::diff::{self, Difference};
 
 pub type TypeName = String;
-pub type TypeNameId = (TypeName, u16);
+pub type TypeNameId = (TypeName, u64);
 pub type ResolvedDesc = Description<TypeNameId>;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct NameMap {
     map: HashMap<TypeNameId, Named<TypeNameId>>
 }
 
 #[derive(Debug)]
 pub enum FeedError {
-    Dups,
+    /// A type was already fed with a different definition.
+    Conflict(TypeNameId, Vec<Difference>),
 }
 
 pub type FeedResult<T> = Result<T, FeedError>;
@@ -32,17 +35,81 @@ impl NameMap {
         &self.map
     }
 
+    /// Adds a single definition whose references were already fed. Returns
+    /// whether the type is new; identical redefinitions are accepted.
+    pub fn insert_named(&mut self, name: TypeNameId, named: Named<TypeNameId>) -> FeedResult<bool>
+    {
+        match self.map.get(&name) {
+            Some(existing) if *existing != named => {
+                let diffs = diff::diff_named(existing, &named);
+                Err(FeedError::Conflict(name, diffs))
+            }
+            Some(_) => Ok(false),
+            None => {
+                self.map.insert(name, named);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Adds all definitions of `other`, failing on the first conflicting one.
+    /// On a conflict, nothing is added.
+    pub fn merge(&mut self, other: &NameMap) -> FeedResult<()>
+    {
+        let mut names: Vec<_> = other.map.keys().collect();
+        names.sort();
+        for name in names.iter() {
+            match self.map.get(*name) {
+                Some(existing) if *existing != other.map[*name] => {
+                    let diffs = diff::diff_named(existing, &other.map[*name]);
+                    return Err(FeedError::Conflict((*name).clone(), diffs));
+                }
+                _ => {}
+            }
+        }
+        for name in names {
+            self.map.entry(name.clone()).or_insert_with(|| other.map[name].clone());
+        }
+        Ok(())
+    }
+
+    /// Feeds into an empty map, and merges it only if that succeeds, so that
+    /// a conflict leaves this map unchanged.
+    fn atomically<T, F>(&mut self, f: F) -> FeedResult<T>
+        where F: FnOnce(&mut NameMap) -> FeedResult<T>
+    {
+        let mut scratch = NameMap::new();
+        let result = f(&mut scratch)?;
+        self.merge(&scratch)?;
+        Ok(result)
+    }
+
     pub fn feed(&mut self, description: ResolvedDesc) -> FeedResult<ResolvedDesc>
+    {
+        self.atomically(|map| map.add_desc(description))
+    }
+
+    pub fn feed_named(&mut self, named: Named<TypeNameId>) -> FeedResult<Named<TypeNameId>>
+    {
+        self.atomically(|map| map.add_named(named))
+    }
+
+    pub fn feed_struct(&mut self, struct_: Struct<TypeNameId>) -> FeedResult<Struct<TypeNameId>>
+    {
+        self.atomically(|map| map.add_struct(struct_))
+    }
+
+    fn add_desc(&mut self, description: ResolvedDesc) -> FeedResult<ResolvedDesc>
     {
         use Description::*;
 
         Ok(match description {
-            Option(o) => Option(Box::new(self.feed(*o)?)),
-            Slice(o) => Slice(Box::new(self.feed(*o)?)),
-            Array(size, o) => Array(size, Box::new(self.feed(*o)?)),
-            Result(t, f) => Result(Box::new(self.feed(*t)?), Box::new(self.feed(*f)?)),
+            Option(o) => Option(Box::new(self.add_desc(*o)?)),
+            Slice(o) => Slice(Box::new(self.add_desc(*o)?)),
+            Array(size, o) => Array(size, Box::new(self.add_desc(*o)?)),
+            Result(t, f) => Result(Box::new(self.add_desc(*t)?), Box::new(self.add_desc(*f)?)),
             Tuple(vec) => Tuple({
-                let items: ::std::result::Result<Vec<_>, _> = vec.into_iter().map(|x| self.feed(x)).collect();
+                let items: ::std::result::Result<Vec<_>, _> = vec.into_iter().map(|x| self.add_desc(x)).collect();
                 items?
             }),
 
@@ -62,28 +129,28 @@ impl NameMap {
 
             q@ByName(_, None) => q,
             ByName(name, Some(named)) => {
-                let v = self.feed_named(named)?;
-                self.map.insert(name.clone(), v);
+                let v = self.add_named(named)?;
+                self.insert_named(name.clone(), v)?;
                 ByName(name, None)
             },
         })
     }
 
-    pub fn feed_named(&mut self, named: Named<TypeNameId>) -> FeedResult<Named<TypeNameId>>
+    fn add_named(&mut self, named: Named<TypeNameId>) -> FeedResult<Named<TypeNameId>>
     {
         use Named::*;
 
         Ok(match named {
             Enum(vec) => Enum({
                 let items: ::std::result::Result<Vec<_>, _> =
-                    vec.into_iter().map(|(name, x)| Ok((name, self.feed_struct(x)?))).collect();
+                    vec.into_iter().map(|(name, x)| Ok((name, self.add_struct(x)?))).collect();
                 items?
             }),
-            Struct(struct_) => Struct(self.feed_struct(struct_)?),
+            Struct(struct_) => Struct(self.add_struct(struct_)?),
         })
     }
 
-    pub fn feed_struct(&mut self, struct_: Struct<TypeNameId>) -> FeedResult<Struct<TypeNameId>>
+    fn add_struct(&mut self, struct_: Struct<TypeNameId>) -> FeedResult<Struct<TypeNameId>>
     {
         use Struct::*;
 
@@ -91,12 +158,12 @@ impl NameMap {
             Unit => Unit,
             Tuple(vec) => Tuple({
                 let items: ::std::result::Result<Vec<_>, _> =
-                    vec.into_iter().map(|x|self.feed(x)).collect();
+                    vec.into_iter().map(|x|self.add_desc(x)).collect();
                 items?
             }),
             Named(vec) => Named({
                 let items: ::std::result::Result<Vec<_>, _> =
-                   vec.into_iter().map(|(name, x)| Ok((name, self.feed(x)?))).collect();
+                   vec.into_iter().map(|(name, x)| Ok((name, self.add_desc(x)?))).collect();
                 items?
             }),
         })
@@ -167,6 +234,65 @@ pub trait Callbacks {
     fn end_slice(&mut self);
 }
 
+/// Callbacks that discard everything, used for skipping over values.
+pub struct Ignore;
+
+impl Callbacks for Ignore {
+    type SubType = Ignore;
+
+    fn handle_u8(&mut self, _: u8) {}
+    fn handle_u16(&mut self, _: u16) {}
+    fn handle_u32(&mut self, _: u32) {}
+    fn handle_u64(&mut self, _: u64) {}
+    fn handle_i8(&mut self, _: i32) {}
+    fn handle_i16(&mut self, _: i32) {}
+    fn handle_i32(&mut self, _: i32) {}
+    fn handle_i64(&mut self, _: i64) {}
+    fn handle_bool(&mut self, _: bool) {}
+    fn handle_string(&mut self, _: &str) {}
+    fn handle_unit(&mut self) {}
+    fn handle_phantom(&mut self) {}
+    fn handle_raw_ptr(&mut self, _: u64) {}
+
+    fn begin_enum(&mut self, _: &TypeNameId, _: &String) -> &mut Self { self }
+    fn end_enum(&mut self, _: &TypeNameId) {}
+
+    fn option_none(&mut self) {}
+    fn option_some(&mut self) -> &mut Self { self }
+    fn option_end(&mut self) {}
+
+    fn result_ok(&mut self) -> &mut Self { self }
+    fn result_err(&mut self) -> &mut Self { self }
+    fn result_end(&mut self) {}
+
+    fn struct_unit(&mut self, _: Option<&TypeNameId>) {}
+
+    fn begin_struct_named(&mut self, _: Option<&TypeNameId>) -> &mut Self { self }
+    fn begin_named_field(&mut self, _: u16, _: &String) -> &mut Self { self }
+    fn end_named_field(&mut self) {}
+    fn end_struct_named(&mut self) {}
+
+    fn begin_struct_tuple(&mut self, _: Option<&TypeNameId>) -> &mut Self { self }
+    fn begin_tuple_field(&mut self, _: u16) -> &mut Self { self }
+    fn end_tuple_field(&mut self) {}
+    fn end_struct_tuple(&mut self) {}
+
+    fn begin_tuple(&mut self, _: usize) -> &mut Self { self }
+    fn begin_tuple_item(&mut self, _: u16) {}
+    fn end_tuple_item(&mut self) {}
+    fn end_tuple(&mut self) {}
+
+    fn begin_array(&mut self, _: usize) -> &mut Self { self }
+    fn begin_array_item(&mut self, _: u16) {}
+    fn end_array_item(&mut self) {}
+    fn end_array(&mut self) {}
+
+    fn begin_slice(&mut self, _: usize) -> &mut Self { self }
+    fn begin_slice_item(&mut self, _: u16) {}
+    fn end_slice_item(&mut self) {}
+    fn end_slice(&mut self) {}
+}
+
 use std::str::{Utf8Error, self};
 
 #[derive(Debug)]
@@ -209,8 +335,16 @@ impl<'a, 'b> Decoder<'a, 'b> {
             &U16 => simple!(self, callbacks, handle_u16),
             &U32 => simple!(self, callbacks, handle_u32),
             &U64 => simple!(self, callbacks, handle_u64),
-            &I8 => simple!(self, callbacks, handle_i8),
-            &I16 => simple!(self, callbacks, handle_i16),
+            &I8 => {
+                let val = self.buffer.get::<i8>().map_err(Error::GetError)?;
+                callbacks.handle_i8(val as i32);
+                Ok(())
+            }
+            &I16 => {
+                let val = self.buffer.get::<i16>().map_err(Error::GetError)?;
+                callbacks.handle_i16(val as i32);
+                Ok(())
+            }
             &I32 => simple!(self, callbacks, handle_i32),
             &I64 => simple!(self, callbacks, handle_i64),
             &Bool => simple!(self, callbacks, handle_bool),
@@ -232,6 +366,11 @@ impl<'a, 'b> Decoder<'a, 'b> {
         }
     }
 
+    /// Moves past a value of the given type without reporting it.
+    pub fn skip(&mut self, desc: &ResolvedDesc) -> Result<(), Error> {
+        self.decode(desc, &mut Ignore)
+    }
+
     pub fn decode_by_name<C>(&mut self, typename_id: &TypeNameId, callbacks: &mut C) -> Result<(), Error>
         where C: Callbacks
     {
@@ -332,32 +471,7 @@ impl<'a, 'b> Decoder<'a, 'b> {
     fn decode_string<C>(&mut self, callbacks: &mut C) -> Result<(), Error>
         where C: Callbacks
     {
-        let f0 = self.buffer.get::<u8>().map_err(Error::GetError)?;
-
-        let extra_header = f0 & 0x3;
-        let len = match extra_header {
-            0 => (f0 >> 2) as u64,
-            1 => {
-                let f1 = self.buffer.get::<u8>().map_err(Error::GetError)?;
-                ((f1 as u64) << 6) | ((f0 >> 2) as u64)
-            }
-            2 => {
-                let f1 = self.buffer.get::<u8>().map_err(Error::GetError)?;
-                let f2 = self.buffer.get::<u16>().map_err(Error::GetError)?;
-                ((f2 as u64) << 14) |((f1 as u64) << 6) | ((f0 >> 2) as u64)
-            }
-            3 => {
-                let f1 = self.buffer.get::<u8>().map_err(Error::GetError)?;
-                let f2 = self.buffer.get::<u16>().map_err(Error::GetError)?;
-                let f3 = self.buffer.get::<u32>().map_err(Error::GetError)?;
-
-                ((f3 as u64) << 30) | ((f2 as u64) << 14) | ((f1 as u64) << 6) | ((f0 >> 2) as u64)
-            }
-            _ => panic!(),
-        };
-
-        let u8slice = self.buffer.get_slice(len as usize).map_err(Error::GetError)?;
-        let strslice = str::from_utf8(u8slice).map_err(Error::UTF8Error)?;
+        let strslice = decode_stored_string(&mut self.buffer)?;
         callbacks.handle_string(strslice);
 
         Ok(())
@@ -429,3 +543,33 @@ impl<'a, 'b> Decoder<'a, 'b> {
         Ok(())
     }
 }
+
+/// Reads a string as written by `encoder::encode_stored_string`.
+pub fn decode_stored_string<'a>(buffer: &mut BufDecoder<'a>) -> Result<&'a str, Error> {
+    let f0 = buffer.get::<u8>().map_err(Error::GetError)?;
+
+    let extra_header = f0 & 0x3;
+    let len = match extra_header {
+        0 => (f0 >> 2) as u64,
+        1 => {
+            let f1 = buffer.get::<u8>().map_err(Error::GetError)?;
+            ((f1 as u64) << 6) | ((f0 >> 2) as u64)
+        }
+        2 => {
+            let f1 = buffer.get::<u8>().map_err(Error::GetError)?;
+            let f2 = buffer.get::<u16>().map_err(Error::GetError)?;
+            ((f2 as u64) << 14) |((f1 as u64) << 6) | ((f0 >> 2) as u64)
+        }
+        3 => {
+            let f1 = buffer.get::<u8>().map_err(Error::GetError)?;
+            let f2 = buffer.get::<u16>().map_err(Error::GetError)?;
+            let f3 = buffer.get::<u32>().map_err(Error::GetError)?;
+
+            ((f3 as u64) << 30) | ((f2 as u64) << 14) | ((f1 as u64) << 6) | ((f0 >> 2) as u64)
+        }
+        _ => panic!(),
+    };
+
+    let u8slice = buffer.get_slice(len as usize).map_err(Error::GetError)?;
+    str::from_utf8(u8slice).map_err(Error::UTF8Error)
+}
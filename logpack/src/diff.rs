@@ -0,0 +1,176 @@
+use super::Description;
+use super::Named;
+use super::Struct;
+use super::decoder::TypeNameId;
+
+use serde_derive::{Serialize, Deserialize};
+use std::fmt;
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Structural diff between two definitions of the same type
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Difference {
+    /// Where the definitions diverge, e.g. `.req.status` or `::Failed.0`.
+    pub path: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = if self.path.is_empty() { "<root>" } else { self.path.as_str() };
+        write!(f, "{}: {} -> {}", path, self.old, self.new)
+    }
+}
+
+pub fn diff_named(old: &Named<TypeNameId>, new: &Named<TypeNameId>) -> Vec<Difference> {
+    let mut diffs = vec![];
+    named(&mut diffs, "", old, new);
+    diffs
+}
+
+pub fn diff_desc(old: &Description<TypeNameId>, new: &Description<TypeNameId>) -> Vec<Difference> {
+    let mut diffs = vec![];
+    desc(&mut diffs, "", old, new);
+    diffs
+}
+
+fn push(diffs: &mut Vec<Difference>, path: &str, old: String, new: String) {
+    diffs.push(Difference { path: path.to_owned(), old, new });
+}
+
+fn named(diffs: &mut Vec<Difference>, path: &str, old: &Named<TypeNameId>, new: &Named<TypeNameId>) {
+    match (old, new) {
+        (Named::Enum(old), Named::Enum(new)) => {
+            items(diffs, path, "::", old, new, struct_)
+        }
+        (Named::Struct(old), Named::Struct(new)) => struct_(diffs, path, old, new),
+        (old, new) => push(diffs, path, named_summary(old), named_summary(new)),
+    }
+}
+
+fn struct_(diffs: &mut Vec<Difference>, path: &str, old: &Struct<TypeNameId>, new: &Struct<TypeNameId>) {
+    match (old, new) {
+        (Struct::Unit, Struct::Unit) => {}
+        (Struct::Tuple(old), Struct::Tuple(new)) => tuple(diffs, path, old, new),
+        (Struct::Named(old), Struct::Named(new)) => {
+            items(diffs, path, ".", old, new, desc)
+        }
+        (old, new) => push(diffs, path, struct_summary(old), struct_summary(new)),
+    }
+}
+
+/// Matches named items (fields or variants) by name, reporting removals,
+/// additions, moves and changes of the items present on both sides.
+fn items<V, F>(diffs: &mut Vec<Difference>, path: &str, sep: &str,
+               old: &[(String, V)], new: &[(String, V)], sub: F)
+    where F: Fn(&mut Vec<Difference>, &str, &V, &V)
+{
+    for (old_idx, (name, old_item)) in old.iter().enumerate() {
+        let item_path = format!("{}{}{}", path, sep, name);
+        match new.iter().position(|(new_name, _)| new_name == name) {
+            None => push(diffs, &item_path, "present".to_owned(), "removed".to_owned()),
+            Some(new_idx) => {
+                if new_idx != old_idx {
+                    push(diffs, &item_path, format!("at index {}", old_idx),
+                         format!("at index {}", new_idx));
+                }
+                sub(diffs, &item_path, old_item, &new[new_idx].1);
+            }
+        }
+    }
+
+    for (name, _) in new.iter() {
+        if !old.iter().any(|(old_name, _)| old_name == name) {
+            push(diffs, &format!("{}{}{}", path, sep, name), "absent".to_owned(), "added".to_owned());
+        }
+    }
+}
+
+fn tuple(diffs: &mut Vec<Difference>, path: &str,
+         old: &[Description<TypeNameId>], new: &[Description<TypeNameId>])
+{
+    if old.len() != new.len() {
+        push(diffs, path, format!("{} items", old.len()), format!("{} items", new.len()));
+        return;
+    }
+
+    for (idx, (old, new)) in old.iter().zip(new.iter()).enumerate() {
+        desc(diffs, &format!("{}.{}", path, idx), old, new);
+    }
+}
+
+fn desc(diffs: &mut Vec<Difference>, path: &str,
+        old: &Description<TypeNameId>, new: &Description<TypeNameId>)
+{
+    use Description::*;
+
+    match (old, new) {
+        (Option(old), Option(new)) => desc(diffs, &format!("{}?", path), old, new),
+        (Slice(old), Slice(new)) => desc(diffs, &format!("{}[]", path), old, new),
+        (Array(old_size, old), Array(new_size, new)) if old_size == new_size => {
+            desc(diffs, &format!("{}[]", path), old, new)
+        }
+        (Result(old_ok, old_err), Result(new_ok, new_err)) => {
+            desc(diffs, &format!("{}::Ok", path), old_ok, new_ok);
+            desc(diffs, &format!("{}::Err", path), old_err, new_err);
+        }
+        (Tuple(old), Tuple(new)) => tuple(diffs, path, old, new),
+        (ByName(old_id, Some(old)), ByName(new_id, Some(new))) if old_id == new_id => {
+            named(diffs, path, old, new)
+        }
+        (ByName(old_id, _), ByName(new_id, _)) if old_id == new_id => {}
+        (old, new) => {
+            if old != new {
+                push(diffs, path, summary(old), summary(new))
+            }
+        }
+    }
+}
+
+/// A one-line rendering of a description, without expanding named types.
+pub fn summary(desc: &Description<TypeNameId>) -> String {
+    use Description::*;
+
+    match desc {
+        U64 => "u64".to_owned(),
+        U32 => "u32".to_owned(),
+        U16 => "u16".to_owned(),
+        U8 => "u8".to_owned(),
+        I64 => "i64".to_owned(),
+        I32 => "i32".to_owned(),
+        I16 => "i16".to_owned(),
+        I8 => "i8".to_owned(),
+        Unit => "()".to_owned(),
+        PhantomData => "PhantomData".to_owned(),
+        Bool => "bool".to_owned(),
+        String => "String".to_owned(),
+        RawPtr => "*const _".to_owned(),
+        Option(sub) => format!("Option<{}>", summary(sub)),
+        Result(ok, err) => format!("Result<{}, {}>", summary(ok), summary(err)),
+        Array(size, sub) => format!("[{}; {}]", summary(sub), size),
+        Slice(sub) => format!("[{}]", summary(sub)),
+        Tuple(subs) => {
+            let subs: Vec<_> = subs.iter().map(summary).collect();
+            format!("({})", subs.join(", "))
+        }
+        ByName((name, id), _) => format!("{}#{}", name, id),
+    }
+}
+
+fn named_summary(named: &Named<TypeNameId>) -> String {
+    match named {
+        Named::Enum(variants) => format!("enum with {} variants", variants.len()),
+        Named::Struct(struct_) => struct_summary(struct_),
+    }
+}
+
+pub fn struct_summary(struct_: &Struct<TypeNameId>) -> String {
+    match struct_ {
+        Struct::Unit => "unit struct".to_owned(),
+        Struct::Tuple(fields) => format!("tuple struct with {} fields", fields.len()),
+        Struct::Named(fields) => format!("struct with {} fields", fields.len()),
+    }
+}
@@ -83,7 +83,7 @@ pub fn encode_stored_string(value: &str, buf: &mut buffers::BufEncoder)
     Ok(())
 }
 
-impl<'a> Encoder for &'a str {
+impl Encoder for str {
     #[inline(always)]
     fn logpack_encode(&self, buf: &mut buffers::BufEncoder) -> Result<(), (usize, usize)> {
         encode_stored_string(self, buf)
@@ -137,7 +137,7 @@ array_impls!(00
 
 macro_rules! tuple {
     ($(($type:ident, $num:tt)),*) => {
-        impl<$($type),*> Encoder for ($($type),*)
+        impl<$($type),*> Encoder for ($($type,)*)
             where $($type : Encoder),*
         {
             fn logpack_encode(&self, buf: &mut buffers::BufEncoder) -> Result<(), (usize, usize)> {
@@ -156,6 +156,7 @@ macro_rules! tuple {
     }
 }
 
+tuple!((A, 0));
 tuple!((A, 0), (B, 1));
 tuple!((A, 0), (B, 1), (C, 2));
 tuple!((A, 0), (B, 1), (C, 2), (D, 3));
@@ -189,14 +190,32 @@ impl<T> Encoder for [T]
 
     #[inline(always)]
     fn logpack_sizer(&self) -> usize {
-        let mut size = 0;
-        for i in 0..size {
-            size += self[i as usize].logpack_sizer();
+        let mut size = size_of::<u64>();
+        for item in self.iter() {
+            size += item.logpack_sizer();
         }
         size
     }
 }
 
+macro_rules! deref_impl {
+    ($($desc:tt)+) => {
+        impl $($desc)+ {
+            #[inline(always)]
+            fn logpack_encode(&self, buf: &mut buffers::BufEncoder) -> Result<(), (usize, usize)> {
+                (**self).logpack_encode(buf)
+            }
+
+            #[inline(always)]
+            fn logpack_sizer(&self) -> usize {
+                (**self).logpack_sizer()
+            }
+        }
+    };
+}
+
+deref_impl!(<'a, T: ?Sized> Encoder for &'a T where T: Encoder);
+deref_impl!(<'a, T: ?Sized> Encoder for &'a mut T where T: Encoder);
 
 impl<T> Encoder for Box<T>
     where T: Encoder
@@ -0,0 +1,247 @@
+use super::{Description, Named, Struct, Logpack, RefDesc, SeenTypes};
+use super::buffers::{BufEncoder, BufDecoder};
+use super::decoder::{self, decode_stored_string};
+use super::encoder::Encoder;
+
+use cfg_if::cfg_if;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Log record envelope
+//
+// The metadata common to all log records. In a stream, an entry record
+// carries the encoded envelope followed by the payload value, whose type
+// index is repeated in the envelope.
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub const ALL: [Level; 5] = [Level::Trace, Level::Debug, Level::Info, Level::Warn, Level::Error];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+
+    fn variant_name(self) -> &'static str {
+        match self {
+            Level::Trace => "Trace",
+            Level::Debug => "Debug",
+            Level::Info => "Info",
+            Level::Warn => "Warn",
+            Level::Error => "Error",
+        }
+    }
+
+    pub fn from_index(idx: u8) -> Option<Level> {
+        Self::ALL.get(idx as usize).cloned()
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    /// Accepts both `WARN` and `Warn` spellings, in any case.
+    fn from_str(s: &str) -> Result<Self, ()> {
+        Self::ALL.iter().cloned().find(|l| l.as_str().eq_ignore_ascii_case(s)).ok_or(())
+    }
+}
+
+impl Logpack for Level {
+    fn logpack_describe(st: &mut SeenTypes) -> RefDesc {
+        let (first_seen, typename_id) = st.make_name_for::<Self>();
+        let may_recurse = if first_seen {
+            Some(Named::Enum(Self::ALL.iter().map(|l| (l.variant_name(), Struct::Unit)).collect()))
+        } else {
+            None
+        };
+
+        Description::ByName(typename_id, may_recurse)
+    }
+}
+
+impl Encoder for Level {
+    fn logpack_encode(&self, buf: &mut BufEncoder) -> Result<(), (usize, usize)> {
+        (*self as u8).logpack_encode(buf)
+    }
+
+    fn logpack_sizer(&self) -> usize {
+        1
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Envelope<'a> {
+    /// Wall-clock time, in nanoseconds since the Unix epoch.
+    pub time: u64,
+    /// Monotonic clock time, in nanoseconds, for ordering and durations.
+    pub monotonic: u64,
+    pub level: Level,
+    /// Identifies the module, file and line that logged the record.
+    pub callsite: u32,
+    pub thread_id: u64,
+    pub thread_name: &'a str,
+    /// Type index of the payload in the stream.
+    pub payload_type: u32,
+}
+
+impl<'a> Logpack for Envelope<'a> {
+    fn logpack_describe(st: &mut SeenTypes) -> RefDesc {
+        let (first_seen, typename_id) = st.make_name_for_borrowed::<Self>();
+        let may_recurse = if first_seen {
+            Some(Named::Struct(Struct::Named(vec![
+                ("time", u64::logpack_describe(st)),
+                ("monotonic", u64::logpack_describe(st)),
+                ("level", Level::logpack_describe(st)),
+                ("callsite", u32::logpack_describe(st)),
+                ("thread_id", u64::logpack_describe(st)),
+                ("thread_name", str::logpack_describe(st)),
+                ("payload_type", u32::logpack_describe(st)),
+            ])))
+        } else {
+            None
+        };
+
+        Description::ByName(typename_id, may_recurse)
+    }
+}
+
+impl<'a> Encoder for Envelope<'a> {
+    fn logpack_encode(&self, buf: &mut BufEncoder) -> Result<(), (usize, usize)> {
+        self.time.logpack_encode(buf)?;
+        self.monotonic.logpack_encode(buf)?;
+        self.level.logpack_encode(buf)?;
+        self.callsite.logpack_encode(buf)?;
+        self.thread_id.logpack_encode(buf)?;
+        self.thread_name.logpack_encode(buf)?;
+        self.payload_type.logpack_encode(buf)
+    }
+
+    fn logpack_sizer(&self) -> usize {
+        self.time.logpack_sizer() +
+            self.monotonic.logpack_sizer() +
+            self.level.logpack_sizer() +
+            self.callsite.logpack_sizer() +
+            self.thread_id.logpack_sizer() +
+            self.thread_name.logpack_sizer() +
+            self.payload_type.logpack_sizer()
+    }
+}
+
+impl<'a> Envelope<'a> {
+    /// Reads an envelope directly, without going through a `Decoder`, as
+    /// readers need it for every record.
+    pub fn decode(buf: &mut BufDecoder<'a>) -> Result<Self, decoder::Error> {
+        let time = buf.get::<u64>().map_err(decoder::Error::GetError)?;
+        let monotonic = buf.get::<u64>().map_err(decoder::Error::GetError)?;
+        let level = buf.get::<u8>().map_err(decoder::Error::GetError)?;
+        let level = Level::from_index(level)
+            .ok_or(decoder::Error::InvalidIndex(level as usize, Level::ALL.len()))?;
+        let callsite = buf.get::<u32>().map_err(decoder::Error::GetError)?;
+        let thread_id = buf.get::<u64>().map_err(decoder::Error::GetError)?;
+        let thread_name = decode_stored_string(buf)?;
+        let payload_type = buf.get::<u32>().map_err(decoder::Error::GetError)?;
+
+        Ok(Self { time, monotonic, level, callsite, thread_id, thread_name, payload_type })
+    }
+}
+
+impl Envelope<'static> {
+    /// An envelope stamped with the current time and thread. The payload
+    /// type is filled in when the record is written to a stream.
+    pub fn now(level: Level, callsite: u32) -> Self {
+        let (thread_id, thread_name) = current_thread();
+
+        Self {
+            time: wall_time(),
+            monotonic: monotonic_time(),
+            level,
+            callsite,
+            thread_id,
+            thread_name,
+            payload_type: 0,
+        }
+    }
+}
+
+/// The time of an encoded envelope, e.g. at the start of a log entry
+/// record, without decoding the rest of it.
+pub fn peek_time(bytes: &[u8]) -> Option<u64> {
+    BufDecoder::new(bytes).get::<u64>().ok()
+}
+
+pub fn wall_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+cfg_if! {
+    if #[cfg(unix)] {
+        pub fn monotonic_time() -> u64 {
+            let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            unsafe {
+                libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+            }
+            ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+        }
+    } else {
+        /// Counts from the first call in the process, rather than from boot.
+        pub fn monotonic_time() -> u64 {
+            use std::sync::OnceLock;
+            use std::time::Instant;
+
+            static START: OnceLock<Instant> = OnceLock::new();
+            START.get_or_init(Instant::now).elapsed().as_nanos() as u64
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        fn os_thread_id() -> u64 {
+            unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+        }
+    } else {
+        fn os_thread_id() -> u64 {
+            use std::sync::atomic::{AtomicU64, Ordering};
+
+            static NEXT: AtomicU64 = AtomicU64::new(1);
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+}
+
+/// The id and name of the calling thread. Names are interned, so threads
+/// that come and go under the same names don't keep allocating.
+pub fn current_thread() -> (u64, &'static str) {
+    thread_local! {
+        static CURRENT: (u64, &'static str) = (
+            os_thread_id(),
+            std::thread::current().name().map(|n| super::intern(n.to_owned())).unwrap_or(""),
+        );
+    }
+
+    CURRENT.with(|x| *x)
+}
@@ -0,0 +1,657 @@
+use super::{Logpack, Encoder};
+use super::block::{Unpacker, RECORD_BLOCK, RECORD_BLOCK_INDEX};
+use super::decoder::NameMap;
+use super::envelope::{self, Envelope};
+use super::framing::{FrameReader, FrameWriter, SeekIndex, SeekPoint, SyncPoint, HEADER_LEN,
+                     MAX_FRAME_LEN};
+use super::stream::{self, is_definition, Record, RecordSource, StreamReader, StreamWriter, Value,
+                    RECORD_ENTRY};
+
+use cfg_if::cfg_if;
+use serde::Serialize;
+use serde_derive::{Serialize, Deserialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Log file container
+//
+//     file    := header frame* [footer* index trailer]
+//     header  := "LOGPACK\0" u32(version) u32(len) metadata
+//     footer  := frame(payload u32(payload len))
+//     trailer := u64(index offset) "LPINDEX\0"
+//
+// All integers are little-endian, and the metadata is bincode-serialized.
+// The records of `stream` follow in checksummed frames, as described in
+// `framing`, which also places the sync frames a reader recovers from
+// corruption with. Records may be compressed in blocks, as described in
+// `block`, which the reader expands in place.
+//
+// When the file is finished properly, a `RECORD_INDEX` frame holding the
+// bincode-serialized offsets of the sync frames is written, followed by the
+// trailer. Files of processes that crashed lack them and may end in a partial
+// frame, which the reader ignores, until `reindex` is run on them.
+//
+// Other indices go in footer records right before the index, each ending
+// with its length so that they can be found walking back from the index:
+// the `SeekIndex` of the frames, in a `RECORD_SEEK_INDEX` frame, and the
+// index of `block`, if there is one.
+
+pub const MAGIC: &[u8; 8] = b"LOGPACK\0";
+pub const VERSION: u32 = 2;
+
+const TRAILER_MAGIC: &[u8; 8] = b"LPINDEX\0";
+const TRAILER_LEN: usize = 16;
+
+pub const RECORD_INDEX: u8 = 0x7f;
+pub const RECORD_SEEK_INDEX: u8 = 0x7e;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Metadata {
+    pub pid: u32,
+    pub hostname: String,
+    /// Nanoseconds since the Unix epoch.
+    pub start_time: u64,
+    pub binary: String,
+}
+
+impl Metadata {
+    /// Describes the current process.
+    pub fn current() -> Self {
+        let binary = std::env::current_exe().ok()
+            .and_then(|path| path.file_name().map(|x| x.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+        let start_time = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        Self {
+            pid: std::process::id(),
+            hostname: hostname(),
+            start_time,
+            binary,
+        }
+    }
+}
+
+cfg_if! {
+    if #[cfg(unix)] {
+        fn hostname() -> String {
+            let mut buf = [0u8; 256];
+            let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+            if ret != 0 {
+                return String::new();
+            }
+            let len = buf.iter().position(|x| *x == 0).unwrap_or(buf.len());
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        }
+    } else {
+        fn hostname() -> String {
+            std::env::var("COMPUTERNAME").unwrap_or_default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// A header with metadata longer than `MAX_FRAME_LEN`.
+    BadHeader,
+    Bincode(bincode::Error),
+    Stream(stream::Error),
+    /// An index pointing at something other than what it says.
+    BadIndex,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Writer
+
+pub struct FileWriter<W: Write> {
+    stream: StreamWriter<FrameWriter<W>>,
+}
+
+impl FileWriter<BufWriter<File>> {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), &Metadata::current())
+    }
+}
+
+/// Writes the file header and returns the writer of the frames that follow,
+/// for when the stream is to be owned elsewhere, e.g. by a shared sink. Such
+/// a file ends without an index unless `finish` is called on it.
+pub fn frame_writer<W: Write>(mut out: W, meta: &Metadata) -> io::Result<FrameWriter<W>> {
+    let meta = bincode::serialize(meta).map_err(io::Error::other)?;
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&(meta.len() as u32).to_le_bytes())?;
+    out.write_all(&meta)?;
+
+    let position = (MAGIC.len() + 8 + meta.len()) as u64;
+    Ok(FrameWriter::new(out, position))
+}
+
+/// Serializes a footer record, followed by its length.
+pub(crate) fn footer_record<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+    let mut payload = bincode::serialize(value).map_err(io::Error::other)?;
+    let len = payload.len() as u32;
+    payload.extend_from_slice(&len.to_le_bytes());
+    Ok(payload)
+}
+
+/// Writes the footer indices and trailer after the last frame.
+pub fn finish<W: Write>(mut frames: FrameWriter<W>) -> io::Result<W> {
+    let seek_index = footer_record(frames.seek_index())?;
+    frames.put_raw(RECORD_SEEK_INDEX, 0, &seek_index)?;
+
+    let index_offset = frames.position();
+    let index = bincode::serialize(frames.sync_points()).map_err(io::Error::other)?;
+    frames.put_raw(RECORD_INDEX, 0, &index)?;
+
+    let mut out = frames.into_inner();
+    out.write_all(&index_offset.to_le_bytes())?;
+    out.write_all(TRAILER_MAGIC)?;
+    out.flush()?;
+    Ok(out)
+}
+
+impl<W: Write> FileWriter<W> {
+    pub fn new(out: W, meta: &Metadata) -> io::Result<Self> {
+        Ok(Self { stream: StreamWriter::new(frame_writer(out, meta)?) })
+    }
+
+    /// Writes the schema section: definitions known up front, e.g. from a
+    /// `registry` file. Types not covered are defined on first use anyway.
+    pub fn write_schema(&mut self, names: &NameMap) -> io::Result<()> {
+        self.stream.write_schema(names)
+    }
+
+    pub fn register<T: Logpack + ?Sized>(&mut self) -> io::Result<u32> {
+        self.stream.register::<T>()
+    }
+
+    pub fn write<T: Logpack + Encoder + ?Sized>(&mut self, value: &T) -> io::Result<()> {
+        self.stream.write(value)
+    }
+
+    pub fn write_entry<T>(&mut self, envelope: &Envelope, value: &T) -> io::Result<()>
+        where T: Logpack + Encoder + ?Sized
+    {
+        self.stream.write_entry(envelope, value)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+
+    pub fn stream(&mut self) -> &mut StreamWriter<FrameWriter<W>> {
+        &mut self.stream
+    }
+
+    /// Writes the footer indices and trailer.
+    pub fn finish(self) -> io::Result<W> {
+        finish(self.stream.into_inner())
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Reader
+
+pub struct FileSource<R: BufRead> {
+    frames: FrameReader<R>,
+    complete: bool,
+    blocks: Unpacker,
+    /// Offset of the first frame.
+    start: u64,
+    seq: u64,
+    skip: Skip,
+    /// Set by `FileReader::follow`, to go back to the start of a frame that
+    /// isn't fully written yet.
+    rewind: Option<Rewind<R>>,
+}
+
+/// Goes back to a frame's offset and sequence number, as `FrameReader::seek`.
+type Rewind<R> = fn(&mut FrameReader<R>, u64, u64) -> io::Result<()>;
+
+/// Records to leave out after seeking to a point before the ones sought.
+#[derive(Clone, Copy)]
+enum Skip {
+    Nothing,
+    ToSeq(u64),
+    ToTime(u64),
+}
+
+impl<R: BufRead> FileSource<R> {
+    /// Whether the footer was reached, i.e. the file was finished properly.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    pub fn frames(&self) -> &FrameReader<R> {
+        &self.frames
+    }
+
+    pub fn frames_mut(&mut self) -> &mut FrameReader<R> {
+        &mut self.frames
+    }
+
+    /// The sequence number of the frame of the last record read, or of the
+    /// block it was in.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    fn next_unskipped(&mut self, payload: &mut Vec<u8>) -> io::Result<Option<(u8, u32)>> {
+        loop {
+            if let Some(record) = self.blocks.next(payload)? {
+                return Ok(Some(record));
+            }
+            if self.complete {
+                return Ok(None);
+            }
+
+            let (position, seq) = (self.frames.position(), self.frames.frames());
+            let record = self.frames.next_record(payload)?;
+            self.seq = self.frames.frames().saturating_sub(1);
+            match record {
+                None => {
+                    if let Some(rewind) = self.rewind {
+                        rewind(&mut self.frames, position, seq)?;
+                    }
+                    return Ok(None);
+                }
+                Some((RECORD_INDEX, _)) => {
+                    let mut trailer = [0u8; TRAILER_LEN];
+                    let read = self.frames.read_rest(&mut trailer)?;
+                    self.complete = read == TRAILER_LEN && &trailer[8..] == TRAILER_MAGIC;
+                    return Ok(None);
+                }
+                Some((RECORD_BLOCK, codec)) => self.blocks.unpack(codec, payload)?,
+                Some((RECORD_BLOCK_INDEX, _)) | Some((RECORD_SEEK_INDEX, _)) => {}
+                record => return Ok(record),
+            }
+        }
+    }
+
+    fn is_skipped(&mut self, kind: u8, payload: &[u8]) -> bool {
+        if is_definition(kind) {
+            return false;
+        }
+
+        let skipped = match self.skip {
+            Skip::Nothing => false,
+            Skip::ToSeq(seq) => self.seq < seq,
+            Skip::ToTime(time) => kind != RECORD_ENTRY
+                || envelope::peek_time(payload).is_some_and(|t| t < time),
+        };
+        if !skipped {
+            self.skip = Skip::Nothing;
+        }
+        skipped
+    }
+}
+
+impl<R: BufRead> RecordSource for FileSource<R> {
+    fn next_record(&mut self, payload: &mut Vec<u8>) -> io::Result<Option<(u8, u32)>> {
+        loop {
+            match self.next_unskipped(payload)? {
+                Some((kind, _)) if self.is_skipped(kind, payload) => {}
+                record => return Ok(record),
+            }
+        }
+    }
+}
+
+pub struct FileReader<R: BufRead> {
+    stream: StreamReader<FileSource<R>>,
+    meta: Metadata,
+    version: u32,
+    /// How many of the definitions of a seek index were applied by seeking.
+    defined: usize,
+}
+
+impl FileReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+/// Reads the file header, returning the metadata, version and the offset of
+/// the first frame.
+fn read_header<R: Read>(input: &mut R) -> Result<(Metadata, u32, u64), Error> {
+    let mut header = [0u8; 16];
+    input.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(Error::BadMagic);
+    }
+    let version = u32_at(&header, 8);
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let meta_len = u32_at(&header, 12) as usize;
+    if meta_len > MAX_FRAME_LEN {
+        return Err(Error::BadHeader);
+    }
+    let mut meta = vec![0u8; meta_len];
+    input.read_exact(&mut meta)?;
+    let meta = bincode::deserialize(&meta).map_err(Error::Bincode)?;
+
+    Ok((meta, version, (header.len() + meta_len) as u64))
+}
+
+impl<R: BufRead> FileReader<R> {
+    pub fn new(mut input: R) -> Result<Self, Error> {
+        let (meta, version, start) = read_header(&mut input)?;
+
+        let source = FileSource {
+            frames: FrameReader::new(input, start),
+            complete: false,
+            blocks: Unpacker::default(),
+            start,
+            seq: 0,
+            skip: Skip::Nothing,
+            rewind: None,
+        };
+
+        Ok(Self {
+            stream: StreamReader::new(source),
+            meta,
+            version,
+            defined: 0,
+        })
+    }
+
+    pub fn meta(&self) -> &Metadata {
+        &self.meta
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn name_map(&self) -> &NameMap {
+        self.stream.name_map()
+    }
+
+    pub fn source(&self) -> &FileSource<R> {
+        self.stream.get_ref()
+    }
+
+    pub fn stream(&mut self) -> &mut StreamReader<FileSource<R>> {
+        &mut self.stream
+    }
+
+    /// Returns the stream reader, e.g. to merge it with others.
+    pub fn into_stream(self) -> StreamReader<FileSource<R>> {
+        self.stream
+    }
+
+    pub fn next_record(&mut self) -> Result<Option<Record<'_>>, Error> {
+        self.stream.next_record().map_err(Error::Stream)
+    }
+
+    pub fn next_value(&mut self) -> Result<Option<Value<'_>>, Error> {
+        self.stream.next_value().map_err(Error::Stream)
+    }
+}
+
+impl<R: BufRead + Seek> FileReader<R> {
+    /// Follows a file that is still being written, as `tail -f` does. Once
+    /// no more complete frames are left, reading returns `None` and goes back
+    /// to the start of the partly written frame, if any, so that reading
+    /// again later picks it up whole. `FileSource::is_complete` tells when
+    /// the file is finished.
+    pub fn follow(&mut self) {
+        self.stream.get_mut().rewind = Some(FrameReader::seek);
+    }
+
+    /// Reads the seek index of a finished file, staying where the reader
+    /// was.
+    pub fn seek_index(&mut self) -> Result<Option<SeekIndex>, Error> {
+        let frames = &mut self.stream.get_mut().frames;
+        let (position, seq) = (frames.position(), frames.frames());
+        let index = read_seek_index(frames.get_mut());
+        frames.seek(position, seq)?;
+        index
+    }
+
+    /// Moves to the first entry at or after `time`, in nanoseconds since the
+    /// Unix epoch. Entries of different threads may be somewhat out of
+    /// order, so later ones may still be older.
+    pub fn seek_time(&mut self, index: &SeekIndex, time: u64) -> Result<(), Error> {
+        let point = index.point_for_time(time).cloned();
+        self.seek(index, point, Skip::ToTime(time))
+    }
+
+    /// Moves to the first value in the frame with sequence number `seq` or
+    /// after it.
+    pub fn seek_seq(&mut self, index: &SeekIndex, seq: u64) -> Result<(), Error> {
+        let point = index.point_for_seq(seq).cloned();
+        self.seek(index, point, Skip::ToSeq(seq))
+    }
+
+    fn seek(&mut self, index: &SeekIndex, point: Option<SeekPoint>, skip: Skip) -> Result<(), Error> {
+        let start = self.stream.get_ref().start;
+        let (offset, seq) = point.map(|p| (p.offset, p.seq)).unwrap_or((start, 0));
+
+        // The definitions before the point, as reading from there won't
+        // come across them.
+        let mut payload = vec![];
+        while let Some(&definition) = index.definitions.get(self.defined) {
+            if definition >= offset {
+                break;
+            }
+            let frames = &mut self.stream.get_mut().frames;
+            frames.seek(definition, 0)?;
+            match frames.next_record(&mut payload)? {
+                Some((kind, type_index)) if is_definition(kind) => {
+                    self.stream.apply_definition(kind, type_index, &payload).map_err(Error::Stream)?;
+                }
+                _ => return Err(Error::BadIndex),
+            }
+            self.defined += 1;
+        }
+
+        let source = self.stream.get_mut();
+        source.frames.seek(offset, seq)?;
+        source.blocks = Unpacker::default();
+        source.complete = false;
+        source.skip = skip;
+        Ok(())
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut v = [0u8; 4];
+    v.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(v)
+}
+
+/// Returns the offset of the footer index from the trailer of a finished
+/// file.
+pub(crate) fn index_offset<R: Read + Seek>(input: &mut R) -> io::Result<Option<u64>> {
+    let len = input.seek(SeekFrom::End(0))?;
+    if len < TRAILER_LEN as u64 {
+        return Ok(None);
+    }
+
+    let mut trailer = [0u8; TRAILER_LEN];
+    input.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    input.read_exact(&mut trailer)?;
+    if &trailer[8..] != TRAILER_MAGIC {
+        return Ok(None);
+    }
+
+    let mut offset = [0u8; 8];
+    offset.copy_from_slice(&trailer[..8]);
+    let offset = u64::from_le_bytes(offset);
+    if offset > len - TRAILER_LEN as u64 {
+        return Ok(None);
+    }
+    Ok(Some(offset))
+}
+
+/// Reads the frame at `offset`, or the one after a sync frame there.
+pub(crate) fn read_frame<R: Read + Seek>(input: &mut R, offset: u64)
+    -> io::Result<Option<(u8, u32, Vec<u8>)>>
+{
+    input.seek(SeekFrom::Start(offset))?;
+    let mut frames = FrameReader::new(BufReader::new(input), offset);
+    let mut payload = vec![];
+    match frames.next_record(&mut payload)? {
+        Some((kind, type_index)) if frames.bad_frames().is_empty() => Ok(Some((kind, type_index, payload))),
+        _ => Ok(None),
+    }
+}
+
+/// Reads the payload of the footer record of the given kind.
+pub(crate) fn read_footer_record<R: Read + Seek>(input: &mut R, kind: u8) -> Result<Option<Vec<u8>>, Error> {
+    let mut end = match index_offset(input)? {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+
+    loop {
+        if end < (HEADER_LEN + 4) as u64 {
+            return Ok(None);
+        }
+        let mut len = [0u8; 4];
+        input.seek(SeekFrom::Start(end - 4))?;
+        input.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as u64;
+        let frame_len = HEADER_LEN as u64 + len + 4;
+        if frame_len > end {
+            return Ok(None);
+        }
+
+        let start = end - frame_len;
+        match read_frame(input, start)? {
+            Some((found, _, mut payload)) if payload.len() as u64 == len + 4 => {
+                if found == kind {
+                    payload.truncate(len as usize);
+                    return Ok(Some(payload));
+                }
+                end = start;
+            }
+            _ => return Ok(None),
+        }
+    }
+}
+
+/// Reads the seek index of a finished file, or returns `None` for a file
+/// that doesn't have one.
+pub fn read_seek_index<R: Read + Seek>(input: &mut R) -> Result<Option<SeekIndex>, Error> {
+    match read_footer_record(input, RECORD_SEEK_INDEX)? {
+        Some(payload) => Ok(Some(bincode::deserialize(&payload).map_err(Error::Bincode)?)),
+        None => Ok(None),
+    }
+}
+
+/// Reads the footer index of a finished file, or returns `None` for a file
+/// that doesn't have one.
+pub fn read_index<R: Read + Seek>(input: &mut R) -> Result<Option<Vec<SyncPoint>>, Error> {
+    let offset = match index_offset(input)? {
+        Some(offset) => offset,
+        None => return Ok(None),
+    };
+
+    let len = input.seek(SeekFrom::End(0))?;
+    input.seek(SeekFrom::Start(offset))?;
+    let index_len = len - TRAILER_LEN as u64 - offset;
+    let mut frames = FrameReader::new(BufReader::new(input.take(index_len)), offset);
+    let mut index = vec![];
+    match frames.next_record(&mut index)? {
+        Some((RECORD_INDEX, _)) => Ok(Some(bincode::deserialize(&index).map_err(Error::Bincode)?)),
+        _ => Ok(None),
+    }
+}
+
+/// What `reindex` found.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Reindexed {
+    pub frames: u64,
+    pub seek_points: usize,
+    pub bad_frames: usize,
+    /// Bytes dropped from the end: the previous footer, or a partial frame.
+    pub dropped: u64,
+}
+
+/// Rebuilds the footer of a log file, e.g. of one whose process crashed, or
+/// of one written before an index it lacks was added.
+pub fn reindex(path: &Path) -> Result<Reindexed, Error> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let len = file.metadata()?.len();
+
+    let mut input = BufReader::new(&file);
+    let (_, _, start) = read_header(&mut input)?;
+    let mut frames = FrameReader::new(input, start).with_sync_points();
+    let mut seek_index = SeekIndex::default();
+    let mut blocks = Unpacker::default();
+    let mut latest: Option<u64> = None;
+    let mut payload = vec![];
+    let mut record = vec![];
+    let mut end = start;
+    let mut count = 0;
+
+    loop {
+        let synced = frames.sync_points().len();
+        let (kind, type_index) = match frames.next_record(&mut payload)? {
+            None => break,
+            Some(record) => record,
+        };
+        let offset = frames.position() - (HEADER_LEN + payload.len()) as u64;
+        if kind == RECORD_INDEX || kind == RECORD_SEEK_INDEX {
+            break;
+        }
+
+        for sync in &frames.sync_points()[synced..] {
+            seek_index.points.push(SeekPoint { offset: sync.offset, seq: sync.frames, time: latest });
+        }
+
+        let mut note_time = |payload: &[u8]| {
+            if let Some(time) = envelope::peek_time(payload) {
+                latest = Some(latest.map_or(time, |latest| latest.max(time)));
+            }
+        };
+        if is_definition(kind) {
+            seek_index.definitions.push(offset);
+        } else if kind == RECORD_ENTRY {
+            note_time(&payload);
+        } else if kind == RECORD_BLOCK {
+            blocks.unpack(type_index, &payload)?;
+            while let Some((kind, _)) = blocks.next(&mut record)? {
+                if kind == RECORD_ENTRY {
+                    note_time(&record);
+                }
+            }
+        }
+
+        end = frames.position();
+        count = frames.frames();
+    }
+
+    let bad_frames = frames.bad_frames().len();
+    let sync_points: Vec<SyncPoint> = frames.sync_points().iter().cloned()
+        .filter(|sync| sync.offset < end)
+        .collect();
+    seek_index.points.retain(|point| point.offset < end);
+    drop(frames);
+
+    file.set_len(end)?;
+    let mut out = BufWriter::new(&file);
+    out.seek(SeekFrom::Start(end))?;
+    let seek_points = seek_index.points.len();
+    finish(FrameWriter::resume(out, end, count, sync_points, seek_index))?;
+
+    Ok(Reindexed { frames: count, seek_points, bad_frames, dropped: len - end })
+}
@@ -0,0 +1,452 @@
+use super::decoder::{self, NameMap, ResolvedDesc};
+use super::envelope::{Envelope, Level};
+use super::path::{Builtin, Extractor, Path, Scalar};
+use super::stream::Value;
+
+use regex::Regex;
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Filter expressions
+//
+// Select records by their content, e.g.
+//
+//     level >= WARN && req.status != 200 && req.path ~ "^/api"
+//
+// The grammar is:
+//
+//     expr    := and ("||" and)*
+//     and     := unary ("&&" unary)*
+//     unary   := "!" unary | "(" expr ")" | path [op literal]
+//     op      := "==" | "!=" | "<" | "<=" | ">" | ">=" | "~" | "!~"
+//     literal := integer | string | "true" | "false" | name
+//
+// Paths are as described in `path`, including ones starting with an index,
+// e.g. `0.code`. A path by itself tests that the part it addresses is
+// present and not `false`. A name is compared with the variant of an enum,
+// e.g. `error == Timeout`, or with a level, e.g. `level > INFO`.
+// `~` and `!~` match strings and variant names against a regular
+// expression, given as a string. Strings are in double quotes, where `\`
+// escapes the character after it.
+//
+// Comparing a missing part, or one that is not of the literal's kind, is
+// false whatever the operator, so that e.g. `x != 1` is false where `x` is
+// `None`, while `!(x == 1)` is true.
+//
+// Expressions may nest up to `MAX_DEPTH` levels of `!` and parentheses.
+//
+// The value of a record is only read if the builtin fields don't decide the
+// outcome already, and then only the fields that the paths go through.
+
+pub const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+    NotMatch,
+}
+
+impl Op {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Eq => ordering == Ordering::Equal,
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+            Op::Match | Op::NotMatch => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// What was expected at an offset in the expression.
+    Syntax(usize, &'static str),
+    UnknownLevel(usize, String),
+    Regex(usize, regex::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Syntax(offset, expected) => write!(f, "at {}: expected {}", offset, expected),
+            Error::UnknownLevel(offset, name) => write!(f, "at {}: unknown level {}", offset, name),
+            Error::Regex(offset, err) => write!(f, "at {}: {}", offset, err),
+        }
+    }
+}
+
+enum Literal {
+    Int(i128),
+    Str(String),
+    Bool(bool),
+    Name(String),
+    Level(Level),
+    Regex(Regex),
+}
+
+enum Expr {
+    Or(Vec<Expr>),
+    And(Vec<Expr>),
+    Not(Box<Expr>),
+    /// Whether the path with the index is present and not `false`.
+    Test(usize),
+    Compare(usize, Op, Literal),
+}
+
+pub struct Filter {
+    expr: Expr,
+    extractor: Extractor,
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Error> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0, end: text.len(), paths: vec![], depth: 0 };
+        let expr = parser.expr()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(Error::Syntax(parser.offset(), "`&&`, `||` or the end"));
+        }
+
+        Ok(Self { expr, extractor: Extractor::new(parser.paths) })
+    }
+}
+
+impl Filter {
+    /// The paths the expression refers to.
+    pub fn paths(&self) -> &[Path] {
+        self.extractor.paths()
+    }
+
+    pub fn matches(&self, value: &Value) -> Result<bool, decoder::Error> {
+        self.matches_data(value.envelope.as_ref(), value.desc, value.names, value.data)
+    }
+
+    /// Like `matches`, for a value of type `desc` encoded in `data`.
+    pub fn matches_data(&self, envelope: Option<&Envelope>, desc: &ResolvedDesc, names: &NameMap,
+                        data: &[u8]) -> Result<bool, decoder::Error>
+    {
+        let mut scope = Scope { extractor: &self.extractor, envelope, desc, names, data, values: None };
+        scope.eval(&self.expr)
+    }
+}
+
+struct Scope<'a> {
+    extractor: &'a Extractor,
+    envelope: Option<&'a Envelope<'a>>,
+    desc: &'a ResolvedDesc,
+    names: &'a NameMap,
+    data: &'a [u8],
+    /// The parts of the value, once read.
+    values: Option<Vec<Option<Scalar>>>,
+}
+
+impl<'a> Scope<'a> {
+    fn get(&mut self, slot: usize) -> Result<Option<Scalar>, decoder::Error> {
+        if let Path::Builtin(builtin) = &self.extractor.paths()[slot] {
+            return Ok(builtin.get(self.envelope, self.desc));
+        }
+
+        if self.values.is_none() {
+            let values = self.extractor.extract_data(self.envelope, self.desc, self.names, self.data)?;
+            self.values = Some(values);
+        }
+        Ok(self.values.as_ref().and_then(|values| values[slot].clone()))
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<bool, decoder::Error> {
+        Ok(match expr {
+            Expr::Or(exprs) => {
+                for expr in exprs {
+                    if self.eval(expr)? {
+                        return Ok(true);
+                    }
+                }
+                false
+            }
+            Expr::And(exprs) => {
+                for expr in exprs {
+                    if !self.eval(expr)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            Expr::Not(a) => !self.eval(a)?,
+            Expr::Test(slot) => match self.get(*slot)? {
+                Some(Scalar::Bool(val)) => val,
+                Some(_) => true,
+                None => false,
+            },
+            Expr::Compare(slot, op, literal) => match self.get(*slot)? {
+                Some(scalar) => compare(&scalar, *op, literal),
+                None => false,
+            },
+        })
+    }
+}
+
+fn compare(scalar: &Scalar, op: Op, literal: &Literal) -> bool {
+    if let Literal::Regex(regex) = literal {
+        let text = match scalar {
+            Scalar::Str(text) | Scalar::Variant(text) => text,
+            _ => return false,
+        };
+        return (op == Op::Match) == regex.is_match(text);
+    }
+
+    let equality = |equal: bool| match op {
+        Op::Eq => equal,
+        Op::Ne => !equal,
+        _ => false,
+    };
+
+    let ordering = match (scalar, literal) {
+        (Scalar::Int(a), Literal::Int(b)) => a.cmp(b),
+        (Scalar::Str(a), Literal::Str(b)) | (Scalar::Str(a), Literal::Name(b)) => a.cmp(b),
+        (Scalar::Level(a), Literal::Level(b)) => a.cmp(b),
+        (Scalar::Bool(a), Literal::Bool(b)) => return equality(a == b),
+        (Scalar::Variant(a), Literal::Name(b)) | (Scalar::Variant(a), Literal::Str(b)) => {
+            return equality(a == b);
+        }
+        _ => return false,
+    };
+    op.holds(ordering)
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Parsing
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Or,
+    And,
+    Not,
+    Open,
+    Close,
+    Op(Op),
+    /// A negative integer. Others are words, as they may be paths too.
+    Int(i128),
+    Str(String),
+    /// A path, a name or an integer.
+    Word(String),
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, Error> {
+    let mut tokens = vec![];
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((offset, c)) = chars.next() {
+        let next = chars.peek().map(|(_, c)| *c);
+        let mut pair = |token| {
+            chars.next();
+            token
+        };
+
+        let token = match (c, next) {
+            (c, _) if c.is_whitespace() => continue,
+            ('|', Some('|')) => pair(Token::Or),
+            ('&', Some('&')) => pair(Token::And),
+            ('=', Some('=')) => pair(Token::Op(Op::Eq)),
+            ('!', Some('=')) => pair(Token::Op(Op::Ne)),
+            ('!', Some('~')) => pair(Token::Op(Op::NotMatch)),
+            ('<', Some('=')) => pair(Token::Op(Op::Le)),
+            ('>', Some('=')) => pair(Token::Op(Op::Ge)),
+            ('!', _) => Token::Not,
+            ('(', _) => Token::Open,
+            (')', _) => Token::Close,
+            ('<', _) => Token::Op(Op::Lt),
+            ('>', _) => Token::Op(Op::Gt),
+            ('~', _) => Token::Op(Op::Match),
+            ('"', _) => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => string.push(c),
+                            None => return Err(Error::Syntax(text.len(), "`\"`")),
+                        },
+                        Some((_, c)) => string.push(c),
+                        None => return Err(Error::Syntax(text.len(), "`\"`")),
+                    }
+                }
+                Token::Str(string)
+            }
+            ('-', Some(next)) if next.is_ascii_digit() => {
+                let mut end = offset + 1;
+                while let Some((idx, c)) = chars.peek() {
+                    if !c.is_ascii_digit() {
+                        break;
+                    }
+                    end = idx + 1;
+                    chars.next();
+                }
+                match text[offset..end].parse() {
+                    Ok(val) => Token::Int(val),
+                    Err(_) => return Err(Error::Syntax(offset, "a smaller integer")),
+                }
+            }
+            (c, _) if c.is_alphanumeric() || c == '_' || c == '.' => {
+                let mut end = offset + c.len_utf8();
+                while let Some((idx, c)) = chars.peek() {
+                    if !(c.is_alphanumeric() || *c == '_' || *c == '.') {
+                        break;
+                    }
+                    end = idx + c.len_utf8();
+                    chars.next();
+                }
+                Token::Word(text[offset..end].to_owned())
+            }
+            _ => return Err(Error::Syntax(offset, "a path, literal or operator")),
+        };
+        tokens.push((offset, token));
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// The length of the expression, for errors at its end.
+    end: usize,
+    paths: Vec<Path>,
+    /// The nesting of `!` and parentheses at the current token.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map(|(offset, _)| *offset).unwrap_or(self.end)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expr(&mut self) -> Result<Expr, Error> {
+        let mut exprs = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            exprs.push(self.and()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { Expr::Or(exprs) })
+    }
+
+    fn and(&mut self) -> Result<Expr, Error> {
+        let mut exprs = vec![self.unary()?];
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            exprs.push(self.unary()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { Expr::And(exprs) })
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        let offset = self.offset();
+        match self.next() {
+            Some(Token::Not) => {
+                let expr = self.nested(offset, Self::unary)?;
+                Ok(Expr::Not(Box::new(expr)))
+            }
+            Some(Token::Open) => {
+                let expr = self.nested(offset, Self::expr)?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err(Error::Syntax(self.offset(), "`)`"));
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some(Token::Word(word)) => {
+                let path: Path = word.parse().map_err(|_| Error::Syntax(offset, "a path"))?;
+                let is_level = path == Path::Builtin(Builtin::Level);
+                let slot = self.slot(path);
+
+                let op = match self.peek() {
+                    Some(Token::Op(op)) => *op,
+                    _ => return Ok(Expr::Test(slot)),
+                };
+                self.pos += 1;
+                Ok(Expr::Compare(slot, op, self.literal(op, is_level)?))
+            }
+            _ => Err(Error::Syntax(offset, "a path, `!` or `(`")),
+        }
+    }
+
+    fn nested<F>(&mut self, offset: usize, parse: F) -> Result<Expr, Error>
+        where F: FnOnce(&mut Self) -> Result<Expr, Error>
+    {
+        if self.depth == MAX_DEPTH {
+            return Err(Error::Syntax(offset, "less nesting"));
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn slot(&mut self, path: Path) -> usize {
+        match self.paths.iter().position(|p| p == &path) {
+            Some(slot) => slot,
+            None => {
+                self.paths.push(path);
+                self.paths.len() - 1
+            }
+        }
+    }
+
+    fn literal(&mut self, op: Op, is_level: bool) -> Result<Literal, Error> {
+        let offset = self.offset();
+        let token = self.next();
+
+        if op == Op::Match || op == Op::NotMatch {
+            return match token {
+                Some(Token::Str(pattern)) => {
+                    Regex::new(&pattern).map(Literal::Regex).map_err(|err| Error::Regex(offset, err))
+                }
+                _ => Err(Error::Syntax(offset, "a regular expression string")),
+            };
+        }
+
+        match token {
+            Some(Token::Int(val)) => Ok(Literal::Int(val)),
+            Some(Token::Word(word)) if word.starts_with(|c: char| c.is_ascii_digit()) => {
+                match word.parse() {
+                    Ok(val) => Ok(Literal::Int(val)),
+                    Err(_) => Err(Error::Syntax(offset, "an integer")),
+                }
+            }
+            Some(Token::Str(val)) => Ok(Literal::Str(val)),
+            Some(Token::Word(word)) if is_level => match word.parse() {
+                Ok(level) => Ok(Literal::Level(level)),
+                Err(()) => Err(Error::UnknownLevel(offset, word)),
+            },
+            Some(Token::Word(word)) => Ok(match word.as_str() {
+                "true" => Literal::Bool(true),
+                "false" => Literal::Bool(false),
+                _ => Literal::Name(word),
+            }),
+            _ => Err(Error::Syntax(offset, "a literal")),
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use super::Description;
+use super::Named;
+use super::Struct;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Structural fingerprints of type descriptions
+//
+// The fingerprint depends only on the shape of a description: type, field
+// and variant names, field order and primitive kinds. It does not depend on
+// the order in which types were registered, so two processes describing the
+// same types arrive at the same values.
+//
+// Recursive references are hashed by their distance on the definition stack
+// rather than by their ids, so the counters assigned to them don't leak into
+// the result.
+
+pub trait TypeRef: Clone + Eq + Hash {
+    fn type_name(&self) -> &str;
+    fn type_id(&self) -> u64;
+}
+
+impl TypeRef for (&'static str, u64) {
+    fn type_name(&self) -> &str {
+        self.0
+    }
+
+    fn type_id(&self) -> u64 {
+        self.1
+    }
+}
+
+impl TypeRef for (String, u64) {
+    fn type_name(&self) -> &str {
+        self.0.as_str()
+    }
+
+    fn type_id(&self) -> u64 {
+        self.1
+    }
+}
+
+/// FNV-1a, chosen because it is trivial and its output is stable across
+/// platforms, processes and compiler versions.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn byte(&mut self, b: u8) {
+        self.0 ^= b as u64;
+        self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    fn u64(&mut self, v: u64) {
+        for b in v.to_le_bytes().iter() {
+            self.byte(*b);
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        self.u64(s.len() as u64);
+        for b in s.as_bytes() {
+            self.byte(*b);
+        }
+    }
+}
+
+pub struct Fingerprinter<'a, T, S> {
+    lookup: &'a dyn Fn(&T) -> Option<&'a Named<T, S>>,
+    stack: Vec<T>,
+    memo: HashMap<T, u64>,
+}
+
+impl<'a, T, S> Fingerprinter<'a, T, S>
+    where T: TypeRef, S: AsRef<str>
+{
+    /// `lookup` resolves `ByName` references that carry no inline definition.
+    /// Definitions that appear inline are picked up during the traversal.
+    pub fn new(lookup: &'a dyn Fn(&T) -> Option<&'a Named<T, S>>) -> Self {
+        Self {
+            lookup,
+            stack: vec![],
+            memo: HashMap::new(),
+        }
+    }
+
+    pub fn desc(&mut self, desc: &Description<T, S>) -> u64 {
+        let mut h = Fnv::new();
+        self.hash_desc(&mut h, desc);
+        h.0
+    }
+
+    pub fn named(&mut self, id: &T, named: &Named<T, S>) -> u64 {
+        if let Some(v) = self.memo.get(id) {
+            return *v;
+        }
+
+        let mut h = Fnv::new();
+        h.byte(b'N');
+        h.str(id.type_name());
+        self.stack.push(id.clone());
+        self.hash_named(&mut h, named);
+        self.stack.pop();
+
+        self.memo.insert(id.clone(), h.0);
+        h.0
+    }
+
+    fn hash_by_name(&mut self, h: &mut Fnv, id: &T, named: Option<&Named<T, S>>) {
+        if let Some(pos) = self.stack.iter().rposition(|x| x == id) {
+            h.byte(b'B');
+            h.u64((self.stack.len() - pos) as u64);
+            return;
+        }
+
+        let v = match named {
+            Some(named) => Some(self.named(id, named)),
+            None => match self.memo.get(id) {
+                Some(v) => Some(*v),
+                None => (self.lookup)(id).map(|named| self.named(id, named)),
+            },
+        };
+
+        match v {
+            Some(v) => {
+                h.byte(b'R');
+                h.u64(v);
+            }
+            None => {
+                h.byte(b'?');
+                h.str(id.type_name());
+            }
+        }
+    }
+
+    fn hash_desc(&mut self, h: &mut Fnv, desc: &Description<T, S>) {
+        use Description::*;
+
+        match desc {
+            U64 => h.byte(0),
+            U32 => h.byte(1),
+            U16 => h.byte(2),
+            U8 => h.byte(3),
+            I64 => h.byte(4),
+            I32 => h.byte(5),
+            I16 => h.byte(6),
+            I8 => h.byte(7),
+            Unit => h.byte(8),
+            PhantomData => h.byte(9),
+            Bool => h.byte(10),
+            String => h.byte(11),
+            RawPtr => h.byte(12),
+            Option(sub) => {
+                h.byte(13);
+                self.hash_desc(h, sub);
+            }
+            Result(ok, err) => {
+                h.byte(14);
+                self.hash_desc(h, ok);
+                self.hash_desc(h, err);
+            }
+            Array(size, sub) => {
+                h.byte(15);
+                h.u64(*size as u64);
+                self.hash_desc(h, sub);
+            }
+            Slice(sub) => {
+                h.byte(16);
+                self.hash_desc(h, sub);
+            }
+            Tuple(subs) => {
+                h.byte(17);
+                h.u64(subs.len() as u64);
+                for sub in subs {
+                    self.hash_desc(h, sub);
+                }
+            }
+            ByName(id, named) => {
+                h.byte(18);
+                self.hash_by_name(h, id, named.as_ref());
+            }
+        }
+    }
+
+    fn hash_named(&mut self, h: &mut Fnv, named: &Named<T, S>) {
+        match named {
+            Named::Enum(variants) => {
+                h.byte(0);
+                h.u64(variants.len() as u64);
+                for (name, struct_) in variants {
+                    h.str(name.as_ref());
+                    self.hash_struct(h, struct_);
+                }
+            }
+            Named::Struct(struct_) => {
+                h.byte(1);
+                self.hash_struct(h, struct_);
+            }
+        }
+    }
+
+    fn hash_struct(&mut self, h: &mut Fnv, struct_: &Struct<T, S>) {
+        match struct_ {
+            Struct::Unit => h.byte(0),
+            Struct::Tuple(fields) => {
+                h.byte(1);
+                h.u64(fields.len() as u64);
+                for field in fields {
+                    self.hash_desc(h, field);
+                }
+            }
+            Struct::Named(fields) => {
+                h.byte(2);
+                h.u64(fields.len() as u64);
+                for (name, field) in fields {
+                    h.str(name.as_ref());
+                    self.hash_desc(h, field);
+                }
+            }
+        }
+    }
+}
+
+/// Fingerprint of a description where every named type is defined inline on
+/// its first appearance, as produced by `Logpack::logpack_describe` on a
+/// fresh `SeenTypes`.
+pub fn fingerprint<T, S>(desc: &Description<T, S>) -> u64
+    where T: TypeRef, S: AsRef<str>
+{
+    let lookup = |_: &T| None;
+    Fingerprinter::new(&lookup).desc(desc)
+}
@@ -0,0 +1,500 @@
+use super::envelope;
+use super::stream::{self, RecordSink, RecordSource, RECORD_ENTRY};
+
+use serde_derive::{Serialize, Deserialize};
+use std::io::{self, BufRead, Seek, SeekFrom, Write};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Checksummed record framing
+//
+//     frame := u8(kind) u32(len) u32(type) u32(crc) payload
+//
+// Integers are little-endian. `type` is the type index of the record, or 0
+// for records that don't have one, and `crc` is the CRC32C of the other
+// header fields followed by the payload.
+//
+// Every `sync_interval` bytes the writer emits a sync frame, whose bytes are
+// always the same. A reader that finds a frame with a bad length or checksum
+// reports it and scans ahead for the next sync frame, so that a corrupted
+// byte costs at most the records up to there.
+//
+// Sync frames are also where a reader may start, so the writer keeps a
+// `SeekIndex` of them, giving the number of frames before each one and the
+// latest time of the log entries before it.
+
+pub const RECORD_SYNC: u8 = 0;
+
+pub const SYNC_MARKER: [u8; 16] = [
+    0xa5, 0x4c, 0x50, 0x4b, 0x53, 0x59, 0x4e, 0x43,
+    0x0f, 0x1e, 0x2d, 0x3c, 0x4b, 0x5a, 0x69, 0x78,
+];
+
+pub const HEADER_LEN: usize = 13;
+pub const SYNC_FRAME_LEN: usize = HEADER_LEN + SYNC_MARKER.len();
+
+pub const DEFAULT_SYNC_INTERVAL: u64 = 64 * 1024;
+
+/// Frames longer than this are taken for corruption.
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+
+fn header(kind: u8, type_index: u32, payload: &[u8]) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[0] = kind;
+    header[1..5].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    header[5..9].copy_from_slice(&type_index.to_le_bytes());
+    let crc = crc32c::crc32c_append(crc32c::crc32c(&header[..9]), payload);
+    header[9..].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+fn sync_frame() -> [u8; SYNC_FRAME_LEN] {
+    let mut frame = [0u8; SYNC_FRAME_LEN];
+    frame[..HEADER_LEN].copy_from_slice(&header(RECORD_SYNC, 0, &SYNC_MARKER));
+    frame[HEADER_LEN..].copy_from_slice(&SYNC_MARKER);
+    frame
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut v = [0u8; 4];
+    v.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(v)
+}
+
+/// The offset of a sync frame, and how many frames came before it.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SyncPoint {
+    pub offset: u64,
+    pub frames: u64,
+}
+
+/// A sync frame to start reading from.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SeekPoint {
+    pub offset: u64,
+    /// The number of frames before it, i.e. the sequence number of the
+    /// frame after it.
+    pub seq: u64,
+    /// The latest time of the log entries before it, if there were any.
+    pub time: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct SeekIndex {
+    /// Offsets of the definition records, which a reader starting at a
+    /// seek point needs first.
+    pub definitions: Vec<u64>,
+    pub points: Vec<SeekPoint>,
+}
+
+impl SeekIndex {
+    /// The last point before which all entries are older than `time`, or
+    /// `None` if there is none and reading has to start from the beginning.
+    pub fn point_for_time(&self, time: u64) -> Option<&SeekPoint> {
+        let idx = self.points.partition_point(|p| p.time.is_none_or(|t| t < time));
+        idx.checked_sub(1).map(|idx| &self.points[idx])
+    }
+
+    /// The last point at or before the frame with sequence number `seq`.
+    pub fn point_for_seq(&self, seq: u64) -> Option<&SeekPoint> {
+        let idx = self.points.partition_point(|p| p.seq <= seq);
+        idx.checked_sub(1).map(|idx| &self.points[idx])
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Writer
+
+pub struct FrameWriter<W: Write> {
+    out: W,
+    position: u64,
+    last_sync: u64,
+    frames: u64,
+    sync_interval: u64,
+    sync_points: Vec<SyncPoint>,
+    seek_index: SeekIndex,
+    latest: Option<u64>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// `position` is the offset in the file at which the first frame goes.
+    pub fn new(out: W, position: u64) -> Self {
+        Self {
+            out,
+            position,
+            last_sync: position,
+            frames: 0,
+            sync_interval: DEFAULT_SYNC_INTERVAL,
+            sync_points: vec![],
+            seek_index: SeekIndex::default(),
+            latest: None,
+        }
+    }
+
+    /// Continues a file whose frames were read back, e.g. to write its
+    /// index anew.
+    pub(crate) fn resume(out: W, position: u64, frames: u64, sync_points: Vec<SyncPoint>,
+                         seek_index: SeekIndex) -> Self
+    {
+        Self { frames, sync_points, seek_index, ..Self::new(out, position) }
+    }
+
+    pub fn with_sync_interval(self, sync_interval: u64) -> Self {
+        Self { sync_interval, ..self }
+    }
+
+    fn frame(&mut self, kind: u8, type_index: u32, payload: &[u8]) -> io::Result<()> {
+        let header = header(kind, type_index, payload);
+        self.out.write_all(&header)?;
+        self.out.write_all(payload)?;
+        self.position += (header.len() + payload.len()) as u64;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.sync_points.push(SyncPoint { offset: self.position, frames: self.frames });
+        self.seek_index.points.push(SeekPoint {
+            offset: self.position,
+            seq: self.frames,
+            time: self.latest,
+        });
+        self.out.write_all(&sync_frame())?;
+        self.position += SYNC_FRAME_LEN as u64;
+        self.last_sync = self.position;
+        Ok(())
+    }
+
+    /// Writes a frame without counting it or placing a sync frame before it,
+    /// as done for trailing metadata.
+    pub fn put_raw(&mut self, kind: u8, type_index: u32, payload: &[u8]) -> io::Result<()> {
+        self.frame(kind, type_index, payload)
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn sync_points(&self) -> &[SyncPoint] {
+        &self.sync_points
+    }
+
+    pub fn seek_index(&self) -> &SeekIndex {
+        &self.seek_index
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> RecordSink for FrameWriter<W> {
+    fn put_record(&mut self, kind: u8, type_index: u32, payload: &[u8]) -> io::Result<()> {
+        if self.position - self.last_sync >= self.sync_interval {
+            self.sync()?;
+        }
+        if stream::is_definition(kind) {
+            self.seek_index.definitions.push(self.position);
+        } else if kind == RECORD_ENTRY {
+            if let Some(time) = envelope::peek_time(payload) {
+                self.entry_time(time);
+            }
+        }
+        self.frame(kind, type_index, payload)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn entry_time(&mut self, time: u64) {
+        self.latest = Some(self.latest.map_or(time, |latest| latest.max(time)));
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn offset(&self) -> Option<u64> {
+        Some(self.position)
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Reader
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Problem {
+    /// A length above `MAX_FRAME_LEN`, or one that runs past the end of the
+    /// input while a sync frame follows. Otherwise a frame cut short by the
+    /// end of the input is taken for a truncated file.
+    BadLength(u32),
+    /// Also how a bad length shows up when the frame it gives runs into
+    /// later ones but not past the end of the input.
+    BadChecksum,
+}
+
+/// A frame that was dropped, and how many bytes were skipped from its start
+/// up to the next good frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BadFrame {
+    pub offset: u64,
+    pub problem: Problem,
+    pub skipped: u64,
+}
+
+pub struct FrameReader<R: BufRead> {
+    input: R,
+    /// Bytes given back after a bad frame, read again before `input`.
+    pending: Vec<u8>,
+    pending_pos: usize,
+    position: u64,
+    truncated: bool,
+    bad_frames: Vec<BadFrame>,
+    frames: u64,
+    /// The sync frames passed, if collected.
+    sync_points: Option<Vec<SyncPoint>>,
+}
+
+impl<R: BufRead> FrameReader<R> {
+    pub fn new(input: R, position: u64) -> Self {
+        Self {
+            input,
+            pending: vec![],
+            pending_pos: 0,
+            position,
+            truncated: false,
+            bad_frames: vec![],
+            frames: 0,
+            sync_points: None,
+        }
+    }
+
+    /// Collects the sync frames passed, e.g. to rebuild an index.
+    pub fn with_sync_points(self) -> Self {
+        Self { sync_points: Some(vec![]), ..self }
+    }
+
+    /// Reads as much of `buf` as there is, returning how much that was.
+    fn read_up_to(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+
+        let pending = &self.pending[self.pending_pos..];
+        if !pending.is_empty() {
+            let n = pending.len().min(buf.len());
+            buf[..n].copy_from_slice(&pending[..n]);
+            self.pending_pos += n;
+            read = n;
+        }
+
+        while read < buf.len() {
+            match self.input.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.position += read as u64;
+        Ok(read)
+    }
+
+    fn unread(&mut self, bytes: &[u8]) {
+        let mut pending = bytes.to_vec();
+        pending.extend_from_slice(&self.pending[self.pending_pos..]);
+        self.pending = pending;
+        self.pending_pos = 0;
+        self.position -= bytes.len() as u64;
+    }
+
+    /// Skips ahead to just past the next sync frame. Returns `false` if the
+    /// input ended first.
+    fn resync(&mut self) -> io::Result<bool> {
+        let sync_frame = sync_frame();
+        let mut window = Vec::with_capacity(SYNC_FRAME_LEN + 1);
+        let mut byte = [0u8; 1];
+
+        loop {
+            // Go byte by byte through what was given back, then in chunks
+            // through the input's buffer.
+            if self.pending_pos < self.pending.len() {
+                self.read_up_to(&mut byte)?;
+                window.push(byte[0]);
+            } else {
+                let (consumed, found) = {
+                    let buf = self.input.fill_buf()?;
+                    if buf.is_empty() {
+                        return Ok(false);
+                    }
+                    let mut consumed = 0;
+                    let mut found = false;
+                    for b in buf {
+                        consumed += 1;
+                        window.push(*b);
+                        if window.len() > SYNC_FRAME_LEN {
+                            window.remove(0);
+                        }
+                        if window[..] == sync_frame[..] {
+                            found = true;
+                            break;
+                        }
+                    }
+                    (consumed, found)
+                };
+                self.input.consume(consumed);
+                self.position += consumed as u64;
+                if found {
+                    return Ok(true);
+                }
+                continue;
+            }
+
+            if window.len() > SYNC_FRAME_LEN {
+                window.remove(0);
+            }
+            if window[..] == sync_frame[..] {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Records a bad frame starting at `offset` and skips to the next sync
+    /// frame. Returns `false` if the input ended first.
+    fn bad_frame(&mut self, offset: u64, problem: Problem) -> io::Result<bool> {
+        let found = self.resync()?;
+        let end = if found { self.position - SYNC_FRAME_LEN as u64 } else { self.position };
+        self.bad_frames.push(BadFrame { offset, problem, skipped: end - offset });
+        if found {
+            self.passed_sync(end);
+        }
+        Ok(found)
+    }
+
+    fn passed_sync(&mut self, offset: u64) {
+        if let Some(sync_points) = &mut self.sync_points {
+            sync_points.push(SyncPoint { offset, frames: self.frames });
+        }
+    }
+
+    /// Whether the input ended in the middle of a frame.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// The frames dropped since the last call.
+    pub fn take_bad_frames(&mut self) -> Vec<BadFrame> {
+        std::mem::take(&mut self.bad_frames)
+    }
+
+    pub fn bad_frames(&self) -> &[BadFrame] {
+        &self.bad_frames
+    }
+
+    /// Offset of the next frame.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The number of frames read, counting from the start or the frame
+    /// given to `seek`.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn sync_points(&self) -> &[SyncPoint] {
+        self.sync_points.as_deref().unwrap_or(&[])
+    }
+
+    /// Gives access to the input after the last frame, e.g. to read a
+    /// trailer.
+    pub fn read_rest(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_up_to(buf)
+    }
+}
+
+impl<R: BufRead + Seek> FrameReader<R> {
+    /// Continues reading at `offset`, which is the start of a frame with
+    /// sequence number `seq`.
+    pub fn seek(&mut self, offset: u64, seq: u64) -> io::Result<()> {
+        self.input.seek(SeekFrom::Start(offset))?;
+        self.pending.clear();
+        self.pending_pos = 0;
+        self.position = offset;
+        self.truncated = false;
+        self.frames = seq;
+        Ok(())
+    }
+
+    /// Gives access to the input, which has to be sought back with `seek`
+    /// before reading more frames.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.input
+    }
+}
+
+impl<R: BufRead> RecordSource for FrameReader<R> {
+    fn next_record(&mut self, payload: &mut Vec<u8>) -> io::Result<Option<(u8, u32)>> {
+        loop {
+            let offset = self.position;
+            let mut header = [0u8; HEADER_LEN];
+            let read = self.read_up_to(&mut header)?;
+            if read < header.len() {
+                self.truncated = read > 0;
+                return Ok(None);
+            }
+
+            let kind = header[0];
+            let len = u32_at(&header, 1);
+            let type_index = u32_at(&header, 5);
+            let crc = u32_at(&header, 9);
+
+            if len as usize > MAX_FRAME_LEN {
+                self.unread(&header[1..]);
+                if !self.bad_frame(offset, Problem::BadLength(len))? {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            payload.resize(len as usize, 0);
+            let read = self.read_up_to(payload)?;
+            if read < payload.len() {
+                // Either a crash cut the last frame short, or the length is
+                // bad and the frame runs into the ones after it.
+                let partial = payload[..read].to_vec();
+                self.unread(&partial);
+                if !self.bad_frame(offset, Problem::BadLength(len))? {
+                    self.bad_frames.pop();
+                    self.truncated = true;
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            let expected = crc32c::crc32c_append(crc32c::crc32c(&header[..9]), payload);
+            if crc != expected {
+                let mut rest = header[1..].to_vec();
+                rest.extend_from_slice(payload);
+                self.unread(&rest);
+                if !self.bad_frame(offset, Problem::BadChecksum)? {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            if kind == RECORD_SYNC {
+                self.passed_sync(offset);
+                continue;
+            }
+
+            self.frames += 1;
+            return Ok(Some((kind, type_index)));
+        }
+    }
+}
@@ -3,6 +3,29 @@ use serde_derive::{Serialize, Deserialize};
 pub mod decoder;
 pub mod encoder;
 pub mod buffers;
+pub mod fingerprint;
+pub mod diff;
+pub mod validate;
+pub mod resolve;
+pub mod compat;
+#[cfg(feature = "registry")]
+pub mod registry;
+pub mod compact;
+pub mod stream;
+pub mod framing;
+pub mod file;
+pub mod envelope;
+pub mod callsite;
+pub mod ring;
+#[cfg(feature = "mmap")]
+pub mod mmap_ring;
+pub mod rotate;
+pub mod block;
+pub mod merge;
+pub mod path;
+#[cfg(feature = "filter")]
+pub mod filter;
+pub mod aggregate;
 
 pub use encoder::Encoder;
 pub use decoder::Decoder;
@@ -10,10 +33,12 @@ pub use decoder::NameMap;
 pub use buffers::BufEncoder;
 pub use buffers::BufDecoder;
 pub use decoder::ResolvedDesc;
+pub use envelope::Level;
 
-use std::collections::HashMap;
-use std::marker::PhantomData;
 use std::any::TypeId;
+use std::collections::{BTreeSet, HashMap};
+use std::marker::PhantomData;
+use std::sync::Mutex;
 
 //////////////////////////////////////////////////////////////////////////
 //
@@ -58,17 +83,85 @@ pub enum Description<T, S=String> {
     ByName(T, Option<Named<T, S>>),
 }
 
+impl<T, S: AsRef<str>> Description<T, S> {
+    /// Converts to a description that owns its names, e.g. from the
+    /// `RefDesc` returned by `logpack_describe` to a `ResolvedDesc`.
+    pub fn to_owned_names<U>(&self, id: &dyn Fn(&T) -> U) -> Description<U> {
+        use Description::*;
+
+        match self {
+            U64 => U64,
+            U32 => U32,
+            U16 => U16,
+            U8 => U8,
+            I64 => I64,
+            I32 => I32,
+            I16 => I16,
+            I8 => I8,
+            Unit => Unit,
+            PhantomData => PhantomData,
+            Bool => Bool,
+            String => String,
+            RawPtr => RawPtr,
+            Option(sub) => Option(Box::new(sub.to_owned_names(id))),
+            Result(ok, err) => Result(Box::new(ok.to_owned_names(id)), Box::new(err.to_owned_names(id))),
+            Array(size, sub) => Array(*size, Box::new(sub.to_owned_names(id))),
+            Slice(sub) => Slice(Box::new(sub.to_owned_names(id))),
+            Tuple(subs) => Tuple(subs.iter().map(|x| x.to_owned_names(id)).collect()),
+            ByName(name, named) => ByName(id(name), named.as_ref().map(|x| x.to_owned_names(id))),
+        }
+    }
+}
+
+impl<T, S: AsRef<str>> Named<T, S> {
+    pub fn to_owned_names<U>(&self, id: &dyn Fn(&T) -> U) -> Named<U> {
+        match self {
+            Named::Enum(variants) => Named::Enum(variants.iter().map(|(name, x)| {
+                (name.as_ref().to_owned(), x.to_owned_names(id))
+            }).collect()),
+            Named::Struct(x) => Named::Struct(x.to_owned_names(id)),
+        }
+    }
+}
+
+impl<T, S: AsRef<str>> Struct<T, S> {
+    pub fn to_owned_names<U>(&self, id: &dyn Fn(&T) -> U) -> Struct<U> {
+        match self {
+            Struct::Unit => Struct::Unit,
+            Struct::Tuple(fields) => Struct::Tuple(fields.iter().map(|x| x.to_owned_names(id)).collect()),
+            Struct::Named(fields) => Struct::Named(fields.iter().map(|(name, x)| {
+                (name.as_ref().to_owned(), x.to_owned_names(id))
+            }).collect()),
+        }
+    }
+}
+
 //////////////////////////////////////////////////////////////////////////
 //
 // SeenTypes
 
 pub type TypeName = &'static str;
 pub type FieldName = &'static str;
-pub type TypeNameId = (TypeName, u16);
-
+pub type TypeNameId = (TypeName, u64);
+
+/// Tracks the types that were already described, so that each named type
+/// is emitted in full only on its first appearance.
+///
+/// Types are keyed by their `TypeId`. Types that may not be `'static`, such
+/// as `Request<'a>` or generic ones, are keyed by `std::any::type_name`
+/// instead, with lifetimes erased. Type names aren't guaranteed to be
+/// unique, e.g. for two versions of a crate, and two such types with the same
+/// name are taken for one: the second gets the `TypeNameId` of the first and
+/// is never described.
+///
+/// By default the second half of a `TypeNameId` is a counter that tells
+/// apart types sharing a name, in the order they were seen. With
+/// `with_content_ids` it is a structural fingerprint of the type's
+/// description instead, which is the same in every process that logs it.
 pub struct SeenTypes {
-    by_ids: HashMap<TypeId, (TypeName, u16)>,
-    names: HashMap<TypeName, u16>,
+    by_ids: HashMap<SeenKey, TypeNameId>,
+    names: HashMap<TypeName, u64>,
+    content_ids: bool,
 }
 
 impl SeenTypes {
@@ -76,34 +169,137 @@ impl SeenTypes {
         Self {
             by_ids: HashMap::new(),
             names: HashMap::new(),
+            content_ids: false,
         }
     }
 
-    pub fn make_name_for_id(&mut self, name: &'static str, type_id: TypeId) -> (bool, TypeNameId) {
-        if let Some(value) = self.by_ids.get(&type_id) {
+    pub fn with_content_ids() -> Self {
+        Self {
+            content_ids: true,
+            ..Self::new()
+        }
+    }
+
+    pub fn has_content_ids(&self) -> bool {
+        self.content_ids
+    }
+
+    /// Names the type after its fully instantiated form, e.g. `GenericType<u32>`,
+    /// so that different instantiations of a generic type are told apart.
+    pub fn make_name_for<T: Logpack + ?Sized + 'static>(&mut self) -> (bool, TypeNameId) {
+        let name = short_type_name(std::any::type_name::<T>());
+        self.make_name(name, SeenKey::Id(TypeId::of::<T>()), &|seen| T::logpack_describe(seen))
+    }
+
+    /// Like `make_name_for`, for types that may not be `'static`. These are
+    /// keyed by their `std::any::type_name`, with the limitation described
+    /// above.
+    pub fn make_name_for_borrowed<T: Logpack + ?Sized>(&mut self) -> (bool, TypeNameId) {
+        let type_key = std::any::type_name::<T>();
+        self.make_name(short_type_name(type_key), SeenKey::Name(type_key), &|seen| T::logpack_describe(seen))
+    }
+
+    /// Names a type by an explicit name and key, e.g. for types made up at
+    /// runtime. With content ids, `describe` gives the full description of
+    /// the type to fingerprint.
+    pub fn make_name_for_id(&mut self, name: &'static str, type_key: &'static str,
+                            describe: &dyn Fn(&mut SeenTypes) -> RefDesc) -> (bool, TypeNameId) {
+        self.make_name(name, SeenKey::Name(type_key), describe)
+    }
+
+    fn make_name(&mut self, name: &'static str, type_key: SeenKey,
+                 describe: &dyn Fn(&mut SeenTypes) -> RefDesc) -> (bool, TypeNameId) {
+        if let Some(value) = self.by_ids.get(&type_key) {
             return (false, *value);
         }
 
+        if self.content_ids {
+            // Describe the type from scratch, so that the fingerprint covers
+            // its full definition regardless of what we have seen so far.
+            let desc = describe(&mut SeenTypes::new());
+            let v = (name, fingerprint::fingerprint(&desc));
+            self.by_ids.insert(type_key, v);
+            return (true, v);
+        }
+
         if let Some(value) = self.names.get_mut(name) {
             *value += 1;
             let v = (name, *value);
-            self.by_ids.insert(type_id, v);
+            self.by_ids.insert(type_key, v);
             return (true, v);
         }
 
         let v = (name, 0);
         self.names.insert(name, 0);
-        self.by_ids.insert(type_id, v);
+        self.by_ids.insert(type_key, v);
         (true, v)
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum SeenKey {
+    Id(TypeId),
+    Name(&'static str),
+}
+
+/// Strips module paths from a `std::any::type_name` string, leaving the
+/// generic arguments in place, e.g. `app::GenericType<alloc::string::String>`
+/// becomes `GenericType<String>`. Erased lifetime arguments are dropped too.
+///
+/// The result is interned, so every distinct name is allocated only once per
+/// process.
+pub fn short_type_name(full: &'static str) -> &'static str {
+    if !full.contains("::") && !full.contains("'_") {
+        return full;
+    }
+
+    let mut short = String::with_capacity(full.len());
+    let mut segment_start = 0;
+    let mut chars = full.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&':') {
+            chars.next();
+            short.truncate(segment_start);
+            continue;
+        }
+
+        short.push(c);
+        if !(c.is_alphanumeric() || c == '_') {
+            segment_start = short.len();
+        }
+    }
+
+    let short = short.replace("'_, ", "").replace("<'_>", "");
+    intern(short)
+}
+
+/// Returns a `'static` copy of the string, allocated once per distinct value.
+pub(crate) fn intern(s: String) -> &'static str {
+    static INTERNED: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+
+    let mut interned = INTERNED.lock().unwrap();
+    if let Some(name) = interned.get(s.as_str()) {
+        return name;
+    }
+
+    let name: &'static str = Box::leak(s.into_boxed_str());
+    interned.insert(name);
+    name
+}
+
 //////////////////////////////////////////////////////////////////////////
 //
 // Logpack and impl
 
 pub type RefDesc = Description<TypeNameId, FieldName>;
 
+impl Description<TypeNameId, FieldName> {
+    pub fn to_resolved(&self) -> ResolvedDesc {
+        self.to_owned_names(&|(name, id)| (name.to_string(), *id))
+    }
+}
+
 pub trait Logpack {
     fn logpack_describe(seen: &mut SeenTypes) -> RefDesc;
     fn logpack_describe_by_value(&self, seen: &mut SeenTypes) -> RefDesc {
@@ -188,7 +384,7 @@ array_impls!(01 02 03 04 05 06 07 08 09 10
 
 macro_rules! tuple {
     ($($type:ident),*) => {
-        impl<$($type),*> Logpack for ($($type),*)
+        impl<$($type),*> Logpack for ($($type,)*)
             where $($type : Logpack),*
         {
             fn logpack_describe(seen: &mut SeenTypes) -> RefDesc {
@@ -199,6 +395,7 @@ macro_rules! tuple {
     }
 }
 
+tuple!(A);
 tuple!(A, B);
 tuple!(A, B, C);
 tuple!(A, B, C, D);
@@ -274,8 +471,7 @@ macro_rules! std_type_to_tuple {
         impl Logpack for $name
         {
             fn logpack_describe(seen: &mut SeenTypes) -> RefDesc {
-                let (first_seen, typename_id) = seen.make_name_for_id(stringify!($name),
-                                                                      TypeId::of::<Self>());
+                let (first_seen, typename_id) = seen.make_name_for::<Self>();
                 let may_recurse = if first_seen {
                     Some(Named::Struct(Struct::Tuple(vec![
                         $( $fields::logpack_describe(seen) ),*
@@ -295,3 +491,25 @@ use std::time::Instant;
 
 std_type_to_tuple!(Duration: u64, u32);
 std_type_to_tuple!(Instant: u64, u32);
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Logging
+
+/// Logs a message through the sink installed with `callsite::set_sink`,
+/// e.g. `log!(Level::Warn, "user {} failed: {:?}", id, err)`.
+///
+/// Only the callsite id and the encoded arguments are written. The format
+/// string supports `{}` and `{:?}`, optionally with positions, and is
+/// applied by the reader.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {{
+        static CALLSITE: $crate::callsite::Callsite =
+            $crate::callsite::Callsite::new(module_path!(), file!(), line!(), $fmt);
+        let level = $level;
+        if $crate::callsite::enabled(level) {
+            $crate::callsite::dispatch(&CALLSITE, level, &($(&$arg,)*));
+        }
+    }};
+}
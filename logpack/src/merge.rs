@@ -0,0 +1,163 @@
+use super::Description;
+use super::fingerprint::Fingerprinter;
+use super::stream::{self, RecordSource, StreamReader, Value, RECORD_ENTRY, RECORD_VALUE};
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Merging streams
+//
+// `MergeReader` reads several streams at once, e.g. the log files of the
+// processes of a service, and returns their values ordered by the time of
+// their entries. Each stream is taken to be in order already, so this is a
+// k-way merge holding one value of every stream at a time. Values that are
+// not log entries keep their place after the entry before them in their
+// stream.
+//
+// The type indices of the streams are unrelated to one another, and the
+// same type may be defined under different indices, or a type of the same
+// name may have a different definition in each. The values are therefore
+// also given a merged type id, shared by the types of all streams having
+// the same name and structural fingerprint.
+
+/// What types are told apart by when merging. Types without a name, such
+/// as tuples, go by their fingerprint alone.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct TypeKey {
+    pub name: Option<String>,
+    pub fingerprint: u64,
+}
+
+/// A value read by `MergeReader`.
+pub struct Merged<'a> {
+    /// The index of the stream it came from, as given to `MergeReader::new`.
+    pub stream: usize,
+    pub type_id: u32,
+    pub value: Value<'a>,
+}
+
+#[derive(Debug)]
+pub struct Error {
+    pub stream: usize,
+    pub error: stream::Error,
+}
+
+struct Input<R: RecordSource> {
+    reader: StreamReader<R>,
+    /// The kind and type index of the value read last.
+    head: Option<(u8, u32)>,
+    /// The time of the last entry, given to values that are not entries.
+    time: u64,
+    type_ids: HashMap<u32, u32>,
+}
+
+pub struct MergeReader<R: RecordSource> {
+    inputs: Vec<Input<R>>,
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+    types: HashMap<TypeKey, u32>,
+    keys: Vec<TypeKey>,
+    /// The streams to read the next value of before picking one, at first
+    /// all of them and then the one of the value returned last.
+    pending: Vec<usize>,
+}
+
+impl<R: RecordSource> MergeReader<R> {
+    pub fn new(readers: Vec<StreamReader<R>>) -> Self {
+        let pending = (0..readers.len()).rev().collect();
+        Self {
+            inputs: readers.into_iter().map(|reader| Input {
+                reader,
+                head: None,
+                time: 0,
+                type_ids: HashMap::new(),
+            }).collect(),
+            heap: BinaryHeap::new(),
+            types: HashMap::new(),
+            keys: vec![],
+            pending,
+        }
+    }
+
+    pub fn streams(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn stream(&self, idx: usize) -> &StreamReader<R> {
+        &self.inputs[idx].reader
+    }
+
+    /// The types met so far, indexed by merged type id.
+    pub fn type_keys(&self) -> &[TypeKey] {
+        &self.keys
+    }
+
+    /// Returns the next value of all streams by time. A stream that fails is
+    /// left out from then on, and the others can still be read.
+    pub fn next_value(&mut self) -> Result<Option<Merged<'_>>, Error> {
+        while let Some(idx) = self.pending.pop() {
+            self.advance(idx)?;
+        }
+
+        let idx = match self.heap.pop() {
+            Some(Reverse((_, idx))) => idx,
+            None => return Ok(None),
+        };
+        self.pending.push(idx);
+
+        let (kind, type_index) = self.inputs[idx].head.expect("merged stream without a value");
+        let type_id = self.type_id(idx, type_index);
+        let value = self.inputs[idx].reader.value(kind, type_index)
+            .map_err(|error| Error { stream: idx, error })?;
+
+        Ok(Some(Merged { stream: idx, type_id, value }))
+    }
+
+    fn advance(&mut self, idx: usize) -> Result<(), Error> {
+        let input = &mut self.inputs[idx];
+        input.head = None;
+
+        let value = match input.reader.next_value() {
+            Ok(Some(value)) => value,
+            Ok(None) => return Ok(()),
+            Err(error) => return Err(Error { stream: idx, error }),
+        };
+
+        let (kind, time) = match &value.envelope {
+            Some(envelope) => (RECORD_ENTRY, envelope.time),
+            None => (RECORD_VALUE, input.time),
+        };
+        input.head = Some((kind, value.type_index));
+        input.time = time;
+        self.heap.push(Reverse((time, idx)));
+        Ok(())
+    }
+
+    fn type_id(&mut self, idx: usize, type_index: u32) -> u32 {
+        if let Some(type_id) = self.inputs[idx].type_ids.get(&type_index) {
+            return *type_id;
+        }
+
+        let key = {
+            let reader = &self.inputs[idx].reader;
+            let desc = reader.type_desc(type_index).expect("value of an undefined type");
+            let names = reader.name_map().get_map();
+            let lookup = |id: &_| names.get(id);
+            let fingerprint = Fingerprinter::new(&lookup).desc(desc);
+            let name = match desc {
+                Description::ByName((name, _), _) => Some(name.clone()),
+                _ => None,
+            };
+            TypeKey { name, fingerprint }
+        };
+
+        let next = self.keys.len() as u32;
+        let type_id = *self.types.entry(key.clone()).or_insert(next);
+        if type_id == next {
+            self.keys.push(key);
+        }
+        self.inputs[idx].type_ids.insert(type_index, type_id);
+        type_id
+    }
+}
@@ -0,0 +1,403 @@
+use super::buffers::BufEncoder;
+use super::stream::{is_definition, RecordSink, RecordSource};
+
+use memmap2::MmapMut;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Crash-surviving ring buffer
+//
+// The records of a stream go into a memory-mapped file, so that the last of
+// them can be read back even if the process is killed. The file holds a
+// header, a schema area and a data area:
+//
+//     offset  size  field
+//          0     8  magic "LPMRING\0"
+//          8     4  version
+//         12     4  header length
+//         16     8  schema offset
+//         24     8  schema capacity
+//         32     8  schema used, in bytes
+//         40     8  data offset
+//         48     8  data capacity
+//         56     8  start cursor, of the oldest record
+//         64     8  write cursor, past the newest committed record
+//         72     8  wrap count
+//         80     4  pid of the writer
+//
+// All integers are little-endian. Cursors count bytes written to the data
+// area since its creation; the offset of a cursor in the area is the cursor
+// modulo the capacity.
+//
+// Both areas hold records of the same form:
+//
+//     record := u32(len) u32(type) u8(kind) u8[3] payload padding
+//
+// padded to a multiple of 4 bytes. Type, schema and callsite definitions go
+// to the schema area, which doesn't wrap, so that values stay decodable.
+// Other records go to the data area. A record doesn't wrap either: one with
+// a length of `PAD` marks the rest of the area as unused, as does being
+// less than a record header away from its end.
+//
+// A record is written past the write cursor, which is then moved over it,
+// so a record cut short by a crash is never visible. Before a record
+// overwrites the oldest ones, the start cursor is moved past them.
+
+pub const MAGIC: &[u8; 8] = b"LPMRING\0";
+pub const VERSION: u32 = 1;
+pub const HEADER_LEN: usize = 128;
+
+const RECORD_HEADER: usize = 12;
+const PAD: u32 = u32::MAX;
+
+const SCHEMA_OFFSET: usize = 16;
+const SCHEMA_CAPACITY: usize = 24;
+const SCHEMA_USED: usize = 32;
+const DATA_OFFSET: usize = 40;
+const DATA_CAPACITY: usize = 48;
+const START: usize = 56;
+const CURSOR: usize = 64;
+const WRAPS: usize = 72;
+const PID: usize = 80;
+
+fn record_len(payload: usize) -> usize {
+    (RECORD_HEADER + payload + 3) & !3
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut v = [0u8; 4];
+    v.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(v)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut v = [0u8; 8];
+    v.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(v)
+}
+
+/// The length of the record at `pos` in an area, with its header and
+/// padding, given the bytes from `pos` to the end of the area.
+fn skip_len(area: &[u8], pos: usize) -> usize {
+    let to_end = area.len() - pos;
+    if to_end < RECORD_HEADER {
+        return to_end;
+    }
+    match u32_at(area, pos) {
+        PAD => to_end,
+        len => record_len(len as usize),
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Writer
+
+pub struct MmapRing {
+    map: MmapMut,
+    schema: usize,
+    schema_capacity: usize,
+    schema_used: usize,
+    data: usize,
+    capacity: u64,
+    start: u64,
+    cursor: u64,
+    wraps: u64,
+}
+
+/// Space reserved for a record in the data area. Dropping it without
+/// calling `commit` leaves the record out.
+pub struct Reservation<'a> {
+    ring: &'a mut MmapRing,
+    pos: u64,
+    len: usize,
+}
+
+impl<'a> Reservation<'a> {
+    pub fn buf(&mut self) -> &mut [u8] {
+        let at = self.ring.data_at(self.pos) + RECORD_HEADER;
+        &mut self.ring.map[at..at + self.len]
+    }
+
+    pub fn encoder(&mut self) -> BufEncoder<'_> {
+        BufEncoder::new(self.buf())
+    }
+
+    pub fn commit(self) {
+        self.ring.set_cursor(self.pos + record_len(self.len) as u64);
+    }
+}
+
+impl MmapRing {
+    /// Creates the file with room for `capacity` bytes of records, and
+    /// `schema_capacity` bytes of definitions.
+    pub fn create(path: &Path, capacity: usize, schema_capacity: usize) -> io::Result<Self> {
+        let capacity = (capacity.max(64) + 3) & !3;
+        let schema_capacity = (schema_capacity + 3) & !3;
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len((HEADER_LEN + schema_capacity + capacity) as u64)?;
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+
+        map[..MAGIC.len()].copy_from_slice(MAGIC);
+        map[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        map[12..16].copy_from_slice(&(HEADER_LEN as u32).to_le_bytes());
+        map[PID..PID + 4].copy_from_slice(&std::process::id().to_le_bytes());
+
+        let ring = Self {
+            map,
+            schema: HEADER_LEN,
+            schema_capacity,
+            schema_used: 0,
+            data: HEADER_LEN + schema_capacity,
+            capacity: capacity as u64,
+            start: 0,
+            cursor: 0,
+            wraps: 0,
+        };
+        ring.store(SCHEMA_OFFSET, ring.schema as u64);
+        ring.store(SCHEMA_CAPACITY, schema_capacity as u64);
+        ring.store(DATA_OFFSET, ring.data as u64);
+        ring.store(DATA_CAPACITY, capacity as u64);
+        Ok(ring)
+    }
+
+    fn store(&self, offset: usize, value: u64) {
+        // The map is page-aligned, and the header fields are 8-byte aligned.
+        let field = unsafe { &*(self.map.as_ptr().add(offset) as *const AtomicU64) };
+        field.store(value.to_le(), Ordering::Release);
+    }
+
+    fn data_at(&self, pos: u64) -> usize {
+        self.data + (pos % self.capacity) as usize
+    }
+
+    fn set_cursor(&mut self, cursor: u64) {
+        self.cursor = cursor;
+        self.store(CURSOR, cursor);
+    }
+
+    /// Moves the start cursor past the records that writing up to `end`
+    /// would overwrite.
+    fn make_room(&mut self, end: u64) {
+        let area = self.data..self.data + self.capacity as usize;
+        while end - self.start > self.capacity {
+            let pos = (self.start % self.capacity) as usize;
+            self.start += skip_len(&self.map[area.clone()], pos) as u64;
+        }
+        self.store(START, self.start);
+    }
+
+    fn write_header(&mut self, at: usize, len: u32, kind: u8, type_index: u32) {
+        let header = &mut self.map[at..at + RECORD_HEADER];
+        header[..4].copy_from_slice(&len.to_le_bytes());
+        header[4..8].copy_from_slice(&type_index.to_le_bytes());
+        header[8] = kind;
+        header[9..].copy_from_slice(&[0; 3]);
+    }
+
+    /// Reserves room for a record of `len` bytes in the data area,
+    /// overwriting the oldest records as needed.
+    pub fn reserve(&mut self, kind: u8, type_index: u32, len: usize) -> io::Result<Reservation<'_>> {
+        let need = record_len(len) as u64;
+        if need > self.capacity || len >= PAD as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "record larger than the ring"));
+        }
+
+        let mut pos = self.cursor;
+        let to_end = self.capacity - pos % self.capacity;
+        if to_end < need {
+            self.make_room(pos + to_end);
+            if to_end >= RECORD_HEADER as u64 {
+                let at = self.data_at(pos);
+                self.write_header(at, PAD, 0, 0);
+            }
+            pos += to_end;
+            self.wraps += 1;
+            self.store(WRAPS, self.wraps);
+            self.set_cursor(pos);
+        }
+
+        self.make_room(pos + need);
+        let at = self.data_at(pos);
+        self.write_header(at, len as u32, kind, type_index);
+        Ok(Reservation { ring: self, pos, len })
+    }
+
+    fn put_definition(&mut self, kind: u8, type_index: u32, payload: &[u8]) -> io::Result<()> {
+        let need = record_len(payload.len());
+        if self.schema_used + need > self.schema_capacity {
+            return Err(io::Error::other("schema area of the ring is full"));
+        }
+
+        let at = self.schema + self.schema_used;
+        self.write_header(at, payload.len() as u32, kind, type_index);
+        self.map[at + RECORD_HEADER..at + RECORD_HEADER + payload.len()].copy_from_slice(payload);
+        self.schema_used += need;
+        self.store(SCHEMA_USED, self.schema_used as u64);
+        Ok(())
+    }
+
+    pub fn wraps(&self) -> u64 {
+        self.wraps
+    }
+}
+
+impl RecordSink for MmapRing {
+    fn put_record(&mut self, kind: u8, type_index: u32, payload: &[u8]) -> io::Result<()> {
+        if is_definition(kind) {
+            return self.put_definition(kind, type_index, payload);
+        }
+
+        let mut reservation = self.reserve(kind, type_index, payload.len())?;
+        reservation.buf().copy_from_slice(payload);
+        reservation.commit();
+        Ok(())
+    }
+
+    /// The records reach the file as soon as they are written, as far as
+    /// other processes are concerned. This only starts writing them to disk.
+    fn flush(&mut self) -> io::Result<()> {
+        self.map.flush_async()
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Reader
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// The header's offsets or cursors don't fit the file.
+    BadHeader,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// A copy of a ring file, reading back the definitions and then the
+/// committed records from the oldest on.
+pub struct Snapshot {
+    bytes: Vec<u8>,
+    schema: usize,
+    schema_used: usize,
+    data: usize,
+    capacity: u64,
+    start: u64,
+    cursor: u64,
+    wraps: u64,
+    pid: u32,
+    /// Where the next record is read: in the schema area while below
+    /// `schema_used`, then a data cursor.
+    next_schema: usize,
+    next: u64,
+}
+
+impl Snapshot {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Self::from_bytes(bytes)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, Error> {
+        if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let version = u32_at(&bytes, 8);
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let schema = u64_at(&bytes, SCHEMA_OFFSET) as usize;
+        let schema_capacity = u64_at(&bytes, SCHEMA_CAPACITY) as usize;
+        let schema_used = u64_at(&bytes, SCHEMA_USED) as usize;
+        let data = u64_at(&bytes, DATA_OFFSET) as usize;
+        let capacity = u64_at(&bytes, DATA_CAPACITY);
+        let start = u64_at(&bytes, START);
+        let cursor = u64_at(&bytes, CURSOR);
+
+        let fits = schema.checked_add(schema_capacity).is_some_and(|end| end <= bytes.len())
+            && schema_used <= schema_capacity
+            && capacity > 0
+            && data.checked_add(capacity as usize).is_some_and(|end| end <= bytes.len())
+            && start <= cursor
+            && cursor - start <= capacity;
+        if !fits {
+            return Err(Error::BadHeader);
+        }
+
+        Ok(Self {
+            schema,
+            schema_used,
+            data,
+            capacity,
+            start,
+            cursor,
+            wraps: u64_at(&bytes, WRAPS),
+            pid: u32_at(&bytes, PID),
+            next_schema: 0,
+            next: start,
+            bytes,
+        })
+    }
+
+    pub fn wraps(&self) -> u64 {
+        self.wraps
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// The range of data cursors holding committed records.
+    pub fn cursors(&self) -> (u64, u64) {
+        (self.start, self.cursor)
+    }
+
+    fn record(area: &[u8], pos: usize, payload: &mut Vec<u8>) -> io::Result<(u8, u32)> {
+        let len = u32_at(area, pos) as usize;
+        if record_len(len) > area.len() - pos {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "record runs past the ring area"));
+        }
+        let body = pos + RECORD_HEADER;
+        payload.clear();
+        payload.extend_from_slice(&area[body..body + len]);
+        Ok((area[pos + 8], u32_at(area, pos + 4)))
+    }
+}
+
+impl RecordSource for Snapshot {
+    fn next_record(&mut self, payload: &mut Vec<u8>) -> io::Result<Option<(u8, u32)>> {
+        if self.next_schema + RECORD_HEADER <= self.schema_used {
+            let area = &self.bytes[self.schema..self.schema + self.schema_used];
+            let record = Self::record(area, self.next_schema, payload)?;
+            self.next_schema += record_len(payload.len());
+            return Ok(Some(record));
+        }
+
+        let area = &self.bytes[self.data..self.data + self.capacity as usize];
+        while self.next < self.cursor {
+            let pos = (self.next % self.capacity) as usize;
+            let len = skip_len(area, pos);
+            if area.len() - pos < RECORD_HEADER || u32_at(area, pos) == PAD {
+                self.next += len as u64;
+                continue;
+            }
+
+            let record = Self::record(area, pos, payload)?;
+            self.next += len as u64;
+            return Ok(Some(record));
+        }
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,453 @@
+use super::{Description, Named, Struct};
+use super::buffers::BufDecoder;
+use super::decoder::{self, decode_stored_string, Decoder, NameMap, ResolvedDesc, TypeNameId};
+use super::diff::summary;
+use super::envelope::{Envelope, Level};
+use super::stream::Value;
+
+use std::fmt;
+use std::str::FromStr;
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Field paths
+//
+// A path addresses a part of a record, e.g. `req.status`. It starts with
+// one of the fields of the record itself, listed in `Builtin`, or else with
+// a field of the record's value, from where each segment goes one level
+// down:
+//
+// * A name picks a field of a struct, or enters the variant of an enum if
+//   that is the one present, including `Ok` and `Err` of a `Result`.
+// * A number picks a field of a tuple struct, or an item of a tuple, array
+//   or slice.
+// * Options are looked through, so that `Some(x)` is `x` and `None` is
+//   missing.
+//
+// A leading `.` stands for the value, for reaching the fields of it that
+// share a name with a builtin one, e.g. `.level`, and `.` alone addresses
+// the value itself.
+//
+// `Extractor` reads the parts that a set of paths address from encoded
+// values, skipping the fields that none of them go through, and stopping as
+// soon as everything after them is of no interest.
+
+/// Fields of a record rather than of its value. All but `Type` come from
+/// the envelope, and are missing for values that are not log entries.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Builtin {
+    Time,
+    Level,
+    /// The thread name.
+    Thread,
+    ThreadId,
+    Callsite,
+    /// The name of the type of the value.
+    Type,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 6] = [
+        Builtin::Time, Builtin::Level, Builtin::Thread, Builtin::ThreadId, Builtin::Callsite,
+        Builtin::Type,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Builtin::Time => "time",
+            Builtin::Level => "level",
+            Builtin::Thread => "thread",
+            Builtin::ThreadId => "thread_id",
+            Builtin::Callsite => "callsite",
+            Builtin::Type => "type",
+        }
+    }
+
+    pub fn get(self, envelope: Option<&Envelope>, desc: &ResolvedDesc) -> Option<Scalar> {
+        if self == Builtin::Type {
+            return Some(Scalar::Str(match desc {
+                Description::ByName((name, _), _) => name.clone(),
+                desc => summary(desc),
+            }));
+        }
+
+        let envelope = envelope?;
+        Some(match self {
+            Builtin::Time => Scalar::Int(envelope.time as i128),
+            Builtin::Level => Scalar::Level(envelope.level),
+            Builtin::Thread => Scalar::Str(envelope.thread_name.to_owned()),
+            Builtin::ThreadId => Scalar::Int(envelope.thread_id as i128),
+            Builtin::Callsite => Scalar::Int(envelope.callsite as i128),
+            Builtin::Type => unreachable!(),
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Segment {
+    Name(String),
+    Index(usize),
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Path {
+    Builtin(Builtin),
+    Value(Vec<Segment>),
+}
+
+impl FromStr for Path {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        if let Some(builtin) = Builtin::ALL.iter().find(|b| b.as_str() == s) {
+            return Ok(Path::Builtin(*builtin));
+        }
+
+        if s == "." {
+            return Ok(Path::Value(vec![]));
+        }
+
+        let s = s.strip_prefix('.').unwrap_or(s);
+        let mut segments = vec![];
+        for segment in s.split('.') {
+            let mut chars = segment.chars();
+            let valid = match chars.next() {
+                Some(c) if c.is_ascii_digit() => segment.chars().all(|c| c.is_ascii_digit()),
+                Some(c) if c.is_alphabetic() || c == '_' => chars.all(|c| c.is_alphanumeric() || c == '_'),
+                _ => false,
+            };
+            if !valid {
+                return Err(());
+            }
+            segments.push(match segment.parse() {
+                Ok(idx) => Segment::Index(idx),
+                Err(_) => Segment::Name(segment.to_owned()),
+            });
+        }
+
+        Ok(Path::Value(segments))
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let segments = match self {
+            Path::Builtin(builtin) => return f.write_str(builtin.as_str()),
+            Path::Value(segments) if segments.is_empty() => return f.write_str("."),
+            Path::Value(segments) => segments,
+        };
+
+        let is_builtin = match segments.first() {
+            Some(Segment::Name(name)) => Builtin::ALL.iter().any(|b| b.as_str() == name),
+            _ => false,
+        };
+        for (idx, segment) in segments.iter().enumerate() {
+            if idx > 0 || is_builtin {
+                f.write_str(".")?;
+            }
+            match segment {
+                Segment::Name(name) => f.write_str(name)?,
+                Segment::Index(idx) => write!(f, "{}", idx)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A part of a record, as read through a path.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Scalar {
+    /// Any integer.
+    Int(i128),
+    Bool(bool),
+    Str(String),
+    Level(Level),
+    /// The variant of an enum, or `Ok` or `Err` of a `Result`.
+    Variant(String),
+    /// Anything else, e.g. a struct.
+    Other,
+}
+
+impl fmt::Display for Scalar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scalar::Int(v) => write!(f, "{}", v),
+            Scalar::Bool(v) => write!(f, "{}", v),
+            Scalar::Str(v) => write!(f, "{:?}", v),
+            Scalar::Level(level) => write!(f, "{}", level),
+            Scalar::Variant(name) => f.write_str(name),
+            Scalar::Other => f.write_str("_"),
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Extraction
+
+/// The paths going through a part of a value, by their next segment.
+#[derive(Default)]
+struct Node {
+    /// The paths ending here, by their index.
+    slots: Vec<usize>,
+    children: Vec<(Segment, Node)>,
+}
+
+impl Node {
+    fn child(&self, segment: &Segment) -> Option<&Node> {
+        self.children.iter().find(|(s, _)| s == segment).map(|(_, node)| node)
+    }
+
+    fn named(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|(s, _)| match s {
+            Segment::Name(n) => n == name,
+            Segment::Index(_) => false,
+        }).map(|(_, node)| node)
+    }
+
+    fn last_index(&self) -> Option<usize> {
+        self.children.iter().filter_map(|(s, _)| match s {
+            Segment::Index(idx) => Some(*idx),
+            Segment::Name(_) => None,
+        }).max()
+    }
+}
+
+pub struct Extractor {
+    paths: Vec<Path>,
+    root: Node,
+}
+
+impl Extractor {
+    pub fn new(paths: Vec<Path>) -> Self {
+        let mut root = Node::default();
+        for (slot, path) in paths.iter().enumerate() {
+            let segments = match path {
+                Path::Value(segments) => segments,
+                Path::Builtin(_) => continue,
+            };
+
+            let mut node = &mut root;
+            for segment in segments {
+                let idx = match node.children.iter().position(|(s, _)| s == segment) {
+                    Some(idx) => idx,
+                    None => {
+                        node.children.push((segment.clone(), Node::default()));
+                        node.children.len() - 1
+                    }
+                };
+                node = &mut node.children[idx].1;
+            }
+            node.slots.push(slot);
+        }
+
+        Self { paths, root }
+    }
+
+    pub fn paths(&self) -> &[Path] {
+        &self.paths
+    }
+
+    /// Whether any of the paths goes into the value, rather than only to
+    /// builtin fields.
+    pub fn reads_value(&self) -> bool {
+        !self.root.slots.is_empty() || !self.root.children.is_empty()
+    }
+
+    /// Reads what each path addresses in a value, or `None` where it is
+    /// missing.
+    pub fn extract(&self, value: &Value) -> Result<Vec<Option<Scalar>>, decoder::Error> {
+        self.extract_data(value.envelope.as_ref(), value.desc, value.names, value.data)
+    }
+
+    /// Like `extract`, for a value of type `desc` encoded in `data`.
+    pub fn extract_data(&self, envelope: Option<&Envelope>, desc: &ResolvedDesc, names: &NameMap,
+                        data: &[u8]) -> Result<Vec<Option<Scalar>>, decoder::Error>
+    {
+        let mut out: Vec<Option<Scalar>> = self.paths.iter().map(|path| match path {
+            Path::Builtin(builtin) => builtin.get(envelope, desc),
+            Path::Value(_) => None,
+        }).collect();
+
+        if self.reads_value() {
+            let mut walker = Walker { names, buf: BufDecoder::new(data), out: &mut out };
+            walker.walk(desc, &self.root, true)?;
+        }
+        Ok(out)
+    }
+}
+
+struct Walker<'a, 'b> {
+    names: &'a NameMap,
+    buf: BufDecoder<'b>,
+    out: &'a mut [Option<Scalar>],
+}
+
+macro_rules! int {
+    ($self:ident, $node:ident, $type:ty) => {
+        {
+            let val = $self.buf.get::<$type>().map_err(decoder::Error::GetError)?;
+            $self.leaf($node, Scalar::Int(val as i128));
+        }
+    }
+}
+
+impl<'a, 'b> Walker<'a, 'b> {
+    fn leaf(&mut self, node: &Node, scalar: Scalar) {
+        for slot in &node.slots {
+            self.out[*slot] = Some(scalar.clone());
+        }
+    }
+
+    /// Moves past a value no path goes into. With `tail`, nothing after it
+    /// is needed either, so it isn't even read.
+    fn skip(&mut self, desc: &ResolvedDesc, tail: bool) -> Result<(), decoder::Error> {
+        if tail {
+            return Ok(());
+        }
+        let mut decoder = Decoder::new(self.names, self.buf.clone());
+        decoder.skip(desc)?;
+        self.buf = decoder.into_decoder();
+        Ok(())
+    }
+
+    fn walk(&mut self, desc: &ResolvedDesc, node: &Node, tail: bool) -> Result<(), decoder::Error> {
+        use Description::*;
+
+        if node.slots.is_empty() && node.children.is_empty() {
+            return self.skip(desc, tail);
+        }
+
+        match desc {
+            U8 => int!(self, node, u8),
+            U16 => int!(self, node, u16),
+            U32 => int!(self, node, u32),
+            U64 => int!(self, node, u64),
+            I8 => int!(self, node, i8),
+            I16 => int!(self, node, i16),
+            I32 => int!(self, node, i32),
+            I64 => int!(self, node, i64),
+            RawPtr => int!(self, node, u64),
+            Bool => {
+                let val = self.buf.get::<bool>().map_err(decoder::Error::GetError)?;
+                self.leaf(node, Scalar::Bool(val));
+            }
+            String => {
+                let val = decode_stored_string(&mut self.buf)?;
+                self.leaf(node, Scalar::Str(val.to_owned()));
+            }
+            Unit | PhantomData => self.leaf(node, Scalar::Other),
+            Option(sub) => match self.buf.get::<u8>().map_err(decoder::Error::GetError)? {
+                0 => {}
+                1 => self.walk(sub, node, tail)?,
+                n => return Err(decoder::Error::InvalidSome(n)),
+            },
+            Result(ok, err) => {
+                let (name, sub) = match self.buf.get::<u8>().map_err(decoder::Error::GetError)? {
+                    0 => ("Ok", ok),
+                    1 => ("Err", err),
+                    n => return Err(decoder::Error::InvalidResult(n)),
+                };
+                self.leaf(node, Scalar::Variant(name.to_owned()));
+                match node.named(name) {
+                    Some(child) => self.walk(sub, child, tail)?,
+                    None => self.skip(sub, tail)?,
+                }
+            }
+            Array(size, sub) => {
+                self.leaf(node, Scalar::Other);
+                self.items(std::iter::repeat_n(&**sub, *size), node, tail)?;
+            }
+            Slice(sub) => {
+                let size = self.buf.get::<u64>().map_err(decoder::Error::GetError)? as usize;
+                self.leaf(node, Scalar::Other);
+                self.items(std::iter::repeat_n(&**sub, size), node, tail)?;
+            }
+            Tuple(subs) => {
+                self.leaf(node, Scalar::Other);
+                self.items(subs.iter(), node, tail)?;
+            }
+            ByName(id, named) => {
+                let named = match named {
+                    Some(named) => named,
+                    None => self.names.get_map().get(id)
+                        .ok_or_else(|| decoder::Error::MissingType(id.clone()))?,
+                };
+                self.named(named, node, tail)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn items<'d, I>(&mut self, items: I, node: &Node, tail: bool) -> Result<(), decoder::Error>
+        where I: Iterator<Item = &'d ResolvedDesc>
+    {
+        let last = node.last_index();
+        for (idx, desc) in items.enumerate() {
+            if tail && last.is_none_or(|last| idx > last) {
+                break;
+            }
+            match node.child(&Segment::Index(idx)) {
+                Some(child) => self.walk(desc, child, tail && Some(idx) == last)?,
+                None => self.skip(desc, false)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn named(&mut self, named: &Named<TypeNameId>, node: &Node, tail: bool)
+        -> Result<(), decoder::Error>
+    {
+        match named {
+            Named::Struct(struct_) => {
+                self.leaf(node, Scalar::Other);
+                self.fields(struct_, node, tail)
+            }
+            Named::Enum(variants) => {
+                let len = variants.len();
+                let idx = if len < 0x100 {
+                    self.buf.get::<u8>().map_err(decoder::Error::GetError)? as usize
+                } else if len < 0x10000 {
+                    self.buf.get::<u16>().map_err(decoder::Error::GetError)? as usize
+                } else {
+                    self.buf.get::<u32>().map_err(decoder::Error::GetError)? as usize
+                };
+                if idx >= len {
+                    return Err(decoder::Error::InvalidIndex(idx, len));
+                }
+
+                let (name, struct_) = &variants[idx];
+                self.leaf(node, Scalar::Variant(name.clone()));
+                match node.named(name) {
+                    Some(child) => {
+                        self.leaf(child, Scalar::Variant(name.clone()));
+                        self.fields(struct_, child, tail)
+                    }
+                    None => self.fields(struct_, &Node::default(), tail),
+                }
+            }
+        }
+    }
+
+    fn fields(&mut self, struct_: &Struct<TypeNameId>, node: &Node, tail: bool)
+        -> Result<(), decoder::Error>
+    {
+        match struct_ {
+            Struct::Unit => Ok(()),
+            Struct::Tuple(fields) => self.items(fields.iter(), node, tail),
+            Struct::Named(fields) => {
+                let last = fields.iter().rposition(|(name, _)| node.named(name).is_some());
+                for (idx, (name, desc)) in fields.iter().enumerate() {
+                    if tail && last.is_none_or(|last| idx > last) {
+                        break;
+                    }
+                    match node.named(name) {
+                        Some(child) => self.walk(desc, child, tail && Some(idx) == last)?,
+                        None => self.skip(desc, false)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use super::Named;
+use super::RefDesc;
+use super::decoder::{NameMap, FeedError, TypeNameId};
+use super::diff;
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Schema registry files
+//
+// A registry file holds the definitions of a `NameMap`, one entry per named
+// type, in either of two formats:
+//
+// * RON: a header line, `// logpack-schema <version>`, followed by one
+//   `((name, id), definition)` entry per line.
+//
+// * Binary: the magic `LPSCHEMA`, a little-endian `u32` version, and then
+//   per entry a little-endian `u32` length followed by the bincode-serialized
+//   `((name, id), definition)`.
+//
+// Both are append-only: entries are only ever added at the end, so a logging
+// process can extend a sidecar file as it discovers new types. An entry left
+// incomplete by a crash is ignored on load.
+
+pub const VERSION: u32 = 1;
+
+const RON_HEADER: &str = "// logpack-schema ";
+const BINARY_MAGIC: &[u8; 8] = b"LPSCHEMA";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Ron,
+    Binary,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Ron(ron::error::SpannedError),
+    RonSer(ron::error::Error),
+    Bincode(bincode::Error),
+    BadHeader,
+    UnsupportedVersion(u32),
+    Feed(FeedError),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+type Entry = (TypeNameId, Named<TypeNameId>);
+
+fn write_header<W: Write>(out: &mut W, format: Format) -> Result<(), Error> {
+    match format {
+        Format::Ron => writeln!(out, "{}{}", RON_HEADER, VERSION)?,
+        Format::Binary => {
+            out.write_all(BINARY_MAGIC)?;
+            out.write_all(&VERSION.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn write_entry<W: Write>(out: &mut W, format: Format, entry: &Entry) -> Result<(), Error> {
+    match format {
+        Format::Ron => {
+            let line = ron::ser::to_string(entry).map_err(Error::RonSer)?;
+            writeln!(out, "{}", line)?;
+        }
+        Format::Binary => {
+            let bytes = bincode::serialize(entry).map_err(Error::Bincode)?;
+            out.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            out.write_all(&bytes)?;
+        }
+    }
+    Ok(())
+}
+
+fn sorted_entries(map: &NameMap) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = map.get_map().iter()
+        .map(|(k, v)| (k.clone(), v.clone())).collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+}
+
+pub fn write_to<W: Write>(map: &NameMap, out: &mut W, format: Format) -> Result<(), Error> {
+    write_header(out, format)?;
+    for entry in sorted_entries(map).iter() {
+        write_entry(out, format, entry)?;
+    }
+    Ok(())
+}
+
+/// Whether the given file content starts like a registry file.
+pub fn is_registry(head: &[u8]) -> bool {
+    head.starts_with(BINARY_MAGIC) || head.starts_with(RON_HEADER.as_bytes())
+}
+
+/// Reads a registry in either format, telling them apart by their header.
+/// Entries are merged into `map`, so definitions that conflict with ones
+/// already there are reported.
+pub fn read_into<R: Read>(input: R, map: &mut NameMap) -> Result<Format, Error> {
+    let (format, entries, _) = read_entries(input)?;
+    for entry in entries {
+        map.insert_named(entry.0, entry.1).map_err(Error::Feed)?;
+    }
+    Ok(format)
+}
+
+/// Returns the entries along with the length of the file up to the end of
+/// the last complete one.
+fn read_entries<R: Read>(input: R) -> Result<(Format, Vec<Entry>, u64), Error> {
+    let mut input = BufReader::new(input);
+    let mut entries = vec![];
+    let mut valid_len;
+
+    if input.fill_buf()?.starts_with(BINARY_MAGIC) {
+        let mut header = [0u8; 12];
+        input.read_exact(&mut header)?;
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&header[8..]);
+        let version = u32::from_le_bytes(version);
+        if version > VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        valid_len = header.len() as u64;
+
+        loop {
+            let mut len = [0u8; 4];
+            if !read_fully(&mut input, &mut len)? {
+                break;
+            }
+            // Read rather than allocate up to the length, which may be
+            // corrupt.
+            let len = u32::from_le_bytes(len) as u64;
+            let mut bytes = vec![];
+            if input.by_ref().take(len).read_to_end(&mut bytes)? as u64 != len {
+                break;
+            }
+            entries.push(bincode::deserialize(&bytes).map_err(Error::Bincode)?);
+            valid_len += 4 + len;
+        }
+
+        return Ok((Format::Binary, entries, valid_len));
+    }
+
+    let mut header = String::new();
+    input.read_line(&mut header)?;
+    let version = header.trim_end().strip_prefix(RON_HEADER)
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or(Error::BadHeader)?;
+    if version > VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    valid_len = header.len() as u64;
+
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        if !line.ends_with('\n') {
+            break;
+        }
+        if !line.trim().is_empty() {
+            entries.push(ron::de::from_str(&line).map_err(Error::Ron)?);
+        }
+        valid_len += line.len() as u64;
+    }
+
+    Ok((Format::Ron, entries, valid_len))
+}
+
+/// Like `read_exact`, but returns `false` when the input ends early.
+fn read_fully<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match input.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn save(map: &NameMap, path: &Path, format: Format) -> Result<(), Error> {
+    let mut file = io::BufWriter::new(File::create(path)?);
+    write_to(map, &mut file, format)?;
+    file.flush()?;
+    Ok(())
+}
+
+pub fn load(path: &Path) -> Result<NameMap, Error> {
+    let mut map = NameMap::new();
+    read_into(File::open(path)?, &mut map)?;
+    Ok(map)
+}
+
+/// Keeps a registry file up to date with the types a process logs, by
+/// appending each definition the first time it is seen.
+pub struct Appender {
+    file: File,
+    format: Format,
+    written: HashMap<TypeNameId, Named<TypeNameId>>,
+}
+
+impl Appender {
+    /// Opens an existing registry file, keeping its format and entries, or
+    /// creates a new one in the given format.
+    pub fn open(path: &Path, format: Format) -> Result<Self, Error> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+
+        let len = file.metadata()?.len();
+        if len == 0 {
+            write_header(&mut file, format)?;
+            return Ok(Self { file, format, written: HashMap::new() });
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        let (format, entries, valid_len) = read_entries(&mut file)?;
+        if valid_len < len {
+            // Drop the remains of an interrupted append.
+            file.set_len(valid_len)?;
+        }
+
+        Ok(Self {
+            file,
+            format,
+            written: entries.into_iter().collect(),
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Appends the definitions carried by a description, such as one just
+    /// returned by `logpack_describe`, that the file doesn't have yet.
+    pub fn append(&mut self, desc: &RefDesc) -> Result<(), Error> {
+        let mut map = NameMap::new();
+        map.feed(desc.to_resolved()).map_err(Error::Feed)?;
+        self.append_map(&map)
+    }
+
+    /// Appends the definitions of `map` that the file doesn't have yet. A
+    /// definition that differs from the one in the file is reported, and
+    /// then nothing is appended.
+    pub fn append_map(&mut self, map: &NameMap) -> Result<(), Error> {
+        let entries: Vec<_> = sorted_entries(map).into_iter()
+            .filter(|(name, _)| !self.written.contains_key(name))
+            .collect();
+        for (name, named) in map.get_map() {
+            match self.written.get(name) {
+                Some(written) if written != named => {
+                    let diffs = diff::diff_named(written, named);
+                    return Err(Error::Feed(FeedError::Conflict(name.clone(), diffs)));
+                }
+                _ => {}
+            }
+        }
+
+        let mut buf = vec![];
+        for entry in entries.iter() {
+            write_entry(&mut buf, self.format, entry)?;
+        }
+        if !buf.is_empty() {
+            self.file.write_all(&buf)?;
+            self.file.flush()?;
+        }
+        self.written.extend(entries);
+        Ok(())
+    }
+}
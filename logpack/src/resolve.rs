@@ -0,0 +1,456 @@
+use super::Description;
+use super::Named;
+use super::Struct;
+use super::buffers::BufDecoder;
+use super::decoder::{self, Callbacks, Decoder, NameMap, TypeNameId, ResolvedDesc};
+use super::diff::{summary, struct_summary};
+
+use std::collections::HashSet;
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Schema resolution
+//
+// Decodes values written under one schema (the writer's) and presents them
+// through `Callbacks` as values of another (the reader's), in the manner of
+// Avro's writer and reader schemas:
+//
+// * Named fields are matched by name. Fields missing on the writer's side
+//   must be `Option`s on the reader's side and are presented as `None`.
+//   Fields missing on the reader's side are skipped.
+// * Enum variants are matched by name.
+// * Integers may be widened, e.g. `u16` to `u64` or `u32` to `i64`.
+// * A value may be presented as `Some` where the reader made it optional.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Incompatibility {
+    /// Path to the offending item, e.g. `.req.status`. Empty for the root.
+    pub path: String,
+    pub writer: String,
+    pub reader: String,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Decode(decoder::Error),
+    Incompatible(Incompatibility),
+    /// The writer used an enum variant that the reader does not have.
+    UnknownVariant(TypeNameId, String),
+}
+
+pub struct Resolver<'a> {
+    writer: &'a NameMap,
+    reader: &'a NameMap,
+}
+
+fn int_kind(desc: &ResolvedDesc) -> Option<(bool, u32)> {
+    use Description::*;
+
+    match desc {
+        U8 => Some((false, 8)),
+        U16 => Some((false, 16)),
+        U32 => Some((false, 32)),
+        U64 => Some((false, 64)),
+        I8 => Some((true, 8)),
+        I16 => Some((true, 16)),
+        I32 => Some((true, 32)),
+        I64 => Some((true, 64)),
+        _ => None,
+    }
+}
+
+/// Whether every value of integer type `writer` fits in integer type `reader`.
+pub fn widens(writer: &ResolvedDesc, reader: &ResolvedDesc) -> bool {
+    match (int_kind(writer), int_kind(reader)) {
+        (Some((w_signed, w_bits)), Some((r_signed, r_bits))) => {
+            if w_signed == r_signed {
+                r_bits >= w_bits
+            } else {
+                !w_signed && r_bits > w_bits
+            }
+        }
+        _ => false,
+    }
+}
+
+fn is_leaf(desc: &ResolvedDesc) -> bool {
+    use Description::*;
+
+    matches!(desc, Unit | PhantomData | Bool | String | RawPtr)
+}
+
+fn lookup<'a>(map: &'a NameMap, id: &TypeNameId, inline: &'a Option<Named<TypeNameId>>)
+    -> Option<&'a Named<TypeNameId>>
+{
+    match inline {
+        Some(named) => Some(named),
+        None => map.get_map().get(id),
+    }
+}
+
+fn incompatible(path: &str, writer: String, reader: String) -> Incompatibility {
+    Incompatibility { path: path.to_owned(), writer, reader }
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(writer: &'a NameMap, reader: &'a NameMap) -> Self {
+        Self { writer, reader }
+    }
+
+    /// Reports every change between the two descriptions that would prevent
+    /// some value of the writer's type from being presented as the reader's.
+    pub fn check(&self, writer: &ResolvedDesc, reader: &ResolvedDesc) -> Result<(), Vec<Incompatibility>> {
+        let mut errors = vec![];
+        let mut visited = HashSet::new();
+        self.check_desc("", writer, reader, &mut visited, &mut errors);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn check_desc(&self, path: &str, writer: &ResolvedDesc, reader: &ResolvedDesc,
+                  visited: &mut HashSet<(TypeNameId, TypeNameId)>,
+                  errors: &mut Vec<Incompatibility>)
+    {
+        use Description::*;
+
+        if (is_leaf(writer) && writer == reader) || widens(writer, reader) {
+            return;
+        }
+
+        match (writer, reader) {
+            (Option(w), Option(r)) => self.check_desc(&format!("{}?", path), w, r, visited, errors),
+            (Slice(w), Slice(r)) => self.check_desc(&format!("{}[]", path), w, r, visited, errors),
+            (Array(w_size, w), Array(r_size, r)) if w_size == r_size => {
+                self.check_desc(&format!("{}[]", path), w, r, visited, errors)
+            }
+            (Result(w_ok, w_err), Result(r_ok, r_err)) => {
+                self.check_desc(&format!("{}::Ok", path), w_ok, r_ok, visited, errors);
+                self.check_desc(&format!("{}::Err", path), w_err, r_err, visited, errors);
+            }
+            (Tuple(w), Tuple(r)) => self.check_tuple(path, w, r, visited, errors),
+            (ByName(w_id, w_named), ByName(r_id, r_named)) => {
+                if !visited.insert((w_id.clone(), r_id.clone())) {
+                    return;
+                }
+                let w_named = match lookup(self.writer, w_id, w_named) {
+                    Some(x) => x,
+                    None => return errors.push(incompatible(path, summary(writer), "undefined".to_owned())),
+                };
+                let r_named = match lookup(self.reader, r_id, r_named) {
+                    Some(x) => x,
+                    None => return errors.push(incompatible(path, "undefined".to_owned(), summary(reader))),
+                };
+                self.check_named(path, w_named, r_named, visited, errors);
+            }
+            (w, Option(r)) => self.check_desc(path, w, r, visited, errors),
+            (w, r) => errors.push(incompatible(path, summary(w), summary(r))),
+        }
+    }
+
+    fn check_tuple(&self, path: &str, writer: &[ResolvedDesc], reader: &[ResolvedDesc],
+                   visited: &mut HashSet<(TypeNameId, TypeNameId)>,
+                   errors: &mut Vec<Incompatibility>)
+    {
+        if writer.len() != reader.len() {
+            errors.push(incompatible(path, format!("{} items", writer.len()),
+                                     format!("{} items", reader.len())));
+            return;
+        }
+
+        for (idx, (w, r)) in writer.iter().zip(reader.iter()).enumerate() {
+            self.check_desc(&format!("{}.{}", path, idx), w, r, visited, errors);
+        }
+    }
+
+    fn check_named(&self, path: &str, writer: &Named<TypeNameId>, reader: &Named<TypeNameId>,
+                   visited: &mut HashSet<(TypeNameId, TypeNameId)>,
+                   errors: &mut Vec<Incompatibility>)
+    {
+        match (writer, reader) {
+            (Named::Struct(w), Named::Struct(r)) => self.check_struct(path, w, r, visited, errors),
+            (Named::Enum(w), Named::Enum(r)) => {
+                for (name, w_struct) in w.iter() {
+                    let variant_path = format!("{}::{}", path, name);
+                    match r.iter().find(|(r_name, _)| r_name == name) {
+                        Some((_, r_struct)) => {
+                            self.check_struct(&variant_path, w_struct, r_struct, visited, errors)
+                        }
+                        None => errors.push(incompatible(&variant_path, "variant".to_owned(),
+                                                         "removed".to_owned())),
+                    }
+                }
+            }
+            (Named::Struct(_), Named::Enum(_)) => {
+                errors.push(incompatible(path, "struct".to_owned(), "enum".to_owned()))
+            }
+            (Named::Enum(_), Named::Struct(_)) => {
+                errors.push(incompatible(path, "enum".to_owned(), "struct".to_owned()))
+            }
+        }
+    }
+
+    fn check_struct(&self, path: &str, writer: &Struct<TypeNameId>, reader: &Struct<TypeNameId>,
+                    visited: &mut HashSet<(TypeNameId, TypeNameId)>,
+                    errors: &mut Vec<Incompatibility>)
+    {
+        match (writer, reader) {
+            (Struct::Unit, Struct::Unit) => {}
+            (Struct::Tuple(w), Struct::Tuple(r)) => self.check_tuple(path, w, r, visited, errors),
+            (Struct::Named(w), Struct::Named(r)) => {
+                for (name, r_field) in r.iter() {
+                    let field_path = format!("{}.{}", path, name);
+                    match w.iter().find(|(w_name, _)| w_name == name) {
+                        Some((_, w_field)) => {
+                            self.check_desc(&field_path, w_field, r_field, visited, errors)
+                        }
+                        None => {
+                            if let Description::Option(_) = r_field {
+                                continue;
+                            }
+                            errors.push(incompatible(&field_path, "absent".to_owned(),
+                                                     format!("added as {}", summary(r_field))));
+                        }
+                    }
+                }
+            }
+            (w, r) => errors.push(incompatible(path, struct_summary(w), struct_summary(r))),
+        }
+    }
+
+    /// Decodes one value written as `writer` and presents it as `reader`.
+    pub fn decode<C>(&self, buffer: &mut BufDecoder, writer: &ResolvedDesc, reader: &ResolvedDesc,
+                     callbacks: &mut C) -> Result<(), Error>
+        where C: Callbacks
+    {
+        use Description::*;
+
+        if is_leaf(writer) && writer == reader {
+            let mut decoder = Decoder::new(self.writer, buffer.clone());
+            decoder.decode(writer, callbacks).map_err(Error::Decode)?;
+            *buffer = decoder.into_decoder();
+            return Ok(());
+        }
+
+        if widens(writer, reader) {
+            return self.decode_int(buffer, writer, reader, callbacks);
+        }
+
+        match (writer, reader) {
+            (Option(w), Option(r)) => {
+                match buffer.get::<u8>().map_err(|e| Error::Decode(decoder::Error::GetError(e)))? {
+                    0 => callbacks.option_none(),
+                    1 => {
+                        let ctx = callbacks.option_some();
+                        self.decode(buffer, w, r, ctx)?;
+                        ctx.option_end();
+                    }
+                    n => return Err(Error::Decode(decoder::Error::InvalidSome(n))),
+                }
+                Ok(())
+            }
+            (Result(w_ok, w_err), Result(r_ok, r_err)) => {
+                match buffer.get::<u8>().map_err(|e| Error::Decode(decoder::Error::GetError(e)))? {
+                    0 => {
+                        let ctx = callbacks.result_ok();
+                        self.decode(buffer, w_ok, r_ok, ctx)?;
+                        ctx.result_end();
+                    }
+                    1 => {
+                        let ctx = callbacks.result_err();
+                        self.decode(buffer, w_err, r_err, ctx)?;
+                        ctx.result_end();
+                    }
+                    n => return Err(Error::Decode(decoder::Error::InvalidResult(n))),
+                }
+                Ok(())
+            }
+            (Slice(w), Slice(r)) => {
+                let size = buffer.get::<u64>()
+                    .map_err(|e| Error::Decode(decoder::Error::GetError(e)))? as usize;
+                let ctx = callbacks.begin_slice(size);
+                for idx in 0..size {
+                    ctx.begin_slice_item(idx as u16);
+                    self.decode(buffer, w, r, ctx)?;
+                    ctx.end_slice_item();
+                }
+                ctx.end_slice();
+                Ok(())
+            }
+            (Array(w_size, w), Array(r_size, r)) if w_size == r_size => {
+                let ctx = callbacks.begin_array(*r_size);
+                for idx in 0..*r_size {
+                    ctx.begin_array_item(idx as u16);
+                    self.decode(buffer, w, r, ctx)?;
+                    ctx.end_array_item();
+                }
+                ctx.end_array();
+                Ok(())
+            }
+            (Tuple(w), Tuple(r)) if w.len() == r.len() => {
+                let ctx = callbacks.begin_tuple(r.len());
+                for (idx, (w, r)) in w.iter().zip(r.iter()).enumerate() {
+                    ctx.begin_tuple_item(idx as u16);
+                    self.decode(buffer, w, r, ctx)?;
+                    ctx.end_tuple_item();
+                }
+                ctx.end_tuple();
+                Ok(())
+            }
+            (ByName(w_id, w_named), ByName(r_id, r_named)) => {
+                let w_named = lookup(self.writer, w_id, w_named)
+                    .ok_or_else(|| Error::Decode(decoder::Error::MissingType(w_id.clone())))?;
+                let r_named = lookup(self.reader, r_id, r_named)
+                    .ok_or_else(|| Error::Decode(decoder::Error::MissingType(r_id.clone())))?;
+                self.decode_named(buffer, w_named, r_id, r_named, callbacks)
+            }
+            (w, Option(r)) => {
+                let ctx = callbacks.option_some();
+                self.decode(buffer, w, r, ctx)?;
+                ctx.option_end();
+                Ok(())
+            }
+            (w, r) => Err(Error::Incompatible(incompatible("", summary(w), summary(r)))),
+        }
+    }
+
+    fn decode_int<C>(&self, buffer: &mut BufDecoder, writer: &ResolvedDesc, reader: &ResolvedDesc,
+                     callbacks: &mut C) -> Result<(), Error>
+        where C: Callbacks
+    {
+        use Description::*;
+
+        let get_err = |e| Error::Decode(decoder::Error::GetError(e));
+        let value: i128 = match writer {
+            U8 => buffer.get::<u8>().map_err(get_err)? as i128,
+            U16 => buffer.get::<u16>().map_err(get_err)? as i128,
+            U32 => buffer.get::<u32>().map_err(get_err)? as i128,
+            U64 => buffer.get::<u64>().map_err(get_err)? as i128,
+            I8 => buffer.get::<i8>().map_err(get_err)? as i128,
+            I16 => buffer.get::<i16>().map_err(get_err)? as i128,
+            I32 => buffer.get::<i32>().map_err(get_err)? as i128,
+            I64 => buffer.get::<i64>().map_err(get_err)? as i128,
+            _ => unreachable!(),
+        };
+
+        match reader {
+            U8 => callbacks.handle_u8(value as u8),
+            U16 => callbacks.handle_u16(value as u16),
+            U32 => callbacks.handle_u32(value as u32),
+            U64 => callbacks.handle_u64(value as u64),
+            I8 => callbacks.handle_i8(value as i32),
+            I16 => callbacks.handle_i16(value as i32),
+            I32 => callbacks.handle_i32(value as i32),
+            I64 => callbacks.handle_i64(value as i64),
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    fn decode_named<C>(&self, buffer: &mut BufDecoder, writer: &Named<TypeNameId>,
+                       reader_id: &TypeNameId, reader: &Named<TypeNameId>,
+                       callbacks: &mut C) -> Result<(), Error>
+        where C: Callbacks
+    {
+        match (writer, reader) {
+            (Named::Struct(w), Named::Struct(r)) => {
+                self.decode_struct(buffer, w, Some(reader_id), r, callbacks)
+            }
+            (Named::Enum(w), Named::Enum(r)) => {
+                let len = w.len();
+                let get_err = |e| Error::Decode(decoder::Error::GetError(e));
+                let idx = if len < 0x100 {
+                    buffer.get::<u8>().map_err(get_err)? as usize
+                } else if len < 0x10000 {
+                    buffer.get::<u16>().map_err(get_err)? as usize
+                } else {
+                    buffer.get::<u32>().map_err(get_err)? as usize
+                };
+                if idx >= len {
+                    return Err(Error::Decode(decoder::Error::InvalidIndex(idx, len)));
+                }
+
+                let (name, w_struct) = &w[idx];
+                let r_struct = match r.iter().find(|(r_name, _)| r_name == name) {
+                    Some((_, r_struct)) => r_struct,
+                    None => return Err(Error::UnknownVariant(reader_id.clone(), name.clone())),
+                };
+
+                let ctx = callbacks.begin_enum(reader_id, name);
+                self.decode_struct(buffer, w_struct, None, r_struct, ctx)?;
+                ctx.end_enum(reader_id);
+                Ok(())
+            }
+            (Named::Struct(_), Named::Enum(_)) => {
+                Err(Error::Incompatible(incompatible("", "struct".to_owned(), "enum".to_owned())))
+            }
+            (Named::Enum(_), Named::Struct(_)) => {
+                Err(Error::Incompatible(incompatible("", "enum".to_owned(), "struct".to_owned())))
+            }
+        }
+    }
+
+    fn decode_struct<C>(&self, buffer: &mut BufDecoder, writer: &Struct<TypeNameId>,
+                        reader_id: Option<&TypeNameId>, reader: &Struct<TypeNameId>,
+                        callbacks: &mut C) -> Result<(), Error>
+        where C: Callbacks
+    {
+        match (writer, reader) {
+            (Struct::Unit, Struct::Unit) => {
+                callbacks.struct_unit(reader_id);
+                Ok(())
+            }
+            (Struct::Tuple(w), Struct::Tuple(r)) if w.len() == r.len() => {
+                let ctx = callbacks.begin_struct_tuple(reader_id);
+                for (idx, (w, r)) in w.iter().zip(r.iter()).enumerate() {
+                    let ctx = ctx.begin_tuple_field(idx as u16);
+                    self.decode(buffer, w, r, ctx)?;
+                    ctx.end_tuple_field();
+                }
+                ctx.end_struct_tuple();
+                Ok(())
+            }
+            (Struct::Named(w), Struct::Named(r)) => {
+                // The writer's fields are laid out in its own order, so find
+                // where each one lies before presenting them in the reader's.
+                let mut fields = Vec::with_capacity(w.len());
+                for (_, w_field) in w.iter() {
+                    let before = buffer.clone();
+                    let mut decoder = Decoder::new(self.writer, buffer.clone());
+                    decoder.skip(w_field).map_err(Error::Decode)?;
+                    *buffer = decoder.into_decoder();
+
+                    let size = before.remaining() - buffer.remaining();
+                    let mut before = before;
+                    let bytes = before.get_slice(size)
+                        .map_err(|e| Error::Decode(decoder::Error::GetError(e)))?;
+                    fields.push(bytes);
+                }
+
+                let ctx = callbacks.begin_struct_named(reader_id);
+                for (idx, (name, r_field)) in r.iter().enumerate() {
+                    let ctx = ctx.begin_named_field(idx as u16, name);
+                    match w.iter().position(|(w_name, _)| w_name == name) {
+                        Some(w_idx) => {
+                            let mut sub = BufDecoder::new(fields[w_idx]);
+                            self.decode(&mut sub, &w[w_idx].1, r_field, ctx)?;
+                        }
+                        None => match r_field {
+                            Description::Option(_) => ctx.option_none(),
+                            _ => return Err(Error::Incompatible(incompatible(
+                                &format!(".{}", name), "absent".to_owned(), summary(r_field)))),
+                        }
+                    }
+                    ctx.end_named_field();
+                }
+                ctx.end_struct_named();
+                Ok(())
+            }
+            (w, r) => Err(Error::Incompatible(incompatible("", struct_summary(w), struct_summary(r)))),
+        }
+    }
+}
@@ -0,0 +1,593 @@
+use super::buffers::{BufEncoder, BufDecoder};
+use super::callsite::{self, CallsiteInfo, Sink};
+use super::encoder::Encoder;
+use super::envelope::{Envelope, Level};
+
+use std::cell::{RefCell, UnsafeCell};
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::Duration;
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Per-thread ring buffers
+//
+// Each producing thread owns a single-producer ring of bytes, into which it
+// reserves space for a record, encodes it in place and commits it. A
+// consumer drains the rings of all threads, so producers never share a lock.
+//
+//     record := u32(len) payload padding
+//
+// Records are padded to a multiple of 4 bytes. A record that doesn't fit
+// before the end of the ring is preceded by a `PAD` header that tells the
+// consumer to skip to the start.
+//
+// `head` and `tail` are byte counters that only grow; the consumer advances
+// `head` and the producer `tail`. With `Overflow::DropOldest` the producer
+// advances `head` too, holding the ring's `pop` lock like the consumer does
+// while it copies a record out, so a record is never written over while it
+// is being read.
+//
+// With `Overflow::Block` a producer waits on a condition variable, which the
+// drain thread signals whenever it makes room.
+
+const HEADER: usize = 4;
+const PAD: u32 = u32::MAX;
+
+const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+fn record_len(payload: usize) -> usize {
+    (HEADER + payload + 3) & !3
+}
+
+/// What a producer does when its ring is full.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Overflow {
+    /// Drop the record being written.
+    DropNewest,
+    /// Drop the oldest records until the new one fits.
+    DropOldest,
+    /// Wait for the consumer to make room. Records are dropped instead when
+    /// the drain thread has exited, or when it is the one writing them.
+    Block,
+}
+
+struct Ring {
+    buf: Box<[UnsafeCell<u8>]>,
+    mask: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    dropped: AtomicU64,
+    /// Set when the producer is gone, so the ring goes away once drained.
+    closed: AtomicBool,
+    /// Held while advancing `head`.
+    pop: Mutex<()>,
+}
+
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(64).next_power_of_two();
+        Self {
+            buf: (0..capacity).map(|_| UnsafeCell::new(0)).collect(),
+            mask: capacity - 1,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            pop: Mutex::new(()),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+
+    fn ptr(&self, pos: usize) -> *mut u8 {
+        unsafe { (self.buf.as_ptr() as *mut u8).add(pos & self.mask) }
+    }
+
+    fn to_end(&self, pos: usize) -> usize {
+        self.capacity() - (pos & self.mask)
+    }
+
+    fn read_header(&self, pos: usize) -> u32 {
+        u32::from_ne_bytes(unsafe { std::ptr::read_volatile(self.ptr(pos) as *const [u8; HEADER]) })
+    }
+
+    fn write_header(&self, pos: usize, header: u32) {
+        unsafe { std::ptr::write_volatile(self.ptr(pos) as *mut [u8; HEADER], header.to_ne_bytes()) }
+    }
+
+    /// The length of the record at `pos`, with its header and padding.
+    fn skip_len(&self, pos: usize, header: u32) -> usize {
+        match header {
+            PAD => self.to_end(pos),
+            len => record_len(len as usize),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::SeqCst) == self.tail.load(Ordering::SeqCst)
+    }
+
+    /// Drops the oldest record, on the producer's side.
+    fn drop_oldest(&self) {
+        let _pop = lock(&self.pop);
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let header = self.read_header(head);
+        self.head.store(head + self.skip_len(head, header), Ordering::Release);
+        if header != PAD {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Moves the next record into `out`, on the consumer's side.
+    fn pop(&self, out: &mut Vec<u8>) -> bool {
+        let _pop = lock(&self.pop);
+        loop {
+            let head = self.head.load(Ordering::Relaxed);
+            if head == self.tail.load(Ordering::Acquire) {
+                return false;
+            }
+
+            let header = self.read_header(head);
+            if header != PAD {
+                out.clear();
+                out.resize(header as usize, 0);
+                unsafe {
+                    std::ptr::copy_nonoverlapping(self.ptr(head + HEADER), out.as_mut_ptr(), out.len());
+                }
+            }
+
+            self.head.store(head + self.skip_len(head, header), Ordering::Release);
+            if header != PAD {
+                return true;
+            }
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Producer
+
+/// The writing end of one thread's ring.
+pub struct Producer {
+    ring: Arc<Ring>,
+    shared: Arc<Shared>,
+}
+
+/// Space reserved in a ring. The record is published by `commit`, and
+/// abandoned if the reservation is dropped instead.
+pub struct Reservation<'a> {
+    ring: &'a Ring,
+    start: usize,
+    len: usize,
+}
+
+impl<'a> Reservation<'a> {
+    pub fn buf(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ring.ptr(self.start + HEADER), self.len) }
+    }
+
+    pub fn commit(self) {
+        self.ring.tail.store(self.start + record_len(self.len), Ordering::Release);
+    }
+}
+
+impl Producer {
+    /// Waits for `len` bytes to be free past the tail, as far as the
+    /// overflow policy allows.
+    fn make_room(&self, len: usize) -> bool {
+        let ring = &*self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let fits = || tail + len - ring.head.load(Ordering::Acquire) <= ring.capacity();
+        if fits() {
+            return true;
+        }
+
+        match self.shared.overflow {
+            Overflow::DropNewest => false,
+            Overflow::DropOldest => {
+                while !fits() {
+                    ring.drop_oldest();
+                }
+                true
+            }
+            Overflow::Block => self.shared.can_block() && self.shared.wait(fits),
+        }
+    }
+
+    /// Reserves room for a record of `len` bytes. Returns `None` if the
+    /// record was dropped, either because the ring is full under
+    /// `Overflow::DropNewest` or because it can never fit.
+    pub fn reserve(&mut self, len: usize) -> Option<Reservation<'_>> {
+        let ring = &*self.ring;
+        let need = record_len(len);
+        if need > ring.capacity() || len >= PAD as usize {
+            ring.dropped.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let to_end = ring.to_end(tail);
+        if to_end < need {
+            if !self.make_room(to_end) {
+                ring.dropped.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            ring.write_header(tail, PAD);
+            ring.tail.store(tail + to_end, Ordering::Release);
+        }
+
+        if !self.make_room(need) {
+            ring.dropped.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let start = ring.tail.load(Ordering::Relaxed);
+        ring.write_header(start, len as u32);
+        Some(Reservation { ring, start, len })
+    }
+
+    /// Encodes a value as a record, returning whether it was written.
+    pub fn write<T: Encoder + ?Sized>(&mut self, value: &T) -> bool {
+        let mut reservation = match self.reserve(value.logpack_sizer()) {
+            Some(reservation) => reservation,
+            None => return false,
+        };
+        if value.logpack_encode(&mut BufEncoder::new(reservation.buf())).is_err() {
+            return false;
+        }
+        reservation.commit();
+        true
+    }
+
+    /// How many records this ring dropped.
+    pub fn dropped(&self) -> u64 {
+        self.ring.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.ring.closed.store(true, Ordering::Release);
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Rings
+
+struct Shared {
+    rings: Mutex<Vec<Arc<Ring>>>,
+    capacity: usize,
+    overflow: Overflow,
+    /// Records dropped by rings that are gone.
+    dropped: AtomicU64,
+    consumer: Mutex<Option<Thread>>,
+    busy: AtomicBool,
+    stop: AtomicBool,
+    /// Set when the drain thread has exited, whether stopped or panicked.
+    exited: AtomicBool,
+    /// How many threads wait on `room`.
+    waiters: AtomicUsize,
+    waiting: Mutex<()>,
+    /// Signaled by the drain thread when it makes room or goes idle.
+    room: Condvar,
+}
+
+impl Shared {
+    fn wake(&self) {
+        if let Some(consumer) = lock(&self.consumer).as_ref() {
+            consumer.unpark();
+        }
+    }
+
+    /// Whether waiting for the consumer can end, i.e. the drain thread
+    /// hasn't exited and isn't the calling thread.
+    fn can_block(&self) -> bool {
+        if self.exited.load(Ordering::SeqCst) {
+            return false;
+        }
+        let current = thread::current().id();
+        lock(&self.consumer).as_ref().is_none_or(|consumer| consumer.id() != current)
+    }
+
+    /// Waits for the drain thread until `done` returns true, or returns
+    /// false if the drain thread exits first. `can_block` must be checked
+    /// before.
+    fn wait(&self, done: impl Fn() -> bool) -> bool {
+        self.wake();
+        let mut waiting = lock(&self.waiting);
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        // Pairs with the fence in `notify`, so that either `done` sees the
+        // room made or the drain thread sees the waiter.
+        fence(Ordering::SeqCst);
+
+        let result = loop {
+            if done() {
+                break true;
+            }
+            if self.exited.load(Ordering::SeqCst) {
+                break false;
+            }
+            waiting = self.room.wait(waiting).unwrap_or_else(|e| e.into_inner());
+        };
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// Wakes the threads in `wait`, on the drain thread's side.
+    fn notify(&self) {
+        fence(Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _waiting = lock(&self.waiting);
+            self.room.notify_all();
+        }
+    }
+}
+
+/// Marks the drain thread as exited when it returns or unwinds.
+struct Exit<'a>(&'a Shared);
+
+impl<'a> Drop for Exit<'a> {
+    fn drop(&mut self) {
+        self.0.busy.store(false, Ordering::SeqCst);
+        self.0.exited.store(true, Ordering::SeqCst);
+        self.0.notify();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A set of per-thread rings, drained together.
+#[derive(Clone)]
+pub struct Rings {
+    shared: Arc<Shared>,
+}
+
+impl Rings {
+    /// `capacity` is the size in bytes of each thread's ring, rounded up to
+    /// a power of two.
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                rings: Mutex::new(vec![]),
+                capacity,
+                overflow,
+                dropped: AtomicU64::new(0),
+                consumer: Mutex::new(None),
+                busy: AtomicBool::new(false),
+                stop: AtomicBool::new(false),
+                exited: AtomicBool::new(false),
+                waiters: AtomicUsize::new(0),
+                waiting: Mutex::new(()),
+                room: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Adds a ring, returning its producer.
+    pub fn producer(&self) -> Producer {
+        let ring = Arc::new(Ring::new(self.shared.capacity));
+        lock(&self.shared.rings).push(ring.clone());
+        Producer { ring, shared: self.shared.clone() }
+    }
+
+    /// Runs `f` with the calling thread's producer, adding a ring for the
+    /// thread on its first use.
+    pub fn with_producer<R>(&self, f: impl FnOnce(&mut Producer) -> R) -> R {
+        thread_local! {
+            static PRODUCERS: RefCell<Vec<Producer>> = const { RefCell::new(Vec::new()) };
+        }
+
+        let mut f = Some(f);
+        let result = PRODUCERS.try_with(|producers| {
+            let mut producers = producers.try_borrow_mut().ok()?;
+            producers.retain(|p| Arc::strong_count(&p.shared) > 1);
+            let idx = match producers.iter().position(|p| Arc::ptr_eq(&p.shared, &self.shared)) {
+                Some(idx) => idx,
+                None => {
+                    producers.push(self.producer());
+                    producers.len() - 1
+                }
+            };
+            Some((f.take().unwrap())(&mut producers[idx]))
+        });
+
+        match result {
+            Ok(Some(result)) => result,
+            // Called again from within `f`, or while the thread exits.
+            _ => (f.take().unwrap())(&mut self.producer()),
+        }
+    }
+
+    /// Passes every record in the rings to `f`, returning how many there
+    /// were. Rings of producers that are gone are removed once empty.
+    pub fn drain(&self, f: &mut dyn FnMut(&[u8])) -> usize {
+        let rings = lock(&self.shared.rings).clone();
+        let mut buf = vec![];
+        let mut count = 0;
+        let mut removed = false;
+
+        for ring in &rings {
+            while ring.pop(&mut buf) {
+                self.shared.notify();
+                f(&buf);
+                count += 1;
+            }
+            removed |= ring.closed.load(Ordering::Acquire);
+        }
+
+        if removed {
+            let shared = &self.shared;
+            lock(&shared.rings).retain(|ring| {
+                let gone = ring.closed.load(Ordering::Acquire) && ring.is_empty();
+                if gone {
+                    shared.dropped.fetch_add(ring.dropped.load(Ordering::Relaxed), Ordering::Relaxed);
+                }
+                !gone
+            });
+        }
+
+        count
+    }
+
+    /// How many records were dropped by all rings.
+    pub fn dropped(&self) -> u64 {
+        let dropped: u64 = lock(&self.shared.rings).iter()
+            .map(|ring| ring.dropped.load(Ordering::Relaxed))
+            .sum();
+        dropped + self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    fn is_empty(&self) -> bool {
+        lock(&self.shared.rings).iter().all(|ring| ring.is_empty())
+    }
+
+    /// Starts a thread that drains the rings into `f`, until stopped.
+    pub fn spawn<F>(&self, mut f: F) -> Drainer
+        where F: FnMut(&[u8]) + Send + 'static
+    {
+        let rings = self.clone();
+        self.shared.exited.store(false, Ordering::SeqCst);
+        let handle = thread::Builder::new()
+            .name("logpack-drain".to_owned())
+            .spawn(move || {
+                let shared = &rings.shared;
+                let _exit = Exit(shared);
+                loop {
+                    shared.busy.store(true, Ordering::SeqCst);
+                    let count = rings.drain(&mut f);
+                    shared.busy.store(false, Ordering::SeqCst);
+                    shared.notify();
+
+                    if count == 0 {
+                        if shared.stop.load(Ordering::SeqCst) {
+                            rings.drain(&mut f);
+                            break;
+                        }
+                        thread::park_timeout(Duration::from_millis(1));
+                    }
+                }
+            })
+            .expect("spawning the drain thread");
+
+        *lock(&self.shared.consumer) = Some(handle.thread().clone());
+        Drainer { rings: self.clone(), handle: Some(handle) }
+    }
+
+    /// Waits until the drain thread has handled all records committed so
+    /// far. Returns at once if it has exited, or is the calling thread.
+    pub fn flush(&self) {
+        if lock(&self.shared.consumer).is_none() || !self.shared.can_block() {
+            return;
+        }
+
+        self.shared.wait(|| self.is_empty() && !self.shared.busy.load(Ordering::SeqCst));
+    }
+}
+
+/// The drain thread started by `Rings::spawn`. Dropping it stops the thread
+/// after a last drain.
+pub struct Drainer {
+    rings: Rings,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drainer {
+    pub fn stop(self) {}
+}
+
+impl Drop for Drainer {
+    fn drop(&mut self) {
+        let shared = &self.rings.shared;
+        shared.stop.store(true, Ordering::SeqCst);
+        shared.wake();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        *lock(&shared.consumer) = None;
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Sink
+
+/// A `log!` sink that hands records over to a drain thread through
+/// per-thread rings, which passes them on to another sink. A record holds
+/// the callsite id, followed by the encoded envelope and arguments.
+///
+/// The inner sink must not log through the same `RingSink`, as the drain
+/// thread would then wait on itself. Under `Overflow::Block` such records
+/// are dropped once the ring is full, and counted in `dropped`.
+pub struct RingSink {
+    rings: Rings,
+    inner: &'static dyn Sink,
+    _drainer: Drainer,
+}
+
+impl RingSink {
+    pub fn new(inner: &'static dyn Sink, overflow: Overflow) -> Self {
+        Self::with_capacity(inner, DEFAULT_CAPACITY, overflow)
+    }
+
+    pub fn with_capacity(inner: &'static dyn Sink, capacity: usize, overflow: Overflow) -> Self {
+        let rings = Rings::new(capacity, overflow);
+        let drainer = rings.spawn(move |record| {
+            let mut buf = BufDecoder::new(record);
+            let id = match buf.get::<u32>() {
+                Ok(id) => id,
+                Err(_) => return,
+            };
+            if let (Some(callsite), Ok(envelope)) = (callsite::lookup(id), Envelope::decode(&mut buf)) {
+                inner.log(callsite, &envelope, &record[record.len() - buf.remaining()..]);
+            }
+        });
+
+        Self { rings, inner, _drainer: drainer }
+    }
+
+    pub fn rings(&self) -> &Rings {
+        &self.rings
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.rings.dropped()
+    }
+}
+
+impl Sink for RingSink {
+    fn enabled(&self, level: Level) -> bool {
+        self.inner.enabled(level)
+    }
+
+    fn log(&self, callsite: &'static CallsiteInfo, envelope: &Envelope, args: &[u8]) {
+        let len = callsite.id.logpack_sizer() + envelope.logpack_sizer() + args.len();
+        self.rings.with_producer(|producer| {
+            if let Some(mut reservation) = producer.reserve(len) {
+                let (header, tail) = reservation.buf().split_at_mut(len - args.len());
+                tail.copy_from_slice(args);
+                let mut buf = BufEncoder::new(header);
+                let encoded = callsite.id.logpack_encode(&mut buf)
+                    .and_then(|_| envelope.logpack_encode(&mut buf));
+                if encoded.is_ok() {
+                    reservation.commit();
+                }
+            }
+        });
+    }
+
+    fn flush(&self) {
+        self.rings.flush();
+        self.inner.flush();
+    }
+}
@@ -0,0 +1,272 @@
+use super::file::{self, FileReader, Metadata};
+use super::framing::FrameWriter;
+use super::stream::{is_definition, RecordSink};
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Rotating log files
+//
+// A stream written to a series of log files, the segments, named
+// `<prefix>.<seq>.logpack` in a directory. The stream moves on to a new
+// segment when the current one grows past a size, after a time interval, or
+// on demand. Every segment starts with the definitions written to the stream
+// so far, so that it can be read on its own.
+//
+// A segment is finished with its index when closed, after which it may be
+// compressed to `<prefix>.<seq>.logpack.zst`, and the oldest segments are
+// removed according to the retention policy. Failing to do so doesn't stop
+// the stream, which goes on in the new segment, and is reported apart from
+// writing, by `take_errors`.
+
+pub const EXTENSION: &str = "logpack";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Compression {
+    /// zstd at the given level. Requires the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+pub struct RotatingFile {
+    dir: PathBuf,
+    prefix: String,
+    frames: FrameWriter<BufWriter<File>>,
+    seq: u64,
+    opened: Instant,
+    max_size: Option<u64>,
+    interval: Option<Duration>,
+    keep: Option<usize>,
+    max_age: Option<Duration>,
+    compression: Option<Compression>,
+    /// The definitions written so far, repeated at the start of each segment.
+    definitions: Vec<(u8, u32, Vec<u8>)>,
+    /// Errors closing segments and applying retention, since the last
+    /// `take_errors`.
+    errors: Vec<io::Error>,
+}
+
+impl RotatingFile {
+    /// Starts a new segment in `dir`, numbered after the existing ones.
+    pub fn create(dir: &Path, prefix: &str) -> io::Result<Self> {
+        let seq = segments(dir, prefix)?.last().map(|segment| segment.seq + 1).unwrap_or(0);
+        let frames = open(&segment_path(dir, prefix, seq))?;
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            prefix: prefix.to_owned(),
+            frames,
+            seq,
+            opened: Instant::now(),
+            max_size: None,
+            interval: None,
+            keep: None,
+            max_age: None,
+            compression: None,
+            definitions: vec![],
+            errors: vec![],
+        })
+    }
+
+    /// Rotates once a segment has reached `max_size` bytes.
+    pub fn with_max_size(self, max_size: u64) -> Self {
+        Self { max_size: Some(max_size), ..self }
+    }
+
+    /// Rotates once a segment has been open for `interval`.
+    pub fn with_interval(self, interval: Duration) -> Self {
+        Self { interval: Some(interval), ..self }
+    }
+
+    /// Keeps at most `keep` segments, counting the current one.
+    pub fn with_keep(self, keep: usize) -> Self {
+        Self { keep: Some(keep.max(1)), ..self }
+    }
+
+    /// Removes closed segments last modified longer than `max_age` ago.
+    pub fn with_max_age(self, max_age: Duration) -> Self {
+        Self { max_age: Some(max_age), ..self }
+    }
+
+    pub fn with_compression(self, compression: Compression) -> Self {
+        Self { compression: Some(compression), ..self }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        segment_path(&self.dir, &self.prefix, self.seq)
+    }
+
+    fn is_due(&self) -> bool {
+        self.max_size.is_some_and(|max| self.frames.position() >= max)
+            || self.interval.is_some_and(|interval| self.opened.elapsed() >= interval)
+    }
+
+    /// Closes the current segment and continues in a new one. Returns an
+    /// error only if the new segment couldn't be started, leaving the
+    /// current one in place.
+    pub fn rotate(&mut self) -> io::Result<()> {
+        let mut frames = open(&segment_path(&self.dir, &self.prefix, self.seq + 1))?;
+        for (kind, type_index, payload) in &self.definitions {
+            frames.put_record(*kind, *type_index, payload)?;
+        }
+
+        let closed = self.path();
+        let frames = std::mem::replace(&mut self.frames, frames);
+        self.seq += 1;
+        self.opened = Instant::now();
+
+        let compression = self.compression;
+        let result = file::finish(frames).and_then(|_| match compression {
+            Some(compression) => compress(&closed, compression),
+            None => Ok(()),
+        });
+        self.errors.extend(result.err());
+        if let Err(err) = self.apply_retention() {
+            self.errors.push(err);
+        }
+        Ok(())
+    }
+
+    /// The errors finishing, compressing or removing closed segments since
+    /// the last call.
+    pub fn take_errors(&mut self) -> Vec<io::Error> {
+        std::mem::take(&mut self.errors)
+    }
+
+    fn apply_retention(&self) -> io::Result<()> {
+        let closed: Vec<_> = segments(&self.dir, &self.prefix)?.into_iter()
+            .filter(|segment| segment.seq < self.seq)
+            .collect();
+
+        let excess = self.keep.map(|keep| (closed.len() + 1).saturating_sub(keep)).unwrap_or(0);
+        let now = SystemTime::now();
+        for (idx, segment) in closed.iter().enumerate() {
+            let expired = match self.max_age {
+                Some(max_age) => fs::metadata(&segment.path)?.modified()?
+                    .checked_add(max_age).is_some_and(|until| until < now),
+                None => false,
+            };
+            if idx < excess || expired {
+                fs::remove_file(&segment.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finishes the current segment, leaving it uncompressed.
+    pub fn finish(self) -> io::Result<()> {
+        file::finish(self.frames).map(|_| ())
+    }
+}
+
+impl RecordSink for RotatingFile {
+    fn put_record(&mut self, kind: u8, type_index: u32, payload: &[u8]) -> io::Result<()> {
+        if self.is_due() {
+            self.rotate()?;
+        }
+
+        if is_definition(kind) {
+            self.definitions.push((kind, type_index, payload.to_owned()));
+        }
+        self.frames.put_record(kind, type_index, payload)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.frames.flush()
+    }
+
+    fn entry_time(&mut self, time: u64) {
+        self.frames.entry_time(time)
+    }
+}
+
+fn open(path: &Path) -> io::Result<FrameWriter<BufWriter<File>>> {
+    let out = BufWriter::new(File::create(path)?);
+    file::frame_writer(out, &Metadata::current())
+}
+
+fn segment_path(dir: &Path, prefix: &str, seq: u64) -> PathBuf {
+    dir.join(format!("{}.{:06}.{}", prefix, seq, EXTENSION))
+}
+
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+fn compress(path: &Path, compression: Compression) -> io::Result<()> {
+    match compression {
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(level) => {
+            let mut target = path.as_os_str().to_owned();
+            target.push(".zst");
+            let partial = path.with_extension("zst-partial");
+
+            zstd::stream::copy_encode(File::open(path)?, File::create(&partial)?, level)?;
+            fs::rename(&partial, target)?;
+            fs::remove_file(path)
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Reading segments
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Segment {
+    pub seq: u64,
+    pub path: PathBuf,
+    pub compressed: bool,
+}
+
+/// The segments of `prefix` in `dir`, oldest first.
+pub fn segments(dir: &Path, prefix: &str) -> io::Result<Vec<Segment>> {
+    let mut segments = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let rest = match name.strip_prefix(prefix).and_then(|rest| rest.strip_prefix('.')) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let (seq, suffix) = match rest.split_once('.') {
+            Some(split) => split,
+            None => continue,
+        };
+        let compressed = match suffix.strip_prefix(EXTENSION) {
+            Some("") => false,
+            Some(".zst") => true,
+            _ => continue,
+        };
+        if let Ok(seq) = seq.parse() {
+            segments.push(Segment { seq, path, compressed });
+        }
+    }
+
+    segments.sort_by_key(|segment| segment.seq);
+    Ok(segments)
+}
+
+/// Opens a segment, decompressing it if needed.
+pub fn open_segment(segment: &Segment) -> Result<FileReader<Box<dyn BufRead>>, file::Error> {
+    let input = File::open(&segment.path)?;
+    let input: Box<dyn BufRead> = if segment.compressed {
+        decompress(input)?
+    } else {
+        Box::new(BufReader::new(input))
+    };
+    FileReader::new(input)
+}
+
+#[cfg(feature = "zstd")]
+fn decompress(input: File) -> io::Result<Box<dyn BufRead>> {
+    Ok(Box::new(BufReader::new(zstd::stream::Decoder::new(input)?)))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress(_input: File) -> io::Result<Box<dyn BufRead>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "reading zstd segments requires the `zstd` feature"))
+}
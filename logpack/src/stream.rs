@@ -0,0 +1,535 @@
+use super::{Description, Logpack, Encoder, RefDesc, SeenTypes};
+use super::buffers::{BufEncoder, BufDecoder};
+use super::callsite::CallsiteInfo;
+use super::compact;
+use super::envelope::Envelope;
+use super::framing::MAX_FRAME_LEN;
+use super::decoder::{self, decode_stored_string, Callbacks, Decoder, FeedError, NameMap, ResolvedDesc};
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Self-describing streams
+//
+// A stream is a sequence of records, each with a kind, a type index and a
+// payload. The first time a type is written, a type definition record
+// precedes its value, so a reader can rebuild the `NameMap` as it goes and
+// needs nothing but the stream itself:
+//
+// * `RECORD_TYPE_DEF`: a `compact` schema block holding the description of
+//   the type with the record's index. Named types that earlier definitions
+//   already carried are only referred to by name.
+//
+// * `RECORD_VALUE`: a value of the type with the record's index, as written
+//   by `Encoder`.
+//
+// * `RECORD_SCHEMA`: a `compact` schema block of named type definitions,
+//   added to the reader's `NameMap` without being bound to a type index.
+//
+// * `RECORD_ENTRY`: a log entry, i.e. an `Envelope` followed by a value of
+//   the type with the record's index.
+//
+// * `RECORD_CALLSITE`: a `log!` callsite, i.e. its `u32` id and line,
+//   followed by its module, file and format string. The record's index is
+//   the type of its arguments, which entries from the callsite carry as
+//   their value.
+//
+// How records are delimited is up to the `RecordSink` and `RecordSource`
+// implementations. `Framed` is the simplest one, prefixing every record with
+// its kind, type index and length, while `framing` adds checksums.
+
+pub const RECORD_TYPE_DEF: u8 = 1;
+pub const RECORD_VALUE: u8 = 2;
+pub const RECORD_SCHEMA: u8 = 3;
+pub const RECORD_ENTRY: u8 = 4;
+pub const RECORD_CALLSITE: u8 = 5;
+
+/// Whether records of the kind define something for the records after them,
+/// rather than holding a value.
+pub fn is_definition(kind: u8) -> bool {
+    kind == RECORD_TYPE_DEF || kind == RECORD_SCHEMA || kind == RECORD_CALLSITE
+}
+
+pub trait RecordSink {
+    fn put_record(&mut self, kind: u8, type_index: u32, payload: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// The offset in the file at which the next record goes, for sinks that
+    /// know it.
+    fn offset(&self) -> Option<u64> {
+        None
+    }
+
+    /// Notes the time of an entry written inside another record, e.g. in a
+    /// compressed block, for sinks that index entries by time.
+    fn entry_time(&mut self, _time: u64) {}
+}
+
+pub trait RecordSource {
+    /// Reads the next record into `payload`, returning its kind and type
+    /// index, or `None` at the end of the stream.
+    fn next_record(&mut self, payload: &mut Vec<u8>) -> io::Result<Option<(u8, u32)>>;
+}
+
+impl<S: RecordSource + ?Sized> RecordSource for Box<S> {
+    fn next_record(&mut self, payload: &mut Vec<u8>) -> io::Result<Option<(u8, u32)>> {
+        (**self).next_record(payload)
+    }
+}
+
+/// Delimits records with a kind byte, and little-endian `u32` type index and
+/// length.
+pub struct Framed<T>(pub T);
+
+impl<W: Write> RecordSink for Framed<W> {
+    fn put_record(&mut self, kind: u8, type_index: u32, payload: &[u8]) -> io::Result<()> {
+        let mut header = [0u8; 9];
+        header[0] = kind;
+        header[1..5].copy_from_slice(&type_index.to_le_bytes());
+        header[5..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.0.write_all(&header)?;
+        self.0.write_all(payload)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<R: Read> RecordSource for Framed<R> {
+    fn next_record(&mut self, payload: &mut Vec<u8>) -> io::Result<Option<(u8, u32)>> {
+        let mut header = [0u8; 9];
+        let mut read = 0;
+        while read < header.len() {
+            match self.0.read(&mut header[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut type_index = [0u8; 4];
+        type_index.copy_from_slice(&header[1..5]);
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[5..]);
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "record longer than MAX_FRAME_LEN"));
+        }
+        payload.resize(len, 0);
+        self.0.read_exact(payload)?;
+        Ok(Some((header[0], u32::from_le_bytes(type_index))))
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Writer
+
+pub struct StreamWriter<W: RecordSink> {
+    sink: W,
+    seen: SeenTypes,
+    types: HashMap<&'static str, u32>,
+    /// Callsite ids already defined, with the type index of their arguments.
+    callsites: HashMap<u32, u32>,
+    buf: Vec<u8>,
+}
+
+impl<W: RecordSink> StreamWriter<W> {
+    pub fn new(sink: W) -> Self {
+        Self::with_seen_types(sink, SeenTypes::new())
+    }
+
+    /// Allows choosing how types are identified, e.g. with
+    /// `SeenTypes::with_content_ids()`.
+    pub fn with_seen_types(sink: W, seen: SeenTypes) -> Self {
+        Self {
+            sink,
+            seen,
+            types: HashMap::new(),
+            callsites: HashMap::new(),
+            buf: vec![],
+        }
+    }
+
+    /// Returns the index under which values of `T` are written, emitting the
+    /// type's definition first if this is its first use.
+    pub fn register<T: Logpack + ?Sized>(&mut self) -> io::Result<u32> {
+        self.register_with(std::any::type_name::<T>(), T::logpack_describe)
+    }
+
+    /// Like `register`, for a type known only by a unique key, such as its
+    /// `std::any::type_name`, and a function describing it.
+    pub fn register_with(&mut self, type_key: &'static str,
+                         describe: impl FnOnce(&mut SeenTypes) -> RefDesc) -> io::Result<u32>
+    {
+        if let Some(index) = self.types.get(type_key) {
+            return Ok(*index);
+        }
+
+        let index = self.types.len() as u32;
+        let desc = describe(&mut self.seen);
+        let block_size = compact::encoded_size(std::slice::from_ref(&desc));
+
+        self.buf.clear();
+        self.buf.resize(block_size, 0);
+        compact::encode(std::slice::from_ref(&desc), &mut BufEncoder::new(&mut self.buf))
+            .map_err(encode_error)?;
+        self.sink.put_record(RECORD_TYPE_DEF, index, &self.buf)?;
+
+        self.types.insert(type_key, index);
+        Ok(index)
+    }
+
+    /// Writes definitions known up front, e.g. loaded from a `registry` file.
+    pub fn write_schema(&mut self, names: &NameMap) -> io::Result<()> {
+        let mut defs: Vec<ResolvedDesc> = names.get_map().iter()
+            .map(|(id, named)| Description::ByName(id.clone(), Some(named.clone())))
+            .collect();
+        defs.sort_by(|a, b| match (a, b) {
+            (Description::ByName(a, _), Description::ByName(b, _)) => a.cmp(b),
+            _ => std::cmp::Ordering::Equal,
+        });
+
+        self.buf.clear();
+        self.buf.resize(compact::encoded_size(&defs), 0);
+        compact::encode(&defs, &mut BufEncoder::new(&mut self.buf)).map_err(encode_error)?;
+        self.sink.put_record(RECORD_SCHEMA, 0, &self.buf)
+    }
+
+    pub fn write<T: Logpack + Encoder + ?Sized>(&mut self, value: &T) -> io::Result<()> {
+        let index = self.register::<T>()?;
+
+        self.buf.clear();
+        self.buf.resize(value.logpack_sizer(), 0);
+        value.logpack_encode(&mut BufEncoder::new(&mut self.buf)).map_err(encode_error)?;
+        self.sink.put_record(RECORD_VALUE, index, &self.buf)
+    }
+
+    /// Writes a value as a log entry. The envelope's payload type is set to
+    /// the value's type index.
+    pub fn write_entry<T>(&mut self, envelope: &Envelope, value: &T) -> io::Result<()>
+        where T: Logpack + Encoder + ?Sized
+    {
+        let index = self.register::<T>()?;
+        let envelope = Envelope { payload_type: index, ..*envelope };
+
+        let envelope_size = envelope.logpack_sizer();
+        self.buf.clear();
+        self.buf.resize(envelope_size + value.logpack_sizer(), 0);
+        envelope.logpack_encode(&mut BufEncoder::new(&mut self.buf[..envelope_size]))
+            .map_err(encode_error)?;
+        value.logpack_encode(&mut BufEncoder::new(&mut self.buf[envelope_size..]))
+            .map_err(encode_error)?;
+        self.sink.put_record(RECORD_ENTRY, index, &self.buf)
+    }
+
+    /// Returns the type index of the callsite's arguments, emitting the
+    /// callsite's definition first if this is its first use.
+    pub fn define_callsite(&mut self, callsite: &CallsiteInfo) -> io::Result<u32> {
+        if let Some(index) = self.callsites.get(&callsite.id) {
+            return Ok(*index);
+        }
+
+        let index = self.register_with(callsite.args_key, callsite.describe)?;
+        let fields = (callsite.id, callsite.line, callsite.module, callsite.file, callsite.format);
+
+        self.buf.clear();
+        self.buf.resize(fields.logpack_sizer(), 0);
+        fields.logpack_encode(&mut BufEncoder::new(&mut self.buf)).map_err(encode_error)?;
+        self.sink.put_record(RECORD_CALLSITE, index, &self.buf)?;
+
+        self.callsites.insert(callsite.id, index);
+        Ok(index)
+    }
+
+    /// Writes a log entry from a callsite, with its arguments already encoded.
+    pub fn write_logged(&mut self, callsite: &CallsiteInfo, envelope: &Envelope,
+                        args: &[u8]) -> io::Result<()>
+    {
+        let index = self.define_callsite(callsite)?;
+        self.write_entry_bytes(&Envelope { callsite: callsite.id, ..*envelope }, index, args)
+    }
+
+    /// Writes a log entry whose value is already encoded, as a value of the
+    /// type with the given index.
+    pub fn write_entry_bytes(&mut self, envelope: &Envelope, type_index: u32,
+                             value: &[u8]) -> io::Result<()>
+    {
+        let envelope = Envelope { payload_type: type_index, ..*envelope };
+
+        let envelope_size = envelope.logpack_sizer();
+        self.buf.clear();
+        self.buf.resize(envelope_size, 0);
+        envelope.logpack_encode(&mut BufEncoder::new(&mut self.buf)).map_err(encode_error)?;
+        self.buf.extend_from_slice(value);
+        self.sink.put_record(RECORD_ENTRY, type_index, &self.buf)
+    }
+
+    /// Forgets all types, so that each is defined again on its next use, as
+    /// needed when continuing into a new file.
+    pub fn reset_types(&mut self) {
+        self.seen = if self.seen.has_content_ids() {
+            SeenTypes::with_content_ids()
+        } else {
+            SeenTypes::new()
+        };
+        self.types.clear();
+        self.callsites.clear();
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.sink
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+
+    pub fn into_inner(self) -> W {
+        self.sink
+    }
+}
+
+fn encode_error((remaining, size): (usize, usize)) -> io::Error {
+    io::Error::other(format!("encoded size mismatch: {} bytes left for {}", remaining, size))
+}
+
+//////////////////////////////////////////////////////////////////////////
+// Reader
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Schema(compact::Error),
+    Feed(FeedError),
+    Envelope(decoder::Error),
+    Callsite(decoder::Error),
+    /// A value of a type index that was never defined.
+    UnknownType(u32),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub enum Record<'a> {
+    Value(Value<'a>),
+    /// A record of a kind this reader doesn't know, left to the caller, with
+    /// its type index and payload.
+    Other(u8, u32, &'a [u8]),
+}
+
+/// A `log!` callsite, as defined in a stream.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CallsiteDef {
+    pub id: u32,
+    pub module: String,
+    pub file: String,
+    pub line: u32,
+    pub format: String,
+    pub args_type: u32,
+}
+
+impl CallsiteDef {
+    fn decode(buf: &mut BufDecoder, args_type: u32) -> Result<Self, decoder::Error> {
+        let id = buf.get::<u32>().map_err(decoder::Error::GetError)?;
+        let line = buf.get::<u32>().map_err(decoder::Error::GetError)?;
+        let module = decode_stored_string(buf)?.to_owned();
+        let file = decode_stored_string(buf)?.to_owned();
+        let format = decode_stored_string(buf)?.to_owned();
+
+        Ok(Self { id, module, file, line, format, args_type })
+    }
+
+    /// The module and line, e.g. `app::net:88`.
+    pub fn location(&self) -> String {
+        format!("{}:{}", self.module, self.line)
+    }
+}
+
+pub struct Value<'a> {
+    pub type_index: u32,
+    /// Present for values written as log entries.
+    pub envelope: Option<Envelope<'a>>,
+    /// Present for entries written by `log!`, whose value is the tuple of
+    /// the arguments to the callsite's format string.
+    pub callsite: Option<&'a CallsiteDef>,
+    pub desc: &'a ResolvedDesc,
+    pub names: &'a NameMap,
+    pub data: &'a [u8],
+}
+
+impl<'a> Value<'a> {
+    pub fn decode<C: Callbacks>(&self, callbacks: &mut C) -> Result<(), decoder::Error> {
+        let mut decoder = Decoder::new(self.names, BufDecoder::new(self.data));
+        decoder.decode(self.desc, callbacks)
+    }
+}
+
+pub struct StreamReader<R: RecordSource> {
+    source: R,
+    names: NameMap,
+    types: HashMap<u32, ResolvedDesc>,
+    callsites: HashMap<u32, CallsiteDef>,
+    buf: Vec<u8>,
+}
+
+impl<R: RecordSource> StreamReader<R> {
+    pub fn new(source: R) -> Self {
+        Self::with_name_map(source, NameMap::new())
+    }
+
+    /// Starts from known types, e.g. ones loaded from a `registry` file.
+    pub fn with_name_map(source: R, names: NameMap) -> Self {
+        Self {
+            source,
+            names,
+            types: HashMap::new(),
+            callsites: HashMap::new(),
+            buf: vec![],
+        }
+    }
+
+    pub fn name_map(&self) -> &NameMap {
+        &self.names
+    }
+
+    pub fn type_desc(&self, index: u32) -> Option<&ResolvedDesc> {
+        self.types.get(&index)
+    }
+
+    pub fn callsite(&self, id: u32) -> Option<&CallsiteDef> {
+        self.callsites.get(&id)
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.source
+    }
+
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Keeps the definitions read so far with the source boxed, e.g. to
+    /// merge streams from sources of different types.
+    pub fn boxed<'a>(self) -> StreamReader<Box<dyn RecordSource + 'a>>
+        where R: 'a
+    {
+        StreamReader {
+            source: Box::new(self.source),
+            names: self.names,
+            types: self.types,
+            callsites: self.callsites,
+            buf: self.buf,
+        }
+    }
+
+    fn define(&mut self, index: u32) -> Result<(), Error> {
+        let descs = compact::decode(&mut BufDecoder::new(&self.buf)).map_err(Error::Schema)?;
+        for desc in descs {
+            let desc = self.names.feed(desc).map_err(Error::Feed)?;
+            self.types.insert(index, desc);
+        }
+        Ok(())
+    }
+
+    fn add_schema(&mut self) -> Result<(), Error> {
+        let descs = compact::decode(&mut BufDecoder::new(&self.buf)).map_err(Error::Schema)?;
+        for desc in descs {
+            self.names.feed(desc).map_err(Error::Feed)?;
+        }
+        Ok(())
+    }
+
+    fn add_callsite(&mut self, index: u32) -> Result<(), Error> {
+        let callsite = CallsiteDef::decode(&mut BufDecoder::new(&self.buf), index)
+            .map_err(Error::Callsite)?;
+        self.callsites.insert(callsite.id, callsite);
+        Ok(())
+    }
+
+    /// Applies a definition read from elsewhere than the source, e.g. when
+    /// seeking past it.
+    pub fn apply_definition(&mut self, kind: u8, type_index: u32, payload: &[u8]) -> Result<(), Error> {
+        self.buf.clear();
+        self.buf.extend_from_slice(payload);
+        match kind {
+            RECORD_TYPE_DEF => self.define(type_index),
+            RECORD_SCHEMA => self.add_schema(),
+            RECORD_CALLSITE => self.add_callsite(type_index),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the next record that isn't a type or callsite definition,
+    /// applying the definitions on the way.
+    pub fn next_record(&mut self) -> Result<Option<Record<'_>>, Error> {
+        let (kind, type_index) = loop {
+            match self.source.next_record(&mut self.buf)? {
+                None => return Ok(None),
+                Some((RECORD_TYPE_DEF, index)) => self.define(index)?,
+                Some((RECORD_SCHEMA, _)) => self.add_schema()?,
+                Some((RECORD_CALLSITE, index)) => self.add_callsite(index)?,
+                Some(record) => break record,
+            }
+        };
+
+        if kind != RECORD_VALUE && kind != RECORD_ENTRY {
+            return Ok(Some(Record::Other(kind, type_index, &self.buf)));
+        }
+
+        Ok(Some(Record::Value(self.value(kind, type_index)?)))
+    }
+
+    /// Like `next_record`, skipping records of unknown kinds.
+    pub fn next_value(&mut self) -> Result<Option<Value<'_>>, Error> {
+        let (kind, type_index) = loop {
+            match self.source.next_record(&mut self.buf)? {
+                None => return Ok(None),
+                Some((RECORD_TYPE_DEF, index)) => self.define(index)?,
+                Some((RECORD_SCHEMA, _)) => self.add_schema()?,
+                Some((RECORD_CALLSITE, index)) => self.add_callsite(index)?,
+                Some(record @ (RECORD_VALUE, _)) | Some(record @ (RECORD_ENTRY, _)) => break record,
+                Some(_) => {}
+            }
+        };
+
+        Ok(Some(self.value(kind, type_index)?))
+    }
+
+    pub(crate) fn value(&self, kind: u8, type_index: u32) -> Result<Value<'_>, Error> {
+        let desc = self.types.get(&type_index).ok_or(Error::UnknownType(type_index))?;
+
+        let mut buf = BufDecoder::new(&self.buf);
+        let envelope = if kind == RECORD_ENTRY {
+            Some(Envelope::decode(&mut buf).map_err(Error::Envelope)?)
+        } else {
+            None
+        };
+
+        let callsite = envelope.and_then(|e| self.callsites.get(&e.callsite));
+
+        Ok(Value {
+            type_index,
+            envelope,
+            callsite,
+            desc,
+            names: &self.names,
+            data: &self.buf[self.buf.len() - buf.remaining()..],
+        })
+    }
+}
@@ -0,0 +1,216 @@
+use super::Description;
+use super::Named;
+use super::Struct;
+use super::decoder::{NameMap, TypeNameId, ResolvedDesc};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+//////////////////////////////////////////////////////////////////////////
+//
+// Schema validation
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    /// A `ByName` reference to a type that has no definition. The referring
+    /// type is `None` when the reference is made by the validated description
+    /// itself.
+    Missing {
+        name: TypeNameId,
+        referenced_from: Option<TypeNameId>,
+    },
+
+    /// Types that contain each other with no `Option`, slice, `Result` or
+    /// multi-variant enum in between, so that no finite value of them exists.
+    InfiniteCycle(Vec<TypeNameId>),
+}
+
+type Defs<'a> = BTreeMap<&'a TypeNameId, &'a Named<TypeNameId>>;
+
+/// A type reference, and whether every value of the referring type must
+/// contain a value of the referred one.
+type Edge<'a> = (&'a TypeNameId, bool);
+
+impl NameMap {
+    /// Checks that `desc` can be decoded against this map without running
+    /// into missing types halfway through a record. All problems are returned
+    /// at once, covering both `desc` and every definition in the map.
+    pub fn validate(&self, desc: &ResolvedDesc) -> Result<(), Vec<ValidationError>> {
+        let mut defs: Defs = self.get_map().iter().collect();
+        inline_defs(desc, &mut defs);
+
+        let mut errors = vec![];
+
+        let mut root_refs = vec![];
+        desc_refs(desc, true, &mut root_refs);
+        let mut reported = BTreeSet::new();
+        for (name, _) in root_refs {
+            if !defs.contains_key(name) && reported.insert(name) {
+                errors.push(ValidationError::Missing {
+                    name: name.clone(),
+                    referenced_from: None,
+                });
+            }
+        }
+
+        let mut graph = BTreeMap::new();
+        for (from, named) in defs.iter() {
+            let mut edges = vec![];
+            named_refs(named, true, &mut edges);
+
+            let mut reported = BTreeSet::new();
+            for (name, _) in edges.iter() {
+                if !defs.contains_key(name) && reported.insert(*name) {
+                    errors.push(ValidationError::Missing {
+                        name: (*name).clone(),
+                        referenced_from: Some((*from).clone()),
+                    });
+                }
+            }
+
+            graph.insert(*from, edges);
+        }
+
+        find_cycles(&graph, &mut errors);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn inline_defs<'a>(desc: &'a ResolvedDesc, defs: &mut Defs<'a>) {
+    use Description::*;
+
+    match desc {
+        Option(sub) | Slice(sub) | Array(_, sub) => inline_defs(sub, defs),
+        Result(ok, err) => {
+            inline_defs(ok, defs);
+            inline_defs(err, defs);
+        }
+        Tuple(subs) => {
+            for sub in subs {
+                inline_defs(sub, defs);
+            }
+        }
+        ByName(name, Some(named)) => {
+            defs.insert(name, named);
+            let structs: Vec<&Struct<TypeNameId>> = match named {
+                Named::Enum(variants) => variants.iter().map(|(_, s)| s).collect(),
+                Named::Struct(s) => vec![s],
+            };
+            for s in structs {
+                match s {
+                    Struct::Unit => {}
+                    Struct::Tuple(fields) => {
+                        for field in fields {
+                            inline_defs(field, defs);
+                        }
+                    }
+                    Struct::Named(fields) => {
+                        for (_, field) in fields {
+                            inline_defs(field, defs);
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn desc_refs<'a>(desc: &'a ResolvedDesc, must: bool, out: &mut Vec<Edge<'a>>) {
+    use Description::*;
+
+    match desc {
+        Option(sub) | Slice(sub) => desc_refs(sub, false, out),
+        Array(size, sub) => desc_refs(sub, must && *size > 0, out),
+        Result(ok, err) => {
+            desc_refs(ok, false, out);
+            desc_refs(err, false, out);
+        }
+        Tuple(subs) => {
+            for sub in subs {
+                desc_refs(sub, must, out);
+            }
+        }
+        ByName(name, _) => out.push((name, must)),
+        _ => {}
+    }
+}
+
+fn named_refs<'a>(named: &'a Named<TypeNameId>, must: bool, out: &mut Vec<Edge<'a>>) {
+    match named {
+        Named::Enum(variants) => {
+            let must = must && variants.len() == 1;
+            for (_, s) in variants {
+                struct_refs(s, must, out);
+            }
+        }
+        Named::Struct(s) => struct_refs(s, must, out),
+    }
+}
+
+fn struct_refs<'a>(s: &'a Struct<TypeNameId>, must: bool, out: &mut Vec<Edge<'a>>) {
+    match s {
+        Struct::Unit => {}
+        Struct::Tuple(fields) => {
+            for field in fields {
+                desc_refs(field, must, out);
+            }
+        }
+        Struct::Named(fields) => {
+            for (_, field) in fields {
+                desc_refs(field, must, out);
+            }
+        }
+    }
+}
+
+/// Reports every cycle formed by edges that are always taken. Each cycle is
+/// reported once, starting from the type that the search entered it by.
+fn find_cycles(graph: &BTreeMap<&TypeNameId, Vec<Edge>>, errors: &mut Vec<ValidationError>) {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Mark { Unvisited, InProgress, Done }
+
+    fn visit<'a>(node: &'a TypeNameId,
+                 graph: &BTreeMap<&'a TypeNameId, Vec<Edge<'a>>>,
+                 marks: &mut BTreeMap<&'a TypeNameId, Mark>,
+                 stack: &mut Vec<&'a TypeNameId>,
+                 errors: &mut Vec<ValidationError>)
+    {
+        marks.insert(node, Mark::InProgress);
+        stack.push(node);
+
+        for (next, must) in graph.get(node).into_iter().flatten() {
+            if !*must {
+                continue;
+            }
+            match marks.get(next).cloned().unwrap_or(Mark::Unvisited) {
+                Mark::Unvisited => {
+                    if graph.contains_key(next) {
+                        visit(next, graph, marks, stack, errors);
+                    }
+                }
+                Mark::InProgress => {
+                    let start = stack.iter().position(|x| x == next).unwrap();
+                    let cycle = stack[start..].iter().map(|x| (*x).clone()).collect();
+                    errors.push(ValidationError::InfiniteCycle(cycle));
+                }
+                Mark::Done => {}
+            }
+        }
+
+        stack.pop();
+        marks.insert(node, Mark::Done);
+    }
+
+    let mut marks = BTreeMap::new();
+    let mut stack = vec![];
+    for node in graph.keys() {
+        if marks.get(node).cloned().unwrap_or(Mark::Unvisited) == Mark::Unvisited {
+            visit(node, graph, &mut marks, &mut stack, errors);
+        }
+    }
+}
@@ -149,6 +149,23 @@ fn test_ser_only<E>(st: &mut logpack::SeenTypes,
     assert_eq!(encoded.len(), sizer_result);
 }
 
+/// Renders a value read back from a stream, as RON.
+fn repr(value: &logpack::stream::Value) -> String
+{
+    let mut output = String::new();
+    value.decode(&mut logpack_ron::Repr::new(&mut output)).unwrap();
+    output
+}
+
+/// The number in a `SimpleStructTuple` read back from a stream, or `None` for
+/// values of other types.
+fn tuple_number(value: &logpack::stream::Value) -> Option<u32>
+{
+    let output = repr(value);
+    let rest = output.strip_prefix("SimpleStructTuple(")?;
+    Some(rest.split(',').next().unwrap().parse().unwrap())
+}
+
 #[derive(Logpack, Debug)]
 pub struct StaticRecord {
     pub file: &'static str,
@@ -157,6 +174,1437 @@ pub struct StaticRecord {
     pub module: &'static str,
 }
 
+#[derive(Logpack, Debug)]
+pub struct Request<'a> {
+    pub path: &'a str,
+    pub body: &'a [u8],
+}
+
+#[derive(Logpack, Debug)]
+pub enum Borrowed<'a, T> {
+    Ref(&'a T),
+    Pair(&'a str, T),
+}
+
+#[derive(Logpack, Debug)]
+pub struct Tree {
+    value: u32,
+    next: Option<Box<Tree>>,
+}
+
+fn test_content_ids()
+{
+    use logpack::Logpack;
+
+    fn id_of(desc: logpack::RefDesc) -> logpack::TypeNameId {
+        match desc {
+            logpack::Description::ByName(id, _) => id,
+            _ => panic!(),
+        }
+    }
+
+    let mut a = logpack::SeenTypes::with_content_ids();
+    let mut b = logpack::SeenTypes::with_content_ids();
+
+    let _ = SimpleStructTuple::logpack_describe(&mut a);
+    let a_enum = id_of(SimpleEnum::logpack_describe(&mut a));
+    let a_tree = id_of(Tree::logpack_describe(&mut a));
+    let b_tree = id_of(Tree::logpack_describe(&mut b));
+    let b_enum = id_of(SimpleEnum::logpack_describe(&mut b));
+
+    println!("");
+    println!("Content ids: {:?} {:?}", a_enum, a_tree);
+    assert_eq!(a_enum, b_enum);
+    assert_eq!(a_tree, b_tree);
+    assert_ne!(id_of(GenericType::<u32>::logpack_describe(&mut a)),
+               id_of(GenericType::<u64>::logpack_describe(&mut a)));
+
+    // Types named explicitly, as by logpack-tracing, get content ids too.
+    let fields = |seen: &mut logpack::SeenTypes| -> logpack::RefDesc {
+        let (_, id) = seen.make_name_for_id("fields", "fields", &|_| logpack::Description::Unit);
+        logpack::Description::ByName(id, Some(logpack::Named::Struct(logpack::Struct::Named(vec![
+            ("inner", SimpleEnum::logpack_describe(seen)),
+        ]))))
+    };
+    let (_, a_fields) = a.make_name_for_id("fields", "fields", &fields);
+    let (_, b_fields) = b.make_name_for_id("fields", "fields", &fields);
+    assert_eq!(a_fields, b_fields);
+    assert_eq!(a_fields.1, logpack::fingerprint::fingerprint(&fields(&mut logpack::SeenTypes::new())));
+}
+
+fn test_generic_names(tm: &logpack::NameMap)
+{
+    // Each instantiation of a generic type is defined under a name of its
+    // own, without module paths or lifetimes.
+    for name in ["GenericType<u8>", "GenericType<String>", "GenericType<Option<SimpleStructUnit>>"] {
+        let count = tm.get_map().keys().filter(|key| key.0 == name).count();
+        assert_eq!(count, 1, "{}", name);
+    }
+    assert_eq!(logpack::short_type_name("app::Borrowed<'_>"), "Borrowed");
+    assert_eq!(logpack::short_type_name("app::Pair<'_, '_, alloc::string::String>"), "Pair<String>");
+    let name = std::any::type_name::<GenericType<Request<'static>>>();
+    assert_eq!(logpack::short_type_name(name), "GenericType<Request>", "{}", name);
+}
+
+fn test_type_keys()
+{
+    use logpack::Logpack;
+
+    // 'static types are told apart by their `TypeId`, so a type key that
+    // happens to be their name doesn't take their place.
+    let mut seen = logpack::SeenTypes::new();
+    let name = std::any::type_name::<SimpleStructUnit>();
+    let (_, taken) = seen.make_name_for_id("SimpleStructUnit", name, &|_| logpack::Description::Unit);
+    let (first_seen, id) = seen.make_name_for::<SimpleStructUnit>();
+    assert!(first_seen);
+    assert_ne!(id, taken);
+
+    // Borrowed types are keyed by name, with lifetimes erased.
+    let (first_seen, borrowed) = seen.make_name_for_borrowed::<Request<'static>>();
+    assert!(first_seen && borrowed.0 == "Request");
+    match Request::logpack_describe(&mut seen) {
+        logpack::Description::ByName(id, None) => assert_eq!(id, borrowed),
+        other => panic!("expected Request to be seen already, got {:?}", other),
+    }
+}
+
+fn test_conflicts(tm: &mut logpack::NameMap)
+{
+    let same = "ByName((\"SimpleStructNamed\",0),Some(Struct(Named([(\"some_str\",String)]))))";
+    let other = "ByName((\"SimpleStructNamed\",0),Some(Struct(Named([(\"some_str\",U32),(\"extra\",Bool)]))))";
+
+    tm.feed(from_str(same).unwrap()).unwrap();
+    match tm.feed(from_str(other).unwrap()) {
+        Err(logpack::decoder::FeedError::Conflict(name, diffs)) => {
+            println!("");
+            println!("Conflicting definition of {:?}:", name);
+            for diff in diffs.iter() {
+                println!("    {}", diff);
+            }
+            assert_eq!(diffs.len(), 2);
+        }
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+
+    // A conflict of the outer type keeps the new nested one out too.
+    let nested = "ByName((\"SimpleStructNamed\",0),Some(Struct(Named([(\"some_str\",String),\
+                  (\"fresh\",ByName((\"FreshType\",0),Some(Struct(Unit))))]))))";
+    let len = tm.get_map().len();
+    assert!(tm.feed(from_str(nested).unwrap()).is_err());
+    assert_eq!(tm.get_map().len(), len);
+    assert!(!tm.get_map().contains_key(&("FreshType".to_owned(), 0)));
+}
+
+fn test_validate()
+{
+    let mut tm = logpack::NameMap::new();
+    let infinite = "ByName((\"Loop\",0),Some(Struct(Named([(\"inner\",Tuple([U8,ByName((\"Loop\",0),None)]))]))))";
+    let finite = "ByName((\"List\",0),Some(Struct(Named([(\"next\",Option(ByName((\"List\",0),None)))]))))";
+    let dangling = "ByName((\"Holder\",0),Some(Struct(Tuple([ByName((\"Gone\",0),None)]))))";
+
+    let infinite = tm.feed(from_str(infinite).unwrap()).unwrap();
+    let finite_def: logpack::ResolvedDesc = from_str(finite).unwrap();
+    let finite = tm.feed(finite_def.clone()).unwrap();
+    let dangling: logpack::ResolvedDesc = from_str(dangling).unwrap();
+
+    let errors = logpack::NameMap::new().validate(&finite).unwrap_err();
+    println!("");
+    println!("Validation errors for an empty map: {:?}", errors);
+    assert_eq!(errors.len(), 1);
+
+    let errors = tm.validate(&dangling).unwrap_err();
+    println!("Validation errors: {:?}", errors);
+    assert_eq!(errors.len(), 2);
+
+    let mut tm = logpack::NameMap::new();
+    let finite = tm.feed(finite_def).unwrap();
+    assert!(tm.validate(&finite).is_ok());
+    assert!(tm.validate(&infinite).is_err());
+}
+
+#[derive(Logpack, Debug)]
+pub enum Kind {
+    Plain,
+    Tagged(u16),
+}
+
+#[derive(Logpack, Debug)]
+pub struct EventV1 {
+    count: u8,
+    name: String,
+    obsolete: u32,
+    kind: Kind,
+}
+
+fn test_resolve()
+{
+    use logpack::Logpack;
+    use logpack::Encoder;
+
+    let mut writer = logpack::NameMap::new();
+    let writer_desc = EventV1::logpack_describe(&mut logpack::SeenTypes::new());
+    let writer_desc = writer.feed(from_str(&to_string(&writer_desc).unwrap()).unwrap()).unwrap();
+
+    let reader_desc = concat!(
+        "ByName((\"EventV1\",0),Some(Struct(Named([",
+        "(\"kind\",ByName((\"Kind\",0),Some(Enum([",
+        "(\"Extra\",Unit),(\"Tagged\",Tuple([U64])),(\"Plain\",Unit)])))),",
+        "(\"name\",String),(\"count\",I32),(\"added\",Option(Bool))]))))");
+    let mut reader = logpack::NameMap::new();
+    let reader_desc = reader.feed(from_str(reader_desc).unwrap()).unwrap();
+
+    let value = EventV1 { count: 7, name: String::from("x"), obsolete: 99, kind: Kind::Tagged(5) };
+    let mut bytes : [u8; 1024] = [0; 1024];
+    let mut enc_buf = logpack::BufEncoder::new(&mut bytes);
+    value.logpack_encode(&mut enc_buf).unwrap();
+
+    let resolver = logpack::resolve::Resolver::new(&writer, &reader);
+    resolver.check(&writer_desc, &reader_desc).unwrap();
+
+    let mut output = String::new();
+    let mut dec_buf = logpack::BufDecoder::new(enc_buf.get_content());
+    resolver.decode(&mut dec_buf, &writer_desc, &reader_desc,
+                    &mut logpack_ron::Repr::new(&mut output)).unwrap();
+
+    println!("");
+    println!("Resolved through a newer schema: {}", output);
+    assert_eq!(output, "EventV1(kind: Tagged(5), name: \"x\", count: 7, added: None)");
+
+    let errors = logpack::resolve::Resolver::new(&reader, &writer)
+        .check(&reader_desc, &writer_desc).unwrap_err();
+    println!("Resolving backwards: {:?}", errors);
+    assert_eq!(errors.len(), 4);
+}
+
+fn test_compat()
+{
+    use logpack::compat::{compare, ChangeKind};
+
+    let old = "[ByName((\"Event\",0),Some(Struct(Named([(\"count\",U8),(\"name\",String)]))))]";
+    let new = "[ByName((\"Event\",1),Some(Struct(Named([(\"name\",String),(\"count\",U16),(\"extra\",Option(U8))]))))]";
+    let old = logpack_ron::schema::name_map_from_ron(old).unwrap();
+    let new = logpack_ron::schema::name_map_from_ron(new).unwrap();
+
+    let report = compare(&old, &new);
+    println!("");
+    print!("Compatibility report:\n{}", report);
+    assert!(report.is_compatible());
+    let kinds: Vec<_> = report.changes.iter().map(|c| c.kind).collect();
+    assert_eq!(kinds, vec![ChangeKind::PrimitiveWidened, ChangeKind::FieldAdded,
+                           ChangeKind::FieldsReordered]);
+    assert!(!compare(&new, &old).is_compatible());
+}
+
+fn test_registry(tm: &logpack::NameMap)
+{
+    use logpack::Logpack;
+    use logpack::registry::{self, Format};
+
+    let dir = std::env::temp_dir().join(format!("logpack-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    for &(format, name) in [(Format::Ron, "schema.ron"), (Format::Binary, "schema.bin")].iter() {
+        let path = dir.join(name);
+        registry::save(tm, &path, format).unwrap();
+        let loaded = registry::load(&path).unwrap();
+        assert_eq!(loaded.get_map(), tm.get_map());
+
+        let path = dir.join(format!("sidecar-{}", name));
+        let mut st = logpack::SeenTypes::new();
+        {
+            let mut appender = registry::Appender::open(&path, format).unwrap();
+            appender.append(&SimpleEnum::logpack_describe(&mut st)).unwrap();
+        }
+        {
+            let mut appender = registry::Appender::open(&path, format).unwrap();
+            appender.append(&SimpleEnum::logpack_describe(&mut logpack::SeenTypes::new())).unwrap();
+            appender.append(&EventV1::logpack_describe(&mut st)).unwrap();
+        }
+
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let loaded = registry::load(&path).unwrap();
+        let mut names: Vec<_> = loaded.get_map().keys().map(|x| x.0.as_str()).collect();
+        names.sort();
+        println!("");
+        println!("Types in truncated {:?} sidecar: {:?}", format, names);
+        assert_eq!(names, vec!["EventV1", "SimpleEnum", "SimpleStructUnit"]);
+
+        let mut appender = registry::Appender::open(&path, format).unwrap();
+        appender.append(&EventV1::logpack_describe(&mut logpack::SeenTypes::new())).unwrap();
+        assert_eq!(registry::load(&path).unwrap().get_map().len(), 4);
+
+        // A conflicting definition is reported rather than skipped.
+        let mut map = logpack::NameMap::new();
+        map.feed(from_str("ByName((\"EventV1\",0),Some(Struct(Unit)))").unwrap()).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        assert!(matches!(appender.append_map(&map), Err(registry::Error::Feed(_))));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        if format == registry::Format::Binary {
+            // A corrupt length reads as an incomplete entry.
+            use std::io::Write;
+            let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+            file.write_all(b"junk").unwrap();
+            assert_eq!(registry::load(&path).unwrap().get_map().len(), 4);
+        }
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn test_compact(tm: &logpack::NameMap)
+{
+    use logpack::Logpack;
+    use logpack::compact;
+
+    let mut st = logpack::SeenTypes::new();
+    let descs = vec![
+        SimpleEnum::logpack_describe(&mut st),
+        Option::<SimpleStructNamed>::logpack_describe(&mut st),
+        EventV1::logpack_describe(&mut st),
+        SimpleEnum::logpack_describe(&mut st),
+    ];
+
+    let bytes = compact::to_vec(&descs);
+    assert_eq!(bytes.len(), compact::encoded_size(&descs));
+
+    let decoded = compact::decode(&mut logpack::BufDecoder::new(&bytes)).unwrap();
+    let resolved: Vec<_> = descs.iter().map(|x| x.to_resolved()).collect();
+    assert_eq!(decoded, resolved);
+    assert_eq!(compact::to_vec(&resolved), bytes);
+
+    let ron = ron::ser::to_string(&resolved).unwrap();
+    println!("");
+    println!("Compact schema block: {} bytes, as RON: {} bytes", bytes.len(), ron.len());
+
+    let mut entries: Vec<_> = tm.get_map().iter()
+        .map(|(k, v)| logpack::Description::ByName(k.clone(), Some(v.clone()))).collect();
+    entries.sort_by_key(logpack::diff::summary);
+    let bytes = compact::to_vec(&entries);
+    let decoded = compact::decode(&mut logpack::BufDecoder::new(&bytes)).unwrap();
+    assert_eq!(decoded, entries);
+
+    for len in 0..bytes.len() {
+        assert!(compact::decode(&mut logpack::BufDecoder::new(&bytes[..len])).is_err());
+    }
+}
+
+fn test_stream()
+{
+    use logpack::stream::{Framed, StreamWriter, StreamReader, RECORD_VALUE};
+
+    let mut writer = StreamWriter::new(Framed(Vec::new()));
+    writer.write(&SimpleEnum::TupleField(7)).unwrap();
+    writer.write(&Some(SimpleStructNamed { some_str: String::from("x") })).unwrap();
+    writer.write(&SimpleEnum::OtherUnit(SimpleStructUnit)).unwrap();
+    writer.write(&EventV1 { count: 1, name: String::from("ev"), obsolete: 0, kind: Kind::Tagged(3) }).unwrap();
+    writer.write(&SimpleStructNamed { some_str: String::from("y") }).unwrap();
+    let bytes = writer.into_inner().0;
+
+    let expected = [
+        "TupleField(7)",
+        "Some(SimpleStructNamed(some_str: \"x\"))",
+        "OtherUnit(SimpleStructUnit)",
+        "EventV1(count: 1, name: \"ev\", obsolete: 0, kind: Tagged(3))",
+        "SimpleStructNamed(some_str: \"y\")",
+    ];
+
+    println!("");
+    println!("Self-describing stream of {} bytes:", bytes.len());
+    let mut reader = StreamReader::new(Framed(&bytes[..]));
+    let mut idx = 0;
+    while let Some(value) = reader.next_value().unwrap() {
+        let mut output = String::new();
+        value.decode(&mut logpack_ron::Repr::new(&mut output)).unwrap();
+        println!("  type #{}: {}", value.type_index, output);
+        assert_eq!(output, expected[idx]);
+        idx += 1;
+    }
+    assert_eq!(idx, expected.len());
+
+    let mut reader = StreamReader::new(Framed(&bytes[..bytes.len() - 1]));
+    let mut values = 0;
+    let err = loop {
+        match reader.next_value() {
+            Ok(Some(_)) => values += 1,
+            Ok(None) => panic!("truncation not detected"),
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(values, expected.len() - 1);
+    println!("Truncated stream: {:?}", err);
+
+    // A corrupt length is an error rather than a huge allocation.
+    let mut corrupt = vec![RECORD_VALUE, 0, 0, 0, 0];
+    corrupt.extend_from_slice(&u32::MAX.to_le_bytes());
+    assert!(StreamReader::new(Framed(&corrupt[..])).next_value().is_err());
+}
+
+fn test_file()
+{
+    use logpack::file::{self, FileReader, FileWriter, Metadata};
+    use logpack::framing::{BadFrame, Problem, SYNC_FRAME_LEN};
+
+    let meta = Metadata {
+        pid: 1234,
+        hostname: String::from("host"),
+        start_time: 1_500_000_000_000_000_000,
+        binary: String::from("test"),
+    };
+
+    let mut schema = logpack::NameMap::new();
+    let mut st = logpack::SeenTypes::new();
+    use logpack::Logpack;
+    schema.feed(EventV1::logpack_describe(&mut st).to_resolved()).unwrap();
+
+    let count = 10000u32;
+    let mut writer = FileWriter::new(Vec::new(), &meta).unwrap();
+    writer.write_schema(&schema).unwrap();
+    for i in 0..count {
+        writer.write(&SimpleStructTuple(i, String::from("record"))).unwrap();
+    }
+    let bytes = writer.finish().unwrap();
+
+    let index = file::read_index(&mut std::io::Cursor::new(&bytes)).unwrap().unwrap();
+    println!("");
+    println!("Log file of {} bytes, index: {:?}", bytes.len(), index);
+    assert!(index.len() >= 2);
+
+    let read_all = |bytes: &[u8]| {
+        let mut reader = FileReader::new(bytes).unwrap();
+        assert_eq!(reader.meta(), &meta);
+        let mut values = vec![];
+        while let Some(value) = reader.next_value().unwrap() {
+            let mut output = String::new();
+            value.decode(&mut logpack_ron::Repr::new(&mut output)).unwrap();
+            values.push(output);
+        }
+        assert!(reader.name_map().get_map().keys().any(|x| x.0 == "EventV1"));
+        let source = reader.source();
+        (values, source.is_complete(), source.frames().is_truncated(),
+         source.frames().bad_frames().to_vec())
+    };
+
+    let (values, complete, truncated, bad_frames) = read_all(&bytes);
+    assert_eq!(values.len(), count as usize);
+    assert_eq!(values[17], "SimpleStructTuple(17, \"record\")");
+    assert!(complete && !truncated && bad_frames.is_empty());
+
+    // A forged metadata length is rejected before allocating for it.
+    let mut forged = bytes[..16].to_vec();
+    forged[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(matches!(FileReader::new(&forged[..]), Err(file::Error::BadHeader)));
+
+    // A crash leaves no footer and possibly half a frame.
+    let cut = index[1].offset as usize + 3;
+    let (values, complete, truncated, bad_frames) = read_all(&bytes[..cut]);
+    println!("Truncated file: {} values, complete: {}, truncated: {}", values.len(), complete, truncated);
+    // The schema and type definition records are counted too.
+    assert_eq!(values.len(), index[1].frames as usize - 2);
+    assert!(!complete && truncated && bad_frames.is_empty());
+    assert!(file::read_index(&mut std::io::Cursor::new(&bytes[..cut])).unwrap().is_none());
+
+    // Corrupt frames are reported, and reading resumes at the next sync frame.
+    let lost = (index[1].frames - index[0].frames) as usize;
+    let first = index[0].offset as usize + SYNC_FRAME_LEN;
+    let len = u32::from_le_bytes([bytes[first + 1], bytes[first + 2], bytes[first + 3], bytes[first + 4]]);
+    for &(offset, problem) in [(first + 4, Problem::BadLength(len ^ 0xff00_0000)),
+                               (first + 20, Problem::BadChecksum)].iter() {
+        let mut corrupt = bytes.clone();
+        corrupt[offset] ^= 0xff;
+        let (values, complete, _, bad_frames) = read_all(&corrupt);
+        println!("Corrupt file: {} values, {:?}", values.len(), bad_frames);
+        assert!(complete);
+        assert_eq!(values.len(), count as usize - lost);
+        assert_eq!(bad_frames, vec![BadFrame {
+            offset: first as u64,
+            problem,
+            skipped: index[1].offset - first as u64,
+        }]);
+    }
+}
+
+fn test_follow()
+{
+    use logpack::file::{FileReader, FileWriter, Metadata};
+    use std::io::Write;
+
+    let mut writer = FileWriter::new(Vec::new(), &Metadata::current()).unwrap();
+    writer.write(&SimpleStructTuple(1, String::from("first"))).unwrap();
+    let first = writer.stream().get_ref().get_ref().len();
+    writer.register::<SimpleEnum>().unwrap();
+    let defined = writer.stream().get_ref().get_ref().len();
+    writer.write(&SimpleEnum::TupleField(2)).unwrap();
+    let second = writer.stream().get_ref().get_ref().len();
+    let bytes = writer.finish().unwrap();
+
+    let path = std::env::temp_dir().join(format!("logpack-test-{}-follow.logpack", std::process::id()));
+    std::fs::write(&path, &bytes[..first]).unwrap();
+    let mut reader = FileReader::open(&path).unwrap();
+    reader.follow();
+
+    let read = |reader: &mut FileReader<_>| {
+        let mut values = vec![];
+        while let Some(value) = reader.next_value().unwrap() {
+            let mut output = String::new();
+            value.decode(&mut logpack_ron::Repr::new(&mut output)).unwrap();
+            values.push(output);
+        }
+        values
+    };
+    assert_eq!(read(&mut reader), ["SimpleStructTuple(1, \"first\")"]);
+
+    // The definition and the value after it are appended in halves, and
+    // the value comes out once both are whole.
+    let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+    let ends = [(first + defined) / 2, (defined + second) / 2, second, bytes.len()];
+    let mut appended = first;
+    let mut values = vec![];
+    for &end in ends.iter() {
+        file.write_all(&bytes[appended..end]).unwrap();
+        appended = end;
+        values.push(read(&mut reader));
+        assert!(!reader.source().frames().is_truncated());
+    }
+    println!("");
+    println!("Followed appends: {:?}", values);
+    assert_eq!(values, [vec![], vec![], vec!["TupleField(2)"], vec![]]);
+    assert!(reader.source().is_complete());
+    std::fs::remove_file(&path).unwrap();
+}
+
+fn test_envelope()
+{
+    use logpack::envelope::{Envelope, Level};
+    use logpack::stream::{Framed, StreamWriter, StreamReader};
+    use logpack_ron::envelope::{format_time, parse_time, Prefix};
+    use logpack_ron::record::{render, Format};
+
+    let envelope = Envelope {
+        time: 1_500_000_000_123_456_789,
+        monotonic: 42,
+        level: Level::Warn,
+        callsite: 7,
+        thread_id: 4711,
+        thread_name: "worker",
+        payload_type: 0,
+    };
+
+    let mut writer = StreamWriter::new(Framed(Vec::new()));
+    writer.write(&SimpleStructUnit).unwrap();
+    writer.write_entry(&envelope, &SimpleEnum::TupleField(3)).unwrap();
+    writer.write_entry(&Envelope::now(Level::Info, 0), &SimpleStructUnit).unwrap();
+    let bytes = writer.into_inner().0;
+
+    let mut reader = StreamReader::new(Framed(&bytes[..]));
+    assert!(reader.next_value().unwrap().unwrap().envelope.is_none());
+
+    let value = reader.next_value().unwrap().unwrap();
+    let read = value.envelope.unwrap();
+    assert_eq!(read, Envelope { payload_type: value.type_index, ..envelope });
+
+    let mut output = Prefix::new(&read).to_string();
+    value.decode(&mut logpack_ron::Repr::new(&mut output)).unwrap();
+    println!("");
+    println!("Log entry: {}", output);
+    assert_eq!(output, "2017-07-14T02:40:00.123456Z  WARN worker[4711] #7: TupleField(3)");
+    assert_eq!(render(&value, Format::Plain), output);
+    assert_eq!(render(&value, Format::Ron), "SimpleEnum::TupleField(3)");
+
+    let value = reader.next_value().unwrap().unwrap();
+    let now = value.envelope.unwrap();
+    let mut output = Prefix::new(&now).with_callsite("test:1").to_string();
+    value.decode(&mut logpack_ron::Repr::new(&mut output)).unwrap();
+    println!("Log entry: {}", output);
+    assert_eq!(now.thread_name, "main");
+    assert_eq!(now.level, Level::Info);
+    assert!(now.time > envelope.time);
+
+    assert_eq!("warn".parse::<Level>(), Ok(Level::Warn));
+    assert!(Level::Error > Level::Warn);
+
+    assert_eq!(parse_time(&format_time(envelope.time)), Some(1_500_000_000_123_456_000));
+    assert_eq!(parse_time("2017-07-14T02:40:00Z"), Some(1_500_000_000_000_000_000));
+    assert_eq!(parse_time("1500000000123456789"), Some(envelope.time));
+    assert_eq!(parse_time("2017-13-14T02:40:00Z"), None);
+    assert_eq!(parse_time("2554-07-21T23:34:33.709551615Z"), Some(u64::MAX));
+    assert_eq!(parse_time("2554-07-21T23:34:33.709551616Z"), None);
+    assert_eq!(parse_time("9999-01-01T00:00:00Z"), None);
+    assert_eq!(parse_time("4294967295-01-01T00:00:00Z"), None);
+}
+
+fn log_generic<T: logpack::Logpack + logpack::Encoder>(value: T)
+{
+    logpack::log!(logpack::Level::Debug, "generic {:?}", value);
+}
+
+fn test_log()
+{
+    use logpack::Level;
+    use logpack::callsite::{self, StreamSink};
+    use logpack::stream::{Framed, StreamWriter, StreamReader};
+    use logpack_ron::envelope::Prefix;
+
+    let sink: &'static StreamSink<Framed<Vec<u8>>> =
+        Box::leak(Box::new(StreamSink::new(StreamWriter::new(Framed(Vec::new())))));
+    callsite::set_sink(sink).ok().unwrap();
+    callsite::set_max_level(Level::Debug);
+
+    let user = 17u32;
+    let err = SimpleEnum::NamedField { some_str: String::from("denied") };
+    let name = String::from("bob");
+    for attempt in 0..2u8 {
+        logpack::log!(Level::Warn, "user {} ({:?}) failed: {:?}, attempt {}", user, name, err, attempt);
+    }
+    logpack::log!(Level::Info, "no arguments, {{literal}} braces");
+    logpack::log!(Level::Trace, "filtered out {}", user);
+    logpack::log!(Level::Error, "{1} before {0}, missing {2}", "a", "b",);
+    log_generic(5u16);
+    log_generic(SimpleStructUnit);
+    callsite::flush();
+
+    let expected = [
+        "WARN", "user 17 (\"bob\") failed: NamedField(some_str: \"denied\"), attempt 0",
+        "WARN", "user 17 (\"bob\") failed: NamedField(some_str: \"denied\"), attempt 1",
+        "INFO", "no arguments, {literal} braces",
+        "ERROR", "b before a, missing {2}",
+        "DEBUG", "generic 5",
+        "DEBUG", "generic SimpleStructUnit",
+    ];
+
+    let bytes = sink.lock().get_ref().0.clone();
+    let mut reader = StreamReader::new(Framed(&bytes[..]));
+    let mut idx = 0;
+    let mut ids = vec![];
+
+    println!("");
+    println!("Deferred formatting, {} bytes:", bytes.len());
+    while let Some(value) = reader.next_value().unwrap() {
+        let envelope = value.envelope.unwrap();
+        let callsite = value.callsite.unwrap();
+        let message = logpack_ron::message::message(&value).unwrap().unwrap();
+        let location = callsite.location();
+        println!("  {}{}", Prefix::new(&envelope).with_callsite(&location), message);
+
+        assert_eq!(envelope.level.as_str(), expected[idx * 2]);
+        assert_eq!(message, expected[idx * 2 + 1]);
+        assert_eq!(callsite.module, "logpack_derive_test");
+        assert_eq!(callsite.file, file!());
+        ids.push(envelope.callsite);
+        idx += 1;
+    }
+    assert_eq!(idx * 2, expected.len());
+
+    // Repeated entries share their callsite, while each instantiation of a
+    // generic one gets its own.
+    assert_eq!(ids[0], ids[1]);
+    assert_eq!(reader.callsite(ids[4]).unwrap().line, reader.callsite(ids[5]).unwrap().line);
+    assert_ne!(ids[4], ids[5]);
+    assert_eq!(callsite::callsites().len(), 5);
+    assert_eq!(sink.errors(), 0);
+
+    // Threads racing to use the instantiations of a generic callsite get a
+    // single id for each.
+    static GENERIC: callsite::Callsite = callsite::Callsite::new(module_path!(), file!(), line!(), "{}");
+    let threads: Vec<_> = (0..4).map(|_| std::thread::spawn(|| {
+        [GENERIC.info::<u8>().id, GENERIC.info::<u16>().id, GENERIC.info::<u32>().id]
+    })).collect();
+    let ids: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+    assert!(ids.iter().all(|i| *i == ids[0]));
+    assert_eq!(callsite::callsites().len(), 8);
+    assert_eq!(callsite::lookup(ids[0][1]).unwrap().args_key, "u16");
+}
+
+fn test_log_facade()
+{
+    use logpack::callsite::StreamSink;
+    use logpack::stream::{Framed, StreamWriter, StreamReader};
+    use logpack_ron::envelope::Prefix;
+
+    let sink: &'static StreamSink<Framed<Vec<u8>>> =
+        Box::leak(Box::new(StreamSink::new(StreamWriter::new(Framed(Vec::new())))));
+    logpack_log::init(sink, log::LevelFilter::Info).unwrap();
+
+    let user = 5u32;
+    log::warn!(target: "net", user, ratio = 0.5, name = "bob", ok = true; "user {} failed", user);
+    log::info!("plain message");
+    log::debug!("filtered out");
+    log::logger().flush();
+
+    let expected = [
+        "WARN", "net", "message: \"user 5 failed\", fields: [Field(key: \"user\", value: U64(5)), \
+         Field(key: \"ratio\", value: Other(\"0.5\")), Field(key: \"name\", value: Str(\"bob\")), \
+         Field(key: \"ok\", value: Bool(true))])",
+        "INFO", "logpack_derive_test", "message: \"plain message\", fields: [])",
+    ];
+
+    let bytes = sink.lock().get_ref().0.clone();
+    let mut reader = StreamReader::new(Framed(&bytes[..]));
+    let mut idx = 0;
+
+    println!("");
+    println!("Records of the log facade:");
+    while let Some(value) = reader.next_value().unwrap() {
+        let envelope = value.envelope.unwrap();
+        let mut output = String::new();
+        value.decode(&mut logpack_ron::Repr::new(&mut output)).unwrap();
+        println!("  {}{}", Prefix::new(&envelope), output);
+
+        assert_eq!(envelope.level.as_str(), expected[idx * 3]);
+        assert!(output.starts_with(&format!("LogRecord(target: \"{}\", module_path: Some(\"logpack_derive_test\")",
+                                            expected[idx * 3 + 1])));
+        assert!(output.ends_with(expected[idx * 3 + 2]), "{}", output);
+        idx += 1;
+    }
+    assert_eq!(idx * 3, expected.len());
+    assert_eq!(sink.errors(), 0);
+}
+
+fn test_tracing()
+{
+    use logpack::Level;
+    use logpack::callsite::StreamSink;
+    use logpack::stream::{Framed, StreamWriter, StreamReader};
+    use logpack_tracing::{LogpackLayer, Packed};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let sink: &'static StreamSink<Framed<Vec<u8>>> =
+        Box::leak(Box::new(StreamSink::new(StreamWriter::new(Framed(Vec::new())))));
+    let subscriber = tracing_subscriber::registry()
+        .with(LogpackLayer::new(sink).with_level(Level::Debug));
+
+    let mut lines = (0, 0);
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("request", path = "/api", id = 3u64);
+        let guard = span.enter();
+        for attempt in 0..2 {
+            lines.0 = line!() + 1;
+            tracing::warn!(attempt, err = ?Packed(SimpleEnum::TupleField(4)), "failed {:?}",
+                           Packed(SimpleStructUnit));
+        }
+        lines.1 = line!() + 1;
+        tracing::debug!(ratio = 0.5, ok = true, other = ?Some(1));
+        tracing::trace!("filtered out");
+        drop(guard);
+        drop(span);
+    });
+
+    let bytes = sink.lock().get_ref().0.clone();
+    let mut reader = StreamReader::new(Framed(&bytes[..]));
+    let mut outputs = vec![];
+    let mut types = vec![];
+
+    println!("");
+    println!("Tracing records:");
+    while let Some(value) = reader.next_value().unwrap() {
+        let mut output = String::new();
+        value.decode(&mut logpack_ron::Repr::new(&mut output)).unwrap();
+        println!("  {} {}", value.envelope.unwrap().level, output);
+        outputs.push(output);
+        types.push(value.type_index);
+    }
+
+    let event = |line| format!("(Event(span: Some(1), target: \"logpack_derive_test\"), event {}:{}", file!(), line);
+    let expected = [
+        "(NewSpan(id: 1, parent: None, target: \"logpack_derive_test\"), request(path: \"/api\", id: 3))".to_owned(),
+        "Enter(1)".to_owned(),
+        event(lines.0) + "(message: \"failed SimpleStructUnit\", attempt: 0, err: TupleField(4)))",
+        event(lines.0) + "(message: \"failed SimpleStructUnit\", attempt: 1, err: TupleField(4)))",
+        event(lines.1) + "(ratio: \"0.5\", ok: true, other: \"Some(1)\"))",
+        "Exit(1)".to_owned(),
+        "Close(1)".to_owned(),
+    ];
+    assert_eq!(outputs, expected);
+
+    // Both events of the loop share a type, described once.
+    assert_eq!(types[2], types[3]);
+    assert_eq!(sink.errors(), 0);
+}
+
+fn test_ring()
+{
+    use logpack::Level;
+    use logpack::callsite::{Callsite, Sink, StreamSink};
+    use logpack::envelope::Envelope;
+    use logpack::ring::{Overflow, RingSink, Rings};
+    use logpack::stream::{Framed, StreamWriter, StreamReader};
+    use std::sync::{Arc, Mutex};
+
+    fn drain_u64s(rings: &Rings) -> Vec<u64> {
+        let mut values = vec![];
+        rings.drain(&mut |record| values.push(logpack::BufDecoder::new(record).get::<u64>().unwrap()));
+        values
+    }
+
+    // A 64 byte ring holds five records of a u64 each.
+    let rings = Rings::new(64, Overflow::DropNewest);
+    let mut producer = rings.producer();
+    let written = (0..8u64).filter(|i| producer.write(i)).count();
+    assert_eq!(written, 5);
+    assert_eq!(drain_u64s(&rings), vec![0, 1, 2, 3, 4]);
+    assert_eq!(rings.dropped(), 3);
+
+    // Wrapping around leaves room for four.
+    assert_eq!((8..12u64).filter(|i| producer.write(i)).count(), 4);
+    assert_eq!(drain_u64s(&rings), vec![8, 9, 10, 11]);
+
+    let rings = Rings::new(64, Overflow::DropOldest);
+    let mut producer = rings.producer();
+    for i in 0..100u64 {
+        assert!(producer.write(&i));
+    }
+    let values = drain_u64s(&rings);
+    assert_eq!(*values.last().unwrap(), 99);
+    assert_eq!(values.len() as u64 + rings.dropped(), 100);
+    assert!(values.windows(2).all(|w| w[0] + 1 == w[1]));
+    drop(producer);
+    rings.drain(&mut |_| {});
+
+    // Records dropped while the drain thread reads are never torn.
+    let rings = Rings::new(64, Overflow::DropOldest);
+    let received = Arc::new(Mutex::new(vec![]));
+    let drainer = {
+        let received = received.clone();
+        rings.spawn(move |record| {
+            let (a, b) = logpack::BufDecoder::new(record).get::<(u64, u64)>().unwrap();
+            assert_eq!(a, !b);
+            received.lock().unwrap().push(a);
+        })
+    };
+    rings.with_producer(|producer| {
+        for i in 0..100000u64 {
+            assert!(producer.write(&(i, !i)));
+        }
+    });
+    rings.flush();
+    drainer.stop();
+    let values = received.lock().unwrap();
+    assert!(values.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(values.len() as u64 + rings.dropped(), 100000);
+
+    // Blocking producers on several threads lose nothing, and each thread's
+    // records arrive in order.
+    let rings = Rings::new(256, Overflow::Block);
+    let received = Arc::new(Mutex::new(vec![vec![]; 4]));
+    let drainer = {
+        let received = received.clone();
+        rings.spawn(move |record| {
+            let mut buf = logpack::BufDecoder::new(record);
+            let thread = buf.get::<u32>().unwrap() as usize;
+            received.lock().unwrap()[thread].push(buf.get::<u64>().unwrap());
+        })
+    };
+    let threads: Vec<_> = (0..4u32).map(|thread| {
+        let rings = rings.clone();
+        std::thread::spawn(move || {
+            for i in 0..10000u64 {
+                rings.with_producer(|producer| assert!(producer.write(&(thread, i))));
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    rings.flush();
+    drainer.stop();
+    for values in received.lock().unwrap().iter() {
+        assert_eq!(*values, (0..10000).collect::<Vec<u64>>());
+    }
+    assert_eq!(rings.dropped(), 0);
+
+    // The drain thread doesn't wait on itself when writing to its rings,
+    // and producers stop waiting once it is gone, dropping records instead.
+    let rings = Rings::new(64, Overflow::Block);
+    let drainer = {
+        let feedback = rings.clone();
+        let first = std::sync::atomic::AtomicBool::new(true);
+        rings.spawn(move |_| {
+            if first.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                for i in 0..10u64 {
+                    feedback.with_producer(|producer| producer.write(&i));
+                }
+            }
+        })
+    };
+    rings.with_producer(|producer| assert!(producer.write(&0u64)));
+    rings.flush();
+    assert_eq!(rings.dropped(), 5);
+    drainer.stop();
+    let mut producer = rings.producer();
+    assert_eq!((0..10u64).filter(|i| producer.write(i)).count(), 5);
+    assert_eq!(rings.dropped(), 10);
+
+    // Records of `log!` pass through the rings on to another sink.
+    static CALLSITE: Callsite = Callsite::new(module_path!(), file!(), line!(), "ring {} of {}");
+    let inner: &'static StreamSink<Framed<Vec<u8>>> =
+        Box::leak(Box::new(StreamSink::new(StreamWriter::new(Framed(Vec::new())))));
+    let sink = RingSink::new(inner, Overflow::Block);
+    let info = CALLSITE.info::<(&u32, &&str)>();
+    for i in 0..3u32 {
+        let args = (&i, &"three");
+        let mut buf = vec![0u8; logpack::Encoder::logpack_sizer(&args)];
+        logpack::Encoder::logpack_encode(&args, &mut logpack::BufEncoder::new(&mut buf)).unwrap();
+        sink.log(info, &Envelope::now(Level::Info, info.id), &buf);
+    }
+    sink.flush();
+
+    let bytes = inner.lock().get_ref().0.clone();
+    let mut reader = StreamReader::new(Framed(&bytes[..]));
+    let mut messages = vec![];
+    while let Some(value) = reader.next_value().unwrap() {
+        messages.push(logpack_ron::message::message(&value).unwrap().unwrap());
+    }
+    println!("");
+    println!("Through rings: {:?}", messages);
+    assert_eq!(messages, ["ring 0 of three", "ring 1 of three", "ring 2 of three"]);
+}
+
+fn test_mmap_ring()
+{
+    use logpack::Level;
+    use logpack::envelope::Envelope;
+    use logpack::mmap_ring::{MmapRing, Snapshot};
+    use logpack::stream::{StreamWriter, StreamReader};
+
+    let path = std::env::temp_dir().join(format!("logpack-test-{}.ring", std::process::id()));
+    let ring = MmapRing::create(&path, 4096, 1024).unwrap();
+    let mut writer = StreamWriter::new(ring);
+
+    // Entries of varying lengths, so that some of them don't fit before the
+    // end of the ring and start over at its beginning.
+    let name = "x".repeat(40);
+    for i in 0..1000u64 {
+        let value = SimpleStructTuple(i as u32, name[..(i % 37 + 1) as usize].to_owned());
+        writer.write_entry(&Envelope::now(Level::Info, 0), &value).unwrap();
+    }
+    assert!(writer.get_ref().wraps() > 5);
+
+    // The writer dies without flushing or unmapping.
+    std::mem::forget(writer);
+
+    let snapshot = Snapshot::open(&path).unwrap();
+    assert_eq!(snapshot.pid(), std::process::id());
+    let mut reader = StreamReader::new(snapshot);
+    let mut numbers = vec![];
+    while let Some(value) = reader.next_value().unwrap() {
+        let n = tuple_number(&value).unwrap();
+        assert_eq!(repr(&value), format!("SimpleStructTuple({}, {:?})", n, &name[..(n % 37 + 1) as usize]));
+        numbers.push(n);
+    }
+    std::fs::remove_file(&path).unwrap();
+
+    println!("");
+    println!("Left in the mmap ring: {} entries, {}..={}", numbers.len(), numbers[0], numbers.last().unwrap());
+    assert_eq!(*numbers.last().unwrap(), 999);
+    assert!(numbers.len() > 20);
+    assert!(numbers.windows(2).all(|w| w[0] + 1 == w[1]));
+}
+
+fn test_rotate()
+{
+    use logpack::Level;
+    use logpack::callsite::StreamSink;
+    use logpack::envelope::Envelope;
+    use logpack::rotate::{segments, open_segment, Compression, RotatingFile};
+    use logpack::stream::StreamWriter;
+    use std::time::Duration;
+
+    let dir = std::env::temp_dir().join(format!("logpack-test-{}-rotate", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let file = RotatingFile::create(&dir, "daemon").unwrap()
+        .with_max_size(1000)
+        .with_keep(4)
+        .with_compression(Compression::Zstd(3));
+    let sink = StreamSink::new(StreamWriter::new(file));
+    for i in 0..200u32 {
+        sink.write_entry(&Envelope::now(Level::Info, 0), &SimpleStructTuple(i, "rotated".to_owned()));
+    }
+
+    // On demand, even without any new definitions to write.
+    sink.lock().get_mut().rotate().unwrap();
+    sink.write_entry(&Envelope::now(Level::Info, 0), &SimpleStructTuple(200, "rotated".to_owned()));
+    assert_eq!(sink.errors(), 0);
+    sink.into_inner().into_inner().finish().unwrap();
+
+    // Each segment is read on its own, and those that were kept hold the
+    // last entries in order.
+    let kept = segments(&dir, "daemon").unwrap();
+    assert_eq!(kept.len(), 4);
+    assert!(kept[..3].iter().all(|segment| segment.compressed));
+    assert!(!kept[3].compressed);
+
+    let mut numbers = vec![];
+    for segment in &kept {
+        let before = numbers.len();
+        let mut reader = open_segment(segment).unwrap();
+        while let Some(value) = reader.next_value().unwrap() {
+            numbers.push(tuple_number(&value).unwrap());
+        }
+        assert!(reader.source().is_complete());
+        assert!(numbers.len() > before, "{:?}", segment);
+    }
+    println!("");
+    println!("Kept {} segments, with entries {}..={}", kept.len(), numbers[0], numbers.last().unwrap());
+    assert_eq!(*numbers.last().unwrap(), 200);
+    assert!(numbers.windows(2).all(|w| w[0] + 1 == w[1]));
+
+    // A new file continues the numbering, and rotates by time, removing
+    // segments by age. The segment closed last may be too recent to go.
+    let file = RotatingFile::create(&dir, "daemon").unwrap()
+        .with_interval(Duration::from_millis(10))
+        .with_max_age(Duration::from_millis(1));
+    assert_eq!(segments(&dir, "daemon").unwrap().last().unwrap().seq, kept[3].seq + 1);
+    let mut writer = StreamWriter::new(file);
+    for i in 0..3u32 {
+        if i > 0 {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        writer.write(&i).unwrap();
+    }
+    let left = segments(&dir, "daemon").unwrap();
+    assert!(left.iter().all(|segment| segment.seq >= kept[3].seq + 2), "{:?}", left);
+    assert_eq!(left.last().unwrap().seq, kept[3].seq + 3);
+    writer.into_inner().finish().unwrap();
+
+    // Failing to compress a closed segment doesn't lose the record that set
+    // off the rotation, and is reported apart from writing.
+    let file = RotatingFile::create(&dir, "broken").unwrap()
+        .with_interval(Duration::ZERO)
+        .with_compression(Compression::Zstd(3));
+    std::fs::create_dir(file.path().with_extension("zst-partial")).unwrap();
+    let mut writer = StreamWriter::new(file);
+    writer.write(&7u32).unwrap();
+    let mut file = writer.into_inner();
+    let errors = file.take_errors();
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    file.finish().unwrap();
+
+    let broken = segments(&dir, "broken").unwrap();
+    assert!(!broken[0].compressed && broken[1].compressed);
+    let mut reader = open_segment(broken.last().unwrap()).unwrap();
+    let mut output = String::new();
+    reader.next_value().unwrap().unwrap().decode(&mut logpack_ron::Repr::new(&mut output)).unwrap();
+    assert_eq!(output, "7");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn test_blocks()
+{
+    use logpack::Level;
+    use logpack::block::{self, BlockSource, BlockWriter, Codec, RangeSource};
+    use logpack::envelope::Envelope;
+    use logpack::file::{self, FileReader, Metadata};
+    use logpack::stream::{self, Framed, RecordSink, RecordSource, StreamWriter, StreamReader};
+    use std::io::Cursor;
+
+    let write = |codec: Option<Codec>| {
+        let frames = file::frame_writer(vec![], &Metadata::current()).unwrap();
+        let entry = |i: u32| (Envelope { time: 1000 * i as u64, ..Envelope::now(Level::Info, 0) },
+                              SimpleStructTuple(i, "a repetitive message".to_owned()));
+        let frames = match codec {
+            Some(codec) => {
+                let mut writer = StreamWriter::new(BlockWriter::new(frames, codec).with_block_size(4096));
+                for i in 0..2000 {
+                    let (envelope, value) = entry(i);
+                    writer.write_entry(&envelope, &value).unwrap();
+                    if i == 1000 {
+                        writer.write(&SimpleEnum::TupleField(i)).unwrap();
+                    }
+                }
+                writer.into_inner().finish().unwrap()
+            }
+            None => {
+                let mut writer = StreamWriter::new(frames);
+                for i in 0..2000 {
+                    let (envelope, value) = entry(i);
+                    writer.write_entry(&envelope, &value).unwrap();
+                }
+                writer.into_inner()
+            }
+        };
+        file::finish(frames).unwrap()
+    };
+
+    let plain = write(None).len();
+    println!("");
+    for (name, codec) in [("lz4", Codec::Lz4), ("zstd", Codec::Zstd(3))] {
+        let bytes = write(Some(codec));
+        println!("Blocks with {}: {} bytes, uncompressed {}", name, bytes.len(), plain);
+        assert!(bytes.len() * 4 < plain);
+
+        // Reading the whole file expands the blocks in place.
+        let mut reader = FileReader::new(&bytes[..]).unwrap();
+        let mut numbers = vec![];
+        while let Some(value) = reader.next_value().unwrap() {
+            if value.envelope.is_some() {
+                numbers.push(tuple_number(&value).unwrap());
+            }
+        }
+        assert!(reader.source().is_complete());
+        assert_eq!(numbers, (0..2000).collect::<Vec<_>>());
+
+        // Seeking by time works across blocks too.
+        let mut reader = FileReader::new(Cursor::new(&bytes)).unwrap();
+        let seek_index = reader.seek_index().unwrap().unwrap();
+        reader.seek_time(&seek_index, 1_500_000).unwrap();
+        assert_eq!(tuple_number(&reader.next_value().unwrap().unwrap()), Some(1500));
+
+        // A time range only takes the blocks it overlaps.
+        let index = block::read_index(&mut Cursor::new(&bytes)).unwrap().unwrap();
+        assert!(index.blocks.len() > 10);
+        assert!(file::read_index(&mut Cursor::new(&bytes)).unwrap().is_some());
+
+        let source = RangeSource::new(Cursor::new(&bytes), &index, 1_000_500, 1_200_000);
+        let mut reader = StreamReader::new(source);
+        let mut numbers = vec![];
+        let mut others = 0;
+        while let Some(value) = reader.next_value().unwrap() {
+            match value.envelope {
+                Some(_) => numbers.push(tuple_number(&value).unwrap()),
+                None => others += 1,
+            }
+        }
+        assert_eq!(numbers, (1001..=1200).collect::<Vec<_>>());
+        assert_eq!(others, 1);
+        let read = reader.get_ref().blocks_read();
+        assert!(read > 0 && read * 4 < index.blocks.len(), "{} of {}", read, index.blocks.len());
+    }
+
+    // Blocks whose header doesn't match their data are rejected, without
+    // allocating for a forged length.
+    let mut writer = StreamWriter::new(BlockWriter::new(Framed(vec![]), Codec::Lz4));
+    writer.write(&SimpleStructTuple(1, "a".to_owned())).unwrap();
+    writer.write(&SimpleStructTuple(2, "b".to_owned())).unwrap();
+    let bytes = writer.into_inner().finish().unwrap().0;
+
+    let mut source = Framed(&bytes[..]);
+    let mut block = vec![];
+    let (definition, type_index) = source.next_record(&mut block).unwrap().unwrap();
+    let mut definition_payload = vec![];
+    definition_payload.extend_from_slice(&block);
+    let (kind, codec) = source.next_record(&mut block).unwrap().unwrap();
+    assert_eq!(kind, block::RECORD_BLOCK);
+
+    let read_forged = |offset: usize, value: u32| {
+        let mut forged = block.clone();
+        forged[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        let mut sink = Framed(vec![]);
+        sink.put_record(definition, type_index, &definition_payload).unwrap();
+        sink.put_record(kind, codec, &forged).unwrap();
+
+        let mut reader = StreamReader::new(BlockSource::new(Framed(&sink.0[..])));
+        let mut count = 0;
+        while reader.next_value()?.is_some() {
+            count += 1;
+        }
+        Ok::<_, logpack::stream::Error>(count)
+    };
+    assert_eq!(read_forged(4, 2).unwrap(), 2);
+    for &(offset, value) in [(0, u32::MAX), (0, 5), (4, 3), (4, 1)].iter() {
+        assert!(read_forged(offset, value).is_err(), "header field at {} set to {}", offset, value);
+    }
+
+    // The block size is capped so that readers don't take blocks for corruption.
+    let mut writer = BlockWriter::new(Framed(vec![]), Codec::Lz4).with_block_size(usize::MAX);
+    let huge = vec![0u8; block::MAX_BLOCK_SIZE];
+    assert!(writer.put_record(stream::RECORD_VALUE, 0, &huge).is_err());
+}
+
+fn test_seek()
+{
+    use logpack::Level;
+    use logpack::envelope::Envelope;
+    use logpack::file::{self, FileReader, Metadata};
+    use logpack::stream::StreamWriter;
+    use std::io::Cursor;
+
+    fn next_number<R: BufRead>(reader: &mut FileReader<R>) -> Option<u32> {
+        let value = reader.next_value().unwrap()?;
+        Some(tuple_number(&value).unwrap_or(u32::MAX))
+    }
+
+    let frames = file::frame_writer(vec![], &Metadata::current()).unwrap().with_sync_interval(4096);
+    let mut writer = StreamWriter::new(frames);
+    for i in 0..20000u32 {
+        let envelope = Envelope { time: 1000 * i as u64, ..Envelope::now(Level::Info, 0) };
+        writer.write_entry(&envelope, &SimpleStructTuple(i, "seekable".to_owned())).unwrap();
+        if i == 5000 || i == 15000 {
+            writer.write(&SimpleEnum::TupleField(i)).unwrap();
+        }
+    }
+    let bytes = file::finish(writer.into_inner()).unwrap();
+
+    let mut reader = FileReader::new(Cursor::new(&bytes)).unwrap();
+    let index = reader.seek_index().unwrap().unwrap();
+    assert!(index.points.len() > 100);
+    assert_eq!(index.definitions.len(), 2);
+
+    // Seeking applies the definitions it jumps over, needed for the value
+    // after entry 15000.
+    reader.seek_time(&index, 12_345_500).unwrap();
+    let mut numbers = vec![];
+    while let Some(number) = next_number(&mut reader) {
+        numbers.push(number);
+    }
+    assert_eq!(numbers.len(), 20000 - 12346 + 1);
+    assert_eq!(numbers[0], 12346);
+    assert!(reader.source().is_complete());
+
+    // Frame 0 is a definition, as are two others before entry 5001.
+    reader.seek_seq(&index, 7000).unwrap();
+    assert_eq!(next_number(&mut reader), Some(6997));
+    assert_eq!(reader.source().seq(), 7000);
+
+    reader.seek_time(&index, 0).unwrap();
+    assert_eq!(next_number(&mut reader), Some(0));
+
+    // A file cut short in a crash gets an index.
+    let path = std::env::temp_dir().join(format!("logpack-test-{}-seek.logpack", std::process::id()));
+    let cut = bytes.len() * 3 / 4;
+    std::fs::write(&path, &bytes[..cut]).unwrap();
+    let reindexed = file::reindex(&path).unwrap();
+    println!("");
+    println!("Reindexed: {:?}", reindexed);
+    assert!(reindexed.dropped > 0 && reindexed.bad_frames == 0);
+
+    let mut reader = FileReader::open(&path).unwrap();
+    let index = reader.seek_index().unwrap().unwrap();
+    reader.seek_time(&index, 13_000_000).unwrap();
+    assert_eq!(next_number(&mut reader), Some(13000));
+
+    // Reindexing a finished file gives the same file.
+    std::fs::write(&path, &bytes).unwrap();
+    file::reindex(&path).unwrap();
+    assert!(std::fs::read(&path).unwrap() == bytes);
+    std::fs::remove_file(&path).unwrap();
+}
+
+mod other {
+    use logpack_derive::Logpack;
+
+    /// Shares its name with the one above, but not its definition.
+    #[derive(Logpack)]
+    pub struct SimpleStructTuple(pub u64);
+}
+
+fn test_merge()
+{
+    use logpack::Level;
+    use logpack::envelope::Envelope;
+    use logpack::merge::MergeReader;
+    use logpack::stream::{Framed, StreamWriter, StreamReader};
+
+    let entry = |time: u64| Envelope { time, ..Envelope::now(Level::Info, 0) };
+
+    // The streams define their types in different orders.
+    let mut a = StreamWriter::new(Framed(Vec::new()));
+    a.write(&SimpleEnum::WithUnit).unwrap();
+    let mut b = StreamWriter::new(Framed(Vec::new()));
+    let mut c = StreamWriter::new(Framed(Vec::new()));
+    for i in 0..100u32 {
+        let time = i as u64 * 3;
+        a.write_entry(&entry(time), &SimpleStructTuple(i, "from a".to_owned())).unwrap();
+        b.write_entry(&entry(time + 1), &other::SimpleStructTuple(i as u64)).unwrap();
+        c.write_entry(&entry(time + 2), &SimpleStructTuple(i, "from c".to_owned())).unwrap();
+        if i == 50 {
+            c.write(&SimpleEnum::TupleField(i)).unwrap();
+        }
+    }
+    let streams = [a.into_inner().0, b.into_inner().0, c.into_inner().0];
+
+    let readers = streams.iter().map(|bytes| StreamReader::new(Framed(&bytes[..]))).collect();
+    let mut reader = MergeReader::new(readers);
+    let mut order = vec![];
+    let mut type_ids = vec![];
+    let mut last_time = 0;
+    while let Some(merged) = reader.next_value().unwrap() {
+        if let Some(envelope) = &merged.value.envelope {
+            assert!(envelope.time >= last_time);
+            last_time = envelope.time;
+        }
+        order.push(merged.stream);
+        type_ids.push((merged.stream, merged.type_id));
+    }
+
+    // The enum value of `c` stays after the entry before it.
+    assert_eq!(order.len(), 302);
+    assert_eq!(&order[..6], &[0, 0, 1, 2, 0, 1]);
+    assert_eq!(&order[152..157], &[1, 2, 2, 0, 1]);
+
+    // The tuple structs of `a` and `c` are the same type, unlike that of `b`.
+    type_ids.sort();
+    type_ids.dedup();
+    assert_eq!(type_ids, vec![(0, 0), (0, 1), (1, 2), (2, 0), (2, 1)]);
+    let keys = reader.type_keys();
+    assert_eq!(keys[1].name, keys[2].name);
+    assert_ne!(keys[1].fingerprint, keys[2].fingerprint);
+    println!("");
+    println!("Merged types: {:?}", keys);
+}
+
+fn test_filter()
+{
+    use logpack::Level;
+    use logpack::envelope::Envelope;
+    use logpack::filter::{Error, Filter};
+    use logpack::stream::{Framed, StreamWriter, StreamReader};
+
+    let mut writer = StreamWriter::new(Framed(Vec::new()));
+    for i in 0..20u32 {
+        let envelope = Envelope::now(Level::from_index((i % 5) as u8).unwrap(), 0);
+        let test = match i % 3 {
+            0 => SimpleEnum::TupleField(i),
+            1 => SimpleEnum::NamedField { some_str: format!("/api/{}", i) },
+            _ => SimpleEnum::WithUnit,
+        };
+        writer.write_entry(&envelope, &GenericType { test, field: i }).unwrap();
+        writer.write_entry(&envelope, &SimpleStructTuple(i, format!("item {}", i))).unwrap();
+    }
+    writer.write(&GenericType { test: Some(7u8), field: 20 }).unwrap();
+    let bytes = writer.into_inner().0;
+
+    let count = |text: &str| {
+        let filter: Filter = text.parse().unwrap();
+        let mut reader = StreamReader::new(Framed(&bytes[..]));
+        let mut count = 0;
+        while let Some(value) = reader.next_value().unwrap() {
+            if filter.matches(&value).unwrap() {
+                count += 1;
+            }
+        }
+        count
+    };
+
+    assert_eq!(count("level >= WARN"), 16);
+    assert_eq!(count("level == INFO || level == error"), 16);
+    assert_eq!(count("field >= 10 && test == TupleField"), 3);
+    assert_eq!(count("test.NamedField.some_str ~ \"^/api/1\""), 5);
+    assert_eq!(count("test.TupleField.0 < 5 || .1 == \"item 19\""), 3);
+    assert_eq!(count("type == SimpleStructTuple && !(.0 > 2)"), 3);
+    assert_eq!(count("0 < 3 && 1 ~ \"^item\""), 3);
+    assert_eq!(count("test != WithUnit && field < 6 && level"), 4);
+    assert_eq!(count("test.WithUnit"), 6);
+    assert_eq!(count("test == 7 && !level"), 1);
+
+    // Missing parts compare false either way.
+    assert_eq!(count("test.TupleField.0 != 3"), 6);
+    assert_eq!(count("!(test.TupleField.0 == 3)"), 40);
+
+    let error = |text: &str| text.parse::<Filter>().err().unwrap();
+    assert!(matches!(error("level >= WARM"), Error::UnknownLevel(9, _)));
+    assert!(matches!(error("field =="), Error::Syntax(8, _)));
+    assert!(matches!(error("(field && level"), Error::Syntax(15, "`)`")));
+    assert!(matches!(error("field 3"), Error::Syntax(6, _)));
+    assert!(matches!(error("test ~ 1"), Error::Syntax(7, _)));
+    assert!(matches!(error("test ~ \"[\""), Error::Regex(7, _)));
+    assert!(matches!(error("field == 1.5"), Error::Syntax(9, _)));
+
+    // Nesting is limited, while long chains are not.
+    let max = logpack::filter::MAX_DEPTH;
+    assert_eq!(count(&format!("{}level", "!".repeat(max))), 40);
+    assert!(matches!(error(&format!("{}level", "!".repeat(100_000))), Error::Syntax(64, "less nesting")));
+    assert!(matches!(error(&format!("{}level", "(".repeat(100_000))), Error::Syntax(64, "less nesting")));
+    assert_eq!(count(&vec!["field < 5"; 100_000].join(" && ")), 5);
+    println!("");
+    println!("Filter error: {}", error("field == \"open"));
+
+    // Only the fields up to the last one needed are read.
+    let filter: Filter = "test == TupleField".parse().unwrap();
+    let mut reader = StreamReader::new(Framed(&bytes[..]));
+    let value = reader.next_value().unwrap().unwrap();
+    let truncated = &value.data[..1];
+    assert!(filter.matches_data(value.envelope.as_ref(), value.desc, value.names, truncated).unwrap());
+    let filter: Filter = "field == 0".parse().unwrap();
+    assert!(filter.matches_data(value.envelope.as_ref(), value.desc, value.names, truncated).is_err());
+
+    // The envelope alone decides this one.
+    let filter: Filter = "level > INFO && field == 0".parse().unwrap();
+    assert!(!filter.matches_data(value.envelope.as_ref(), value.desc, value.names, &[]).unwrap());
+}
+
+fn test_aggregate()
+{
+    use logpack::Level;
+    use logpack::aggregate::{Aggregator, Sketch};
+    use logpack::envelope::Envelope;
+    use logpack::path::Scalar;
+    use logpack::stream::{Framed, StreamWriter, StreamReader};
+
+    let mut writer = StreamWriter::new(Framed(Vec::new()));
+    for i in 0..300u32 {
+        let level = if i % 10 == 0 { Level::Warn } else { Level::Info };
+        let test = match i % 3 {
+            0 => SimpleEnum::TupleField(i),
+            1 => SimpleEnum::NamedField { some_str: format!("{}", i) },
+            _ => SimpleEnum::WithUnit,
+        };
+        writer.write_entry(&Envelope::now(level, 0), &GenericType { test, field: i }).unwrap();
+    }
+    writer.write_entry(&Envelope::now(Level::Info, 0), &SimpleStructTuple(1, "other".to_owned())).unwrap();
+    let bytes = writer.into_inner().0;
+
+    let aggregate = |group_by: &[&str], summarize: &[&str]| {
+        let paths = |texts: &[&str]| texts.iter().map(|text| text.parse().unwrap()).collect();
+        let mut aggregator = Aggregator::new(paths(group_by), paths(summarize));
+        let mut reader = StreamReader::new(Framed(&bytes[..]));
+        while let Some(value) = reader.next_value().unwrap() {
+            aggregator.add(&value).unwrap();
+        }
+        aggregator.into_groups()
+    };
+
+    // Count by variant, where the record of another type has none.
+    let groups = aggregate(&["test"], &[]);
+    let counts: Vec<_> = groups.iter().map(|group| (group.key[0].clone(), group.count)).collect();
+    let variant = |name: &str| Some(Scalar::Variant(name.to_owned()));
+    assert_eq!(counts, vec![
+        (variant("TupleField"), 100), (variant("NamedField"), 100), (variant("WithUnit"), 100), (None, 1),
+    ]);
+
+    // Stats grouped by a builtin field.
+    let groups = aggregate(&["level"], &["field", "test.TupleField.0"]);
+    assert_eq!(groups.len(), 2);
+    let warn = &groups[0];
+    assert_eq!(warn.key, vec![Some(Scalar::Level(Level::Warn))]);
+    assert_eq!(warn.count, 30);
+    assert_eq!((warn.summaries[0].sum, warn.summaries[0].min, warn.summaries[0].max), (4350, Some(0), Some(290)));
+    assert_eq!(warn.summaries[0].mean(), Some(145.0));
+    assert_eq!(warn.summaries[1].count(), 10);
+    assert_eq!(warn.summaries[1].max, Some(270));
+    let info = &groups[1];
+    assert_eq!(info.count, 271);
+    assert_eq!(info.summaries[0].count(), 270);
+
+    // Quantiles are within a few percent.
+    let mut sketch = Sketch::new();
+    for val in 1..=100_000 {
+        sketch.add(val);
+    }
+    for &(q, expected) in &[(0.5, 50_000.0), (0.9, 90_000.0), (0.99, 99_000.0)] {
+        let val = sketch.quantile(q).unwrap() as f64;
+        assert!((val - expected).abs() / expected < 0.03, "q{} = {}", q, val);
+    }
+    assert_eq!(sketch.quantile(0.0), Some(1));
+
+    let mut negative = Sketch::new();
+    for val in &[-1000, -5, 0, 3] {
+        negative.add(*val);
+    }
+    sketch.merge(&negative);
+    assert_eq!(sketch.count(), 100_004);
+    assert_eq!(negative.quantile(0.5), Some(-5));
+    let buckets: Vec<_> = negative.buckets().collect();
+    assert_eq!(buckets, vec![(-1007, -992, 1), (-5, -5, 1), (0, 0, 1), (3, 3, 1)]);
+    println!("");
+    println!("Aggregated warnings: {} records, field p50 {:?}", warn.count, warn.summaries[0].quantile(0.5));
+}
+
 fn main()
 {
     let mut st = logpack::SeenTypes::new();
@@ -172,6 +1620,7 @@ fn main()
     test(&mut st, &mut tm, &SimpleEnum::OtherUnit(SimpleStructUnit));
     test(&mut st, &mut tm, &Some(10u32));
     test(&mut st, &mut tm, &Some((10u32, (4u8, 12u32))));
+    test(&mut st, &mut tm, &(-5i8, (-300i16, 7u8)));
 
     let sr = StaticRecord {
         file : "file.rs",
@@ -181,4 +1630,40 @@ fn main()
     };
 
     test_ser_only(&mut st, &mut tm, &sr);
+
+    let path = String::from("/api/v1");
+    let body = vec![1u8, 2, 3];
+    test_ser_only(&mut st, &mut tm, &Request { path: &path, body: &body });
+    test_ser_only(&mut st, &mut tm, &&[1u32, 2, 3][..]);
+    test_ser_only(&mut st, &mut tm, &Borrowed::Ref(&12u32));
+    test_ser_only(&mut st, &mut tm, &Borrowed::Pair(&path, 13u32));
+
+    test_ser_only(&mut st, &mut tm, &GenericType { test: 1u8, field: 2 });
+    test_ser_only(&mut st, &mut tm, &GenericType { test: String::from("bla"), field: 3 });
+    test_ser_only(&mut st, &mut tm, &GenericType { test: Some(SimpleStructUnit), field: 4 });
+    test_generic_names(&tm);
+
+    test_content_ids();
+    test_type_keys();
+    test_conflicts(&mut tm);
+    test_validate();
+    test_resolve();
+    test_compat();
+    test_registry(&tm);
+    test_compact(&tm);
+    test_stream();
+    test_file();
+    test_follow();
+    test_envelope();
+    test_log();
+    test_log_facade();
+    test_tracing();
+    test_ring();
+    test_mmap_ring();
+    test_rotate();
+    test_blocks();
+    test_seek();
+    test_merge();
+    test_filter();
+    test_aggregate();
 }